@@ -0,0 +1,27 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use mandelbrot::{render, Complex, Mode, RenderOptions, Viewport};
+
+/// The view of bench_render_modes, smaller so every sample is quick:
+/// cargo bench --bench render
+fn render_modes(c: &mut Criterion) {
+    let viewport = Viewport::new(
+        (400, 300),
+        Complex::new(-1.08, 0.28),
+        Complex::new(-1.03, 0.23),
+    );
+    let mut group = c.benchmark_group("render");
+    for mode in [Mode::Single, Mode::Crossbeam, Mode::Rayon, Mode::Tiles] {
+        let options = RenderOptions {
+            mode,
+            max_iter: 1000,
+            ..RenderOptions::default()
+        };
+        group.bench_function(format!("{:?}", mode).to_lowercase(), |b| {
+            b.iter(|| render(&viewport, &options).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, render_modes);
+criterion_main!(benches);
@@ -0,0 +1,364 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+
+use gif::SetParameter;
+
+use crate::cli::{self, Options};
+use crate::output::encode_png;
+use crate::{render_image, CHANNELS};
+
+/// Width of the view at zoom 1, the whole Mandelbrot set fits in it
+pub const BASE_WIDTH: f64 = 3.0;
+
+/// Pixels sampled from all frames together to pick the colors of the animated GIF
+const GIF_SAMPLE_PIXELS: usize = 1 << 18;
+
+/// How the zoom speeds up and slows down between the first and the last frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps animation progress 0..=1 to zoom progress 0..=1
+    fn apply(&self, t: f64) -> f64 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+impl FromStr for Easing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Easing, String> {
+        match s {
+            "linear" => Ok(Easing::Linear),
+            "ease-in" => Ok(Easing::EaseIn),
+            "ease-out" => Ok(Easing::EaseOut),
+            "ease-in-out" => Ok(Easing::EaseInOut),
+            _ => Err(format!(
+                "unknown easing '{}', expected linear, ease-in, ease-out or ease-in-out",
+                s
+            )),
+        }
+    }
+}
+
+/// Zoom into a target point, frame by frame.
+/// Every frame is rendered with the same render options, including max_iter and palette,
+/// so a dot gets the same color in every frame it is in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    pub directory: String,
+    /// Target as "re" and "im" strings, they keep all digits for deep zooms
+    pub target: (String, String),
+    pub zoom_start: f64,
+    pub zoom_end: f64,
+    pub frames: usize,
+    pub easing: Easing,
+    pub apng: Option<String>,
+    pub gif: Option<String>,
+    pub fps: u16,
+    /// Render arguments without the positional corners, shared by all frames
+    pub render_args: Vec<String>,
+    pub bounds: (usize, usize),
+}
+
+impl Animation {
+    /// Zoom of the frame, zoom changes geometrically so each frame zooms in by the same factor
+    pub fn zoom(&self, frame: usize) -> f64 {
+        let t = if self.frames > 1 {
+            frame as f64 / (self.frames - 1) as f64
+        } else {
+            0.0
+        };
+        self.zoom_start * (self.zoom_end / self.zoom_start).powf(self.easing.apply(t))
+    }
+
+    pub fn frame_path(&self, frame: usize) -> PathBuf {
+        Path::new(&self.directory).join(format!("frame-{:05}.png", frame + 1))
+    }
+
+    /// Render options of the frame, built from the same arguments as a single render
+    pub fn frame_options(&self, frame: usize) -> Result<Options, String> {
+        let (upper_left, lower_right) = cli::view_corners(
+            (&self.target.0, &self.target.1),
+            self.zoom(frame),
+            self.bounds,
+        )
+        .ok_or_else(|| format!("error parsing target '{},{}'", self.target.0, self.target.1))?;
+
+        let mut args = vec![
+            self.frame_path(frame).to_string_lossy().into_owned(),
+            format!("{}x{}", self.bounds.0, self.bounds.1),
+            upper_left,
+            lower_right,
+        ];
+        args.extend(self.render_args.iter().cloned());
+        cli::parse_args(args)
+    }
+
+    /// Renders the frames that are not there yet, so an interrupted run continues where it stopped
+    pub fn run(&self) -> Result<(), String> {
+        fs::create_dir_all(&self.directory)
+            .map_err(|e| format!("can't create directory '{}': {}", self.directory, e))?;
+
+        let started = Instant::now();
+        for frame in 0..self.frames {
+            let path = self.frame_path(frame);
+            if frame_is_complete(&path, self.bounds) {
+                println!("Frame {}/{} is already there", frame + 1, self.frames);
+                continue;
+            }
+
+            let frame_started = Instant::now();
+            let options = self.frame_options(frame)?;
+            let pixels = render_image(&options, false);
+
+            // Written under another name first, a frame cut short by an interrupt is never complete.
+            // Frames are 8 bit, the animated PNG is put together from them.
+            let partial = path.with_extension("png.partial");
+            let error = |e: String| format!("can't write frame '{}': {}", partial.display(), e);
+            let output = File::create(&partial).map_err(|e| error(e.to_string()))?;
+            encode_png(
+                BufWriter::new(output),
+                &pixels,
+                self.bounds,
+                8,
+                &options.metadata(),
+            )
+            .map_err(error)?;
+            fs::rename(&partial, &path)
+                .map_err(|e| format!("can't write frame '{}': {}", path.display(), e))?;
+
+            println!(
+                "Frame {}/{} at zoom {:e} rendered in {:.3} sec{}",
+                frame + 1,
+                self.frames,
+                self.zoom(frame),
+                frame_started.elapsed().as_secs_f64(),
+                if options.deep.is_some() {
+                    " (deep)"
+                } else {
+                    ""
+                }
+            );
+        }
+        println!(
+            "Rendered {} frames in {:.3} sec",
+            self.frames,
+            started.elapsed().as_secs_f64()
+        );
+
+        if let Some(apng) = &self.apng {
+            self.write_apng(apng)?;
+            println!("Animation written to {}", apng);
+        }
+        if let Some(gif) = &self.gif {
+            self.write_gif(gif)?;
+            println!("Animation written to {}", gif);
+        }
+        Ok(())
+    }
+
+    /// Joins the frames into an animated PNG, one frame in memory at a time
+    fn write_apng(&self, filename: &str) -> Result<(), String> {
+        let error = |e: png::EncodingError| format!("can't write animation '{}': {}", filename, e);
+        let output = File::create(filename)
+            .map_err(|e| format!("can't write animation '{}': {}", filename, e))?;
+
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(output),
+            self.bounds.0 as u32,
+            self.bounds.1 as u32,
+        );
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(self.frames as u32, 0).map_err(error)?;
+        encoder.set_frame_delay(1, self.fps).map_err(error)?;
+        let mut writer = encoder.write_header().map_err(error)?;
+
+        for frame in 0..self.frames {
+            let pixels = read_frame(&self.frame_path(frame), self.bounds)?;
+            writer.write_image_data(&pixels).map_err(error)?;
+        }
+        writer.finish().map_err(error)
+    }
+
+    /// Joins the frames into an animated GIF. All frames share one palette of 256 colors
+    /// picked from a sample of every frame, so a color stays the same from frame to frame.
+    fn write_gif(&self, filename: &str) -> Result<(), String> {
+        let error = |e: std::io::Error| format!("can't write animation '{}': {}", filename, e);
+        let (width, height) = match (u16::try_from(self.bounds.0), u16::try_from(self.bounds.1)) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => return Err("animated GIF can't be larger than 65535x65535".to_string()),
+        };
+
+        let pixels = self.bounds.0 * self.bounds.1;
+        let step = (pixels * self.frames).div_ceil(GIF_SAMPLE_PIXELS).max(1);
+        let mut sample = Vec::new();
+        for frame in 0..self.frames {
+            let frame = read_frame(&self.frame_path(frame), self.bounds)?;
+            for pixel in frame.chunks(CHANNELS).step_by(step) {
+                sample.extend_from_slice(pixel);
+                sample.push(u8::MAX);
+            }
+        }
+        let colors = color_quant::NeuQuant::new(10, 256, &sample);
+
+        let output = File::create(filename).map_err(error)?;
+        let mut encoder = gif::Encoder::new(
+            BufWriter::new(output),
+            width,
+            height,
+            &colors.color_map_rgb(),
+        )
+        .map_err(error)?;
+        encoder.set(gif::Repeat::Infinite).map_err(error)?;
+        for frame in 0..self.frames {
+            let pixels = read_frame(&self.frame_path(frame), self.bounds)?;
+            let indices: Vec<u8> = pixels
+                .chunks(CHANNELS)
+                .map(|pixel| colors.index_of(&[pixel[0], pixel[1], pixel[2], u8::MAX]) as u8)
+                .collect();
+            let frame = gif::Frame {
+                width,
+                height,
+                // Hundredths of a second
+                delay: (100 / self.fps).max(1),
+                buffer: indices.into(),
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).map_err(error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Frame file that was fully written with the expected size.
+/// The whole image is decoded, a file cut short still has a valid header.
+fn frame_is_complete(path: &Path, bounds: (usize, usize)) -> bool {
+    read_frame(path, bounds).is_ok()
+}
+
+fn read_frame(path: &Path, bounds: (usize, usize)) -> Result<Vec<u8>, String> {
+    let error = |e: String| format!("can't read frame '{}': {}", path.display(), e);
+    let file = File::open(path).map_err(|e| error(e.to_string()))?;
+    let mut reader = png::Decoder::new(file)
+        .read_info()
+        .map_err(|e| error(e.to_string()))?;
+
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut pixels)
+        .map_err(|e| error(e.to_string()))?;
+    if (info.width as usize, info.height as usize) != bounds
+        || info.color_type != png::ColorType::Rgb
+    {
+        return Err(error(
+            "frame has a different size or color type".to_string(),
+        ));
+    }
+
+    pixels.truncate(bounds.0 * bounds.1 * CHANNELS);
+    Ok(pixels)
+}
+
+#[cfg(test)]
+fn test_animation(directory: &str) -> Animation {
+    Animation {
+        directory: directory.to_string(),
+        target: ("-0.75".to_string(), "0.1".to_string()),
+        zoom_start: 1.0,
+        zoom_end: 100.0,
+        frames: 3,
+        easing: Easing::Linear,
+        apng: None,
+        gif: None,
+        fps: 10,
+        render_args: vec!["--max-iter".to_string(), "50".to_string()],
+        bounds: (30, 20),
+    }
+}
+
+#[test]
+fn test_animation_zoom() {
+    let mut animation = test_animation("frames");
+    assert_eq!(animation.zoom(0), 1.0);
+    assert!((animation.zoom(1) - 10.0).abs() < 1e-9);
+    assert!((animation.zoom(2) - 100.0).abs() < 1e-9);
+
+    animation.easing = Easing::EaseInOut;
+    assert!((animation.zoom(1) - 10.0).abs() < 1e-9);
+    animation.easing = Easing::EaseIn;
+    assert!(animation.zoom(1) < 10.0);
+
+    let options = animation.frame_options(0).unwrap();
+    assert_eq!(options.upper_left.re, -2.25);
+    assert_eq!(options.lower_right.re, 0.75);
+    assert_eq!(options.upper_left.im, 1.1);
+    assert_eq!(options.max_iter, 50);
+    assert!(options.file.ends_with("frame-00001.png"));
+}
+
+#[test]
+fn test_animation_resume() {
+    let directory = std::env::temp_dir().join(format!("mandelbrot-frames-{}", std::process::id()));
+    let mut animation = test_animation(&directory.to_string_lossy());
+    animation.apng = Some(directory.join("zoom.png").to_string_lossy().into_owned());
+    animation.gif = Some(directory.join("zoom.gif").to_string_lossy().into_owned());
+
+    animation.run().unwrap();
+    let first = fs::read(animation.frame_path(1)).unwrap();
+    assert!(frame_is_complete(&animation.frame_path(2), (30, 20)));
+
+    // A broken or truncated frame is rendered again, the others are kept
+    fs::write(animation.frame_path(1), b"\x89PNG broken").unwrap();
+    let last = fs::read(animation.frame_path(2)).unwrap();
+    fs::write(animation.frame_path(2), &last[..last.len() / 2]).unwrap();
+    assert!(!frame_is_complete(&animation.frame_path(2), (30, 20)));
+    let kept = fs::metadata(animation.frame_path(0))
+        .unwrap()
+        .modified()
+        .unwrap();
+    animation.run().unwrap();
+    assert_eq!(fs::read(animation.frame_path(1)).unwrap(), first);
+    assert_eq!(fs::read(animation.frame_path(2)).unwrap(), last);
+    assert_eq!(
+        fs::metadata(animation.frame_path(0))
+            .unwrap()
+            .modified()
+            .unwrap(),
+        kept
+    );
+
+    let mut reader = png::Decoder::new(File::open(directory.join("zoom.png")).unwrap())
+        .read_info()
+        .unwrap();
+    assert_eq!(reader.info().animation_control().unwrap().num_frames, 3);
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut pixels).unwrap();
+
+    let mut reader = gif::Decoder::new(File::open(directory.join("zoom.gif")).unwrap())
+        .read_info()
+        .unwrap();
+    assert_eq!((reader.width(), reader.height()), (30, 20));
+    let mut frames = 0;
+    while let Some(frame) = reader.read_next_frame().unwrap() {
+        assert_eq!(frame.buffer.len(), 30 * 20);
+        frames += 1;
+    }
+    assert_eq!(frames, 3);
+
+    fs::remove_dir_all(directory).unwrap();
+}
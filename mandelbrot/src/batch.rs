@@ -0,0 +1,448 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::{Instant, SystemTime};
+
+use crate::cli::{self, Options};
+use crate::output::{read_metadata, write_image, Format};
+use crate::raw::RAW_EXTENSION;
+use crate::stream::STREAM_PIXELS;
+use crate::{tiles, CHANNELS};
+
+/// Job file as written, TOML or JSON
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct JobFile {
+    /// Render options of every job as on the command line, a job's own options go after them
+    #[serde(default)]
+    options: String,
+    jobs: Vec<Job>,
+}
+
+/// View of the job file, rendered once for each of its sizes
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    /// Image file, {size} in it is replaced by the size
+    output: String,
+    size: Sizes,
+    upper_left: Option<String>,
+    lower_right: Option<String>,
+    center: Option<String>,
+    zoom: Option<f64>,
+    palette: Option<String>,
+    #[serde(default)]
+    options: String,
+}
+
+/// "widthxheight" or a list of them
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(untagged)]
+enum Sizes {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Job {
+    /// Command line of the render of every size
+    fn render_args(&self, common_options: &str) -> Result<Vec<Vec<String>>, String> {
+        let sizes = match &self.size {
+            Sizes::One(size) => vec![size.clone()],
+            Sizes::Many(sizes) => sizes.clone(),
+        };
+        if sizes.is_empty() {
+            return Err("size is empty".to_string());
+        }
+        if sizes.len() > 1 && !self.output.contains("{size}") {
+            return Err("output needs {size} in it to render several sizes".to_string());
+        }
+        let view = match (&self.upper_left, &self.lower_right, &self.center, self.zoom) {
+            (Some(upper_left), Some(lower_right), None, None) => {
+                vec![upper_left.clone(), lower_right.clone()]
+            }
+            (None, None, Some(center), Some(zoom)) => vec![
+                "--center".to_string(),
+                center.clone(),
+                "--zoom".to_string(),
+                zoom.to_string(),
+            ],
+            _ => return Err("expected upper_left and lower_right or center and zoom".to_string()),
+        };
+
+        Ok(sizes
+            .iter()
+            .map(|size| {
+                let mut args = vec![self.output.replace("{size}", size), size.clone()];
+                args.extend(view.iter().cloned());
+                if let Some(palette) = &self.palette {
+                    args.extend(["--palette".to_string(), palette.clone()]);
+                }
+                args.extend(common_options.split_whitespace().map(String::from));
+                args.extend(self.options.split_whitespace().map(String::from));
+                args
+            })
+            .collect())
+    }
+}
+
+/// Renders of a job file on one thread pool: jobs are rendered one after another,
+/// each of them in tiles on all the threads, so one image is in memory at a time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    /// Job file, outputs older than it are rendered again
+    pub file: String,
+    pub threads: usize,
+    /// Renders the outputs that are up to date as well
+    pub force: bool,
+    /// Every size of every job, in the order of the job file
+    pub jobs: Vec<Options>,
+}
+
+/// What became of a job
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Seconds it took
+    Rendered(f64),
+    UpToDate,
+    Failed(String),
+}
+
+impl Batch {
+    /// Reads the job file, .json files are JSON and the others TOML
+    pub fn read(file: &str, threads: usize, force: bool) -> Result<Batch, String> {
+        let text = fs::read_to_string(file).map_err(|e| format!("can't read '{}': {}", file, e))?;
+        let jobs = parse_jobs(&text, file.to_ascii_lowercase().ends_with(".json"))
+            .map_err(|e| format!("invalid job file '{}': {}", file, e))?;
+        Ok(Batch {
+            file: file.to_string(),
+            threads,
+            force,
+            jobs,
+        })
+    }
+
+    pub fn run(&self) -> Result<(), String> {
+        let started = Instant::now();
+        let outcomes = self.render();
+
+        for (options, outcome) in self.jobs.iter().zip(&outcomes) {
+            match outcome {
+                Outcome::Rendered(seconds) => {
+                    println!("rendered   {:8.3} sec  {}", seconds, options.file)
+                }
+                Outcome::UpToDate => println!("up to date              {}", options.file),
+                Outcome::Failed(message) => println!("failed                  {}", message),
+            }
+        }
+        let count = |matches: fn(&Outcome) -> bool| outcomes.iter().filter(|o| matches(o)).count();
+        let failed = count(|outcome| matches!(outcome, Outcome::Failed(_)));
+        println!(
+            "{} jobs in {:.3} sec ({} threads): {} rendered, {} up to date, {} failed",
+            outcomes.len(),
+            started.elapsed().as_secs_f64(),
+            self.threads,
+            count(|outcome| matches!(outcome, Outcome::Rendered(_))),
+            count(|outcome| *outcome == Outcome::UpToDate),
+            failed
+        );
+
+        if failed > 0 {
+            return Err(format!("{} of {} jobs failed", failed, outcomes.len()));
+        }
+        Ok(())
+    }
+
+    /// Renders the jobs that aren't up to date in order, one failing doesn't stop the others
+    pub fn render(&self) -> Vec<Outcome> {
+        let job_file_modified = fs::metadata(&self.file)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build()
+            .expect("error creating thread pool")
+            .install(|| {
+                self.jobs
+                    .iter()
+                    .map(|options| {
+                        let up_to_date = job_file_modified
+                            .is_some_and(|modified| is_up_to_date(options, modified));
+                        if up_to_date && !self.force {
+                            return Outcome::UpToDate;
+                        }
+                        let started = Instant::now();
+                        match render_job(options) {
+                            Ok(()) => Outcome::Rendered(started.elapsed().as_secs_f64()),
+                            Err(message) => Outcome::Failed(message),
+                        }
+                    })
+                    .collect()
+            })
+    }
+}
+
+/// Every size of every job parsed the way the command line is,
+/// so a mistake in the last job shows before the first one is rendered
+fn parse_jobs(text: &str, json: bool) -> Result<Vec<Options>, String> {
+    let job_file: JobFile = if json {
+        serde_json::from_str(text).map_err(|e| e.to_string())?
+    } else {
+        toml::from_str(text).map_err(|e| e.to_string())?
+    };
+
+    let mut jobs: Vec<Options> = Vec::new();
+    for (index, job) in job_file.jobs.iter().enumerate() {
+        let error = |e: String| format!("job {} ({}): {}", index + 1, job.output, e);
+        for args in job.render_args(&job_file.options).map_err(error)? {
+            for flag in ["--mode", "--threads", "--stream", "--from-image"] {
+                if args.iter().any(|arg| arg == flag) {
+                    return Err(error(format!(
+                        "{} doesn't apply to batch jobs, they render in tiles on the batch threads",
+                        flag
+                    )));
+                }
+            }
+            let options = cli::parse_args(args).map_err(error)?;
+            check_job(&options).map_err(error)?;
+            if jobs.iter().any(|other| other.file == options.file) {
+                return Err(error(format!(
+                    "'{}' is the output of another job",
+                    options.file
+                )));
+            }
+            jobs.push(options);
+        }
+    }
+    Ok(jobs)
+}
+
+/// Jobs render whole images in memory
+fn check_job(options: &Options) -> Result<(), String> {
+    if options.file.ends_with(RAW_EXTENSION) {
+        return Err("batch jobs render images, not escape data".to_string());
+    }
+    // parse_args streams the images over STREAM_PIXELS, --stream itself is rejected earlier
+    if options.stream || options.bounds.0.saturating_mul(options.bounds.1) > STREAM_PIXELS {
+        return Err(
+            "image is too large for a batch, render it on its own with --stream".to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// True when the output is newer than the job file, and for PNGs, was rendered
+/// with the view and the options of the job
+fn is_up_to_date(options: &Options, job_file_modified: SystemTime) -> bool {
+    let modified = match fs::metadata(&options.file).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return false,
+    };
+    if modified < job_file_modified {
+        return false;
+    }
+    match Format::from_path(&options.file) {
+        Ok(Format::Png) => read_metadata(&options.file) == Ok(options.metadata()),
+        _ => true,
+    }
+}
+
+/// Renders the job in tiles on the pool it runs on and writes the image,
+/// the directory of the output is created when it is missing
+fn render_job(options: &Options) -> Result<(), String> {
+    if let Some(directory) = Path::new(&options.file).parent() {
+        fs::create_dir_all(directory)
+            .map_err(|e| format!("can't create directory of '{}': {}", options.file, e))?;
+    }
+    let mut pixels = vec![0; options.bounds.0 * options.bounds.1 * CHANNELS];
+    tiles::render_multi_thread_tiles(&mut pixels, options, false);
+    write_image(
+        &options.file,
+        &pixels,
+        options.bounds,
+        options.bit_depth,
+        &options.metadata(),
+    )
+}
+
+#[test]
+fn test_parse_jobs() {
+    let toml = r#"
+        options = "--max-iter 100"
+
+        [[jobs]]
+        output = "renders/whole-{size}.png"
+        size = ["40x30", "80x60"]
+        upper_left = "-2.2,1.2"
+        lower_right = "0.8,-1.2"
+
+        [[jobs]]
+        output = "renders/seahorse.ppm"
+        size = "40x30"
+        center = "-0.745,0.105"
+        zoom = 100
+        palette = "fire"
+        options = "--smooth --max-iter 500"
+    "#;
+    let json = r#"{
+        "options": "--max-iter 100",
+        "jobs": [
+            {
+                "output": "renders/whole-{size}.png",
+                "size": ["40x30", "80x60"],
+                "upper_left": "-2.2,1.2",
+                "lower_right": "0.8,-1.2"
+            },
+            {
+                "output": "renders/seahorse.ppm",
+                "size": "40x30",
+                "center": "-0.745,0.105",
+                "zoom": 100,
+                "palette": "fire",
+                "options": "--smooth --max-iter 500"
+            }
+        ]
+    }"#;
+    let jobs = parse_jobs(toml, false).unwrap();
+    assert_eq!(parse_jobs(json, true).unwrap(), jobs);
+
+    let files: Vec<&str> = jobs.iter().map(|options| options.file.as_str()).collect();
+    assert_eq!(
+        files,
+        [
+            "renders/whole-40x30.png",
+            "renders/whole-80x60.png",
+            "renders/seahorse.ppm"
+        ]
+    );
+    assert_eq!(jobs[1].bounds, (80, 60));
+    assert_eq!(jobs[1].max_iter, 100);
+    // A job's own options come after the common ones
+    let expected = cli::parse_args(
+        "renders/seahorse.ppm 40x30 --center -0.745,0.105 --zoom 100 --palette fire --smooth --max-iter 500"
+            .split_whitespace()
+            .map(String::from),
+    )
+    .unwrap();
+    assert_eq!(jobs[2], expected);
+
+    // Every job is checked, the first error names its job
+    let job = |fields: &str| format!("[[jobs]]\noutput = \"a.png\"\nsize = \"40x30\"\n{}", fields);
+    let view = "upper_left = \"-2.2,1.2\"\nlower_right = \"0.8,-1.2\"";
+    assert!(parse_jobs(&job(view), false).is_ok());
+    for (fields, message) in [
+        ("", "expected upper_left"),
+        ("center = \"0,0\"", "expected upper_left"),
+        (
+            "upper_left = \"-2.2;1.2\"\nlower_right = \"0.8,-1.2\"",
+            "upper left",
+        ),
+        (&format!("{}\nzoom = 2", view), "expected upper_left"),
+        (&format!("{}\noptions = \"--mode rayon\"", view), "--mode"),
+        (&format!("{}\noptions = \"--stream\"", view), "--stream"),
+        (&format!("{}\npalette = \"plaid\"", view), "plaid"),
+    ] {
+        let error = parse_jobs(&format!("{}\n{}", job(view), job(fields)), false).unwrap_err();
+        assert!(
+            error.starts_with("job 2 (a.png): ") && error.contains(message),
+            "{}: {}",
+            fields,
+            error
+        );
+    }
+    let error = parse_jobs(&job(&format!("{}\ncolor = \"red\"", view)), false).unwrap_err();
+    assert!(error.contains("unknown field"), "{}", error);
+    let sizes = |output: &str, size: &str| {
+        parse_jobs(
+            &format!(
+                "[[jobs]]\noutput = \"{}\"\nsize = {}\n{}",
+                output, size, view
+            ),
+            false,
+        )
+    };
+    assert!(sizes("a.png", "\"40x\"").is_err());
+    assert!(sizes("a.png", "[]").is_err());
+    assert!(sizes("a.png", "[\"40x30\", \"80x60\"]").is_err());
+    assert!(sizes("a-{size}.png", "[\"40x30\", \"40x30\"]").is_err());
+    assert!(sizes("a.raw", "\"40x30\"").is_err());
+    assert!(sizes("a.png", "\"40000x30000\"").is_err());
+    assert!(sizes("a.png", "\"4294967296x4294967296\"").is_err());
+}
+
+#[test]
+fn test_batch_render() {
+    let directory = std::env::temp_dir().join(format!("mandelbrot-batch-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+    fs::write(
+        path("jobs.toml"),
+        format!(
+            r#"
+            options = "--max-iter 100 --palette fire"
+            [[jobs]]
+            output = "{}"
+            size = ["40x30", "60x45"]
+            upper_left = "-2.2,1.2"
+            lower_right = "0.8,-1.2"
+            [[jobs]]
+            output = "{}"
+            size = "40x30"
+            center = "-0.75,0.1"
+            zoom = 4
+            "#,
+            path("out/whole-{size}.png"),
+            path("out/zoom.ppm")
+        ),
+    )
+    .unwrap();
+
+    let batch = Batch::read(&path("jobs.toml"), 3, false).unwrap();
+    let rendered = |outcomes: &[Outcome]| {
+        outcomes
+            .iter()
+            .map(|outcome| matches!(outcome, Outcome::Rendered(_)))
+            .collect::<Vec<bool>>()
+    };
+    assert_eq!(rendered(&batch.render()), [true, true, true]);
+
+    // Jobs give the images of single renders, the output directory is created for them
+    for options in &batch.jobs {
+        let expected = path("expected.ppm");
+        let pixels = crate::render_image(options, false);
+        write_image(&expected, &pixels, options.bounds, 8, &[]).unwrap();
+        let mut written = options.clone();
+        written.file = path("written.ppm");
+        render_job(&written).unwrap();
+        assert!(fs::read(&expected).unwrap() == fs::read(path("written.ppm")).unwrap());
+    }
+
+    // Up to date outputs are skipped, unless forced
+    assert_eq!(batch.render(), vec![Outcome::UpToDate; 3]);
+    let forced = Batch {
+        force: true,
+        ..batch.clone()
+    };
+    assert_eq!(rendered(&forced.render()), [true, true, true]);
+
+    // Outputs older than the job file and PNGs of other options are rendered again
+    fs::File::options()
+        .write(true)
+        .open(path("out/zoom.ppm"))
+        .unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let mut changed = batch.clone();
+    changed.jobs[0].max_iter = 200;
+    assert_eq!(rendered(&changed.render()), [true, false, true]);
+
+    // A failing job doesn't stop the others
+    let mut failing = forced.clone();
+    failing.jobs[1].file = path("jobs.toml/whole.png");
+    let outcomes = failing.render();
+    assert!(matches!(outcomes[1], Outcome::Failed(_)));
+    assert_eq!(rendered(&outcomes), [true, false, true]);
+    assert!(failing.run().is_err());
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,270 @@
+use num::Complex;
+use rayon::prelude::*;
+use std::time::Instant;
+
+use crate::cli::Options;
+use crate::fractal::Fractal;
+use crate::interior;
+use crate::output::write_image;
+use crate::supersample::split_mix;
+use crate::{in_thread_pool, turn, CHANNELS};
+
+/// Dots are sampled from the square of this half side around zero,
+/// orbits of the dots outside of it leave the circle of radius 2 at once
+const SAMPLE_RADIUS: f64 = 2.0;
+
+/// Samples traced by a rayon job, each chunk has a random sequence of its own
+const CHUNK_SAMPLES: usize = 1 << 14;
+
+/// Orbit density image: random dots are iterated, and the z of every orbit
+/// that escapes is counted in the pixel it falls in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Buddhabrot {
+    /// View, fractal, threads, palette and bit depth of the image
+    pub options: Options,
+    /// Random dots traced per pixel, more of them give less noise
+    pub samples: usize,
+    /// The same seed gives the same image on any number of threads
+    pub seed: u64,
+    /// Nebulabrot iteration limits of the red, green and blue channels,
+    /// None for a Buddhabrot of options.max_iter colored by the palette
+    pub nebula: Option<[usize; CHANNELS]>,
+}
+
+impl Buddhabrot {
+    pub fn run(&self) -> Result<(), String> {
+        let options = &self.options;
+        let started = Instant::now();
+        let pixels = self.pixels();
+        println!(
+            "Traced {} orbits for {}x{} in {:.3} sec ({} threads, {} iterations)",
+            self.total_samples(),
+            options.bounds.0,
+            options.bounds.1,
+            started.elapsed().as_secs_f64(),
+            options.threads,
+            self.limits().iter().max().unwrap()
+        );
+        write_image(
+            &options.file,
+            &pixels,
+            options.bounds,
+            options.bit_depth,
+            &[],
+        )
+    }
+
+    /// Checked by parse_buddhabrot to fit in usize
+    fn total_samples(&self) -> usize {
+        self.samples * self.options.bounds.0 * self.options.bounds.1
+    }
+
+    fn limits(&self) -> [usize; CHANNELS] {
+        self.nebula.unwrap_or([self.options.max_iter; CHANNELS])
+    }
+
+    /// Visits of the orbits, CHANNELS counts per pixel for the iteration limits
+    /// of the channels. The chunks of samples are split into a run per thread,
+    /// every run traces into a histogram of its own and they are added up at the end.
+    pub fn histogram(&self) -> Vec<u32> {
+        let (width, height) = self.options.bounds;
+        let total = self.total_samples();
+        let chunks = total.div_ceil(CHUNK_SAMPLES);
+        let threads = self.options.threads.clamp(1, chunks.max(1));
+        let run = chunks.div_ceil(threads);
+        let mut histogram = Vec::new();
+
+        in_thread_pool(threads, || {
+            histogram = (0..threads)
+                .into_par_iter()
+                .map(|thread| {
+                    let mut histogram = vec![0; width * height * CHANNELS];
+                    for chunk in thread * run..chunks.min((thread + 1) * run) {
+                        let samples = CHUNK_SAMPLES.min(total - chunk * CHUNK_SAMPLES);
+                        self.trace_chunk(&mut histogram, chunk, samples);
+                    }
+                    histogram
+                })
+                .reduce_with(|mut sum, histogram| {
+                    for (count, other) in sum.iter_mut().zip(histogram) {
+                        *count = count.saturating_add(other);
+                    }
+                    sum
+                })
+                .unwrap();
+        });
+        histogram
+    }
+
+    /// Traces samples random dots, the sequence is picked by the seed and the chunk,
+    /// so it doesn't matter which thread traces the chunk
+    fn trace_chunk(&self, histogram: &mut [u32], chunk: usize, samples: usize) {
+        let options = &self.options;
+        let limits = self.limits();
+        let limit = *limits.iter().max().unwrap();
+        let mandelbrot = options.fractal == Fractal::Mandelbrot;
+
+        let mut state = self.seed ^ (chunk as u64).wrapping_mul(0xd1b5_4a32_d192_ed03);
+        let mut random = || {
+            let unit = (split_mix(&mut state) >> 11) as f64 / (1u64 << 53) as f64;
+            (unit * 2.0 - 1.0) * SAMPLE_RADIUS
+        };
+
+        for _ in 0..samples {
+            let c = Complex {
+                re: random(),
+                im: random(),
+            };
+            if options.cardioid && mandelbrot && interior::in_cardioid_or_bulb(c) {
+                continue;
+            }
+            let escape = match options.fractal.escape_time(c, limit, options.periodicity) {
+                Some(escape) => escape,
+                None => continue,
+            };
+
+            // Channels whose limit the orbit escaped within, all of them for a Buddhabrot
+            let channels = limits.map(|channel_limit| escape < channel_limit);
+            let mut z = Complex { re: 0.0, im: 0.0 };
+            for _ in 1..escape {
+                z = options.fractal.step(z, c);
+                if let Some(pixel) = dot_to_pixel(z, options) {
+                    for channel in 0..CHANNELS {
+                        if channels[channel] {
+                            let count = &mut histogram[pixel * CHANNELS + channel];
+                            *count = count.saturating_add(1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// RGB pixels of the histogram. A Buddhabrot colors the density by the palette,
+    /// a Nebulabrot makes every channel its own density.
+    /// Densities are relative to the densest pixel of the channel.
+    pub fn pixels(&self) -> Vec<u16> {
+        let histogram = self.histogram();
+        let mut max = [0; CHANNELS];
+        for pixel in histogram.chunks(CHANNELS) {
+            for channel in 0..CHANNELS {
+                max[channel] = max[channel].max(pixel[channel]);
+            }
+        }
+        let density = |count: u32, channel: usize| {
+            if max[channel] == 0 {
+                return 0.0;
+            }
+            count as f64 / max[channel] as f64
+        };
+
+        let mut pixels = Vec::with_capacity(histogram.len());
+        for pixel in histogram.chunks(CHANNELS) {
+            match self.nebula {
+                Some(_) => {
+                    for (channel, &count) in pixel.iter().enumerate() {
+                        let value = density(count, channel) * u16::MAX as f64;
+                        pixels.push(value.round() as u16);
+                    }
+                }
+                None => {
+                    let color = self.options.palette.color(density(pixel[0], 0));
+                    pixels.extend_from_slice(&color);
+                }
+            }
+        }
+        pixels
+    }
+}
+
+/// Index of the pixel the dot falls in, None outside of the view.
+/// The inverse of convert_pixel_to_dot and the rotation.
+fn dot_to_pixel(dot: Complex<f64>, options: &Options) -> Option<usize> {
+    let (upper_left, lower_right) = (options.upper_left, options.lower_right);
+    let dot = turn(dot, upper_left, lower_right, -options.rotate);
+    let col = (dot.re - upper_left.re) / (lower_right.re - upper_left.re) * options.bounds.0 as f64;
+    let row = (upper_left.im - dot.im) / (upper_left.im - lower_right.im) * options.bounds.1 as f64;
+    if !(col >= 0.0 && row >= 0.0) {
+        return None;
+    }
+    let (col, row) = (col as usize, row as usize);
+    (col < options.bounds.0 && row < options.bounds.1).then_some(row * options.bounds.0 + col)
+}
+
+#[cfg(test)]
+fn test_buddhabrot(flags: &str) -> Buddhabrot {
+    match crate::cli::parse_command(
+        format!(
+            "buddhabrot buddha.png 40x30 -2.2,1.2 0.8,-1.2 --samples 20 {}",
+            flags
+        )
+        .split_whitespace()
+        .map(String::from),
+    )
+    .unwrap()
+    {
+        crate::cli::Command::Buddhabrot(buddhabrot) => buddhabrot,
+        _ => panic!("buddhabrot expected"),
+    }
+}
+
+#[test]
+fn test_dot_to_pixel() {
+    for flags in ["", "--rotate 30"] {
+        let options = test_buddhabrot(flags).options;
+        let (upper_left, lower_right) = (options.upper_left, options.lower_right);
+        for (col, row) in [(0, 0), (17, 5), (39, 29)] {
+            // Middle of the pixel, its corners could round into the neighbours
+            let middle = (2 * col + 1, 2 * row + 1);
+            let dot = crate::convert_pixel_to_dot((80, 60), middle, upper_left, lower_right);
+            let dot = turn(dot, upper_left, lower_right, options.rotate);
+            assert_eq!(
+                dot_to_pixel(dot, &options),
+                Some(row * 40 + col),
+                "{}",
+                flags
+            );
+        }
+        assert_eq!(dot_to_pixel(Complex { re: 2.0, im: 0.0 }, &options), None);
+    }
+}
+
+#[test]
+fn test_histogram() {
+    // Seeded chunks give the same histogram on any number of threads
+    let buddhabrot = test_buddhabrot("--max-iter 50 --seed 7 --threads 1");
+    let histogram = buddhabrot.histogram();
+    assert!(histogram.iter().any(|&count| count > 0));
+    assert!(histogram == test_buddhabrot("--max-iter 50 --seed 7 --threads 3").histogram());
+    assert!(histogram != test_buddhabrot("--max-iter 50 --seed 8").histogram());
+
+    // Dots that don't escape add nothing, skipping them early changes nothing
+    let faster = test_buddhabrot("--max-iter 50 --seed 7 --cardioid --periodicity");
+    assert!(histogram == faster.histogram());
+
+    // A channel of a higher limit gets the orbits of the lower ones and more
+    let nebula = test_buddhabrot("--nebula 200,50,10").histogram();
+    assert!(nebula
+        .chunks(CHANNELS)
+        .all(|pixel| pixel[0] >= pixel[1] && pixel[1] >= pixel[2]));
+    assert!(nebula.chunks(CHANNELS).any(|pixel| pixel[0] > pixel[2]));
+}
+
+#[test]
+fn test_pixels() {
+    let pixels = test_buddhabrot("--palette fire").pixels();
+    assert_eq!(pixels.len(), 40 * 30 * CHANNELS);
+    let fire = crate::palette::Palette::builtin("fire").unwrap();
+    assert!(pixels
+        .chunks(CHANNELS)
+        .any(|pixel| pixel == fire.color(1.0)));
+
+    let nebula = test_buddhabrot("--nebula 200,50,10").pixels();
+    for channel in 0..CHANNELS {
+        assert!(nebula
+            .iter()
+            .skip(channel)
+            .step_by(CHANNELS)
+            .any(|&value| value == u16::MAX));
+    }
+}
@@ -0,0 +1,1439 @@
+use num::Complex;
+use std::io::{self, IsTerminal};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::animation::{Animation, Easing, BASE_WIDTH};
+use crate::batch::Batch;
+use crate::buddhabrot::Buddhabrot;
+use crate::deep::{offset_decimal, Perturbation};
+use crate::distance::Coloring;
+use crate::fractal::Fractal;
+use crate::output::{self, Format};
+use crate::palette::Palette;
+use crate::raw::{self, Colorize, RAW_EXTENSION};
+use crate::serve::Explorer;
+use crate::stream::STREAM_PIXELS;
+use crate::supersample::Supersampling;
+use crate::{parse_complex, parse_pair, Mode, RenderOptions};
+
+pub const USAGE: &str = "\
+Usage: mandelbrot <file.png> <width>x<height> <upper_left_coordinate> <lower_right_coordinate> [options]
+       mandelbrot <file.png> <width>x<height> --center <re,im> --zoom <zoom> [options]
+  --center <re,im>    dot in the middle of the image, instead of the corners
+  --zoom <zoom>       with --center, at zoom 1 the image is 3 wide and as high as its aspect says
+  --rotate <degrees>  turns the view counterclockwise around its middle
+  --mode <mode>       single, crossbeam, rayon or tiles (default single),
+                      tiles balances best and shows progress
+  --threads <n>       threads used by the parallel modes (default number of CPUs)
+  --fractal <name>    mandelbrot, julia:re,im, burning-ship, tricorn or multibrot:d (default mandelbrot)
+  --max-iter <n>      iterations before a point is considered inside the set (default 255)
+  --palette <name>    gray, fire, ocean, ultra, rainbow, a file with a rrggbb color per line
+                      or rrggbb colors separated by commas (default gray)
+  --smooth            color by normalized iteration count instead of whole iterations, removes banding
+  --coloring <c>      escape colors by escape time, distance also darkens the dots
+                      within a pixel or so of the set, which shows its thin filaments,
+                      shaded lights the set as if it was embossed (default escape);
+                      mandelbrot, julia and multibrot without --deep only
+  --bit-depth <n>     bits per color channel, 8 or 16 for .png, .tiff and .ppm (default 8)
+  --stream            render and write a band of rows at a time, memory use stays the same for
+                      any image size, .png and .ppm only; images over 134 megapixels are streamed
+                      without it. --mode picks single or multi threaded bands only
+  --from-image <file.png>
+                      renders the view and the options kept in an image rendered before,
+                      options given next to it take their place; single renders only
+  --deep              render by perturbation with arbitrary precision, turned on by itself
+                      when f64 can't tell the pixels apart, mandelbrot only
+  --cardioid          skip iterating dots in the main cardioid and the period 2 bulb,
+                      mandelbrot without --deep only
+  --periodicity       stop iterating dots whose orbit repeats, not used with --deep
+  --fill              fill rectangles whose edge is in the set without iterating them,
+                      mandelbrot only
+  --supersample <s>   anti-aliasing by averaging several dots per pixel: NxN grid, jitter:n
+                      random dots or adaptive:NxN for the pixels on color edges only (default off),
+                      not used with --fill
+  --cardioid, --periodicity and --fill only make renders faster. --cardioid and --periodicity
+  keep the image exactly the same, --fill can miss a lone dot outside the set between two edge pixels.
+  The image format follows the file extension: .png, .jpg, .webp, .tiff or .ppm.
+  PNGs keep the corners and the options of the render in text chunks.
+  A <file.raw> saves the iteration count and final |z| of every pixel instead of colors,
+  colorize turns it into an image. --mode, --fill, --supersample and --coloring don't apply to it.
+Example: mandelbrot mandel.png 4000x3000 -1.08,0.28 -1.03,0.23 --mode crossbeam --threads 8
+
+Usage: mandelbrot animate <directory> <width>x<height> --target <re,im> --zoom-end <zoom> [options]
+  --target <re,im>    dot the animation zooms into, it stays in the middle of the frames
+  --zoom-start <zoom> zoom of the first frame, at zoom 1 the view is 3 wide (default 1)
+  --zoom-end <zoom>   zoom of the last frame
+  --frames <n>        number of frames (default 100)
+  --easing <easing>   linear, ease-in, ease-out or ease-in-out (default linear)
+  --apng <file.png>   also join the frames into an animated PNG
+  --gif <file.gif>    also join the frames into an animated GIF of 256 colors
+  --fps <n>           frames per second of the animated PNG or GIF (default 30)
+  Render options above apply to every frame, --mode defaults to rayon.
+  Frames already in the directory are kept, so an interrupted animation can be resumed.
+Example: mandelbrot animate frames 640x480 --target -1.7499,0.00001 --zoom-end 1e6 --frames 120
+
+Usage: mandelbrot serve [options]
+  --host <address>    address to listen on, 0.0.0.0 for every interface (default 127.0.0.1)
+  --port <n>          port of the explorer page, http://localhost:<port> (default 8080)
+  --tile-size <n>     side of the square map tiles in pixels (default 256)
+  --cache <n>         rendered tiles kept in memory (default 1024)
+  Render options above apply to every tile.
+Example: mandelbrot serve --palette ultra --smooth --max-iter 1000
+
+Usage: mandelbrot colorize <file.raw> <file.png> [options]
+  --palette <name>    palette as in renders (default gray)
+  --bit-depth <n>     bits per color channel as in renders (default 8)
+  --smooth            color by normalized iteration count
+  --equalize          histogram equalization, each palette color goes to as many pixels
+                      instead of as many iterations
+  --gamma <g>         palette positions are raised to 1/g, above 1 more colors go to
+                      the dots that escape fast (default 1)
+Example: mandelbrot colorize mandel.raw mandel.png --palette ultra --smooth --equalize
+
+Usage: mandelbrot buddhabrot <file.png> <width>x<height> <upper_left_coordinate> <lower_right_coordinate> [options]
+  --samples <n>       random dots traced per pixel, more give less noise (default 50)
+  --seed <n>          seed of the random dots, the same seed gives the same image (default 0)
+  --nebula <r,g,b>    Nebulabrot: iteration limits of the red, green and blue channels
+                      instead of one --max-iter colored by the palette
+  Orbits of the dots that escape are counted in the pixels they pass, the image is their density.
+  View options, --fractal, --max-iter, --palette, --threads, --bit-depth, --cardioid and
+  --periodicity are the ones of renders, --palette defaults to fire; julia and the other
+  render options don't apply.
+Example: mandelbrot buddhabrot nebula.png 1000x1000 --center -0.4,0 --zoom 1 --rotate 90 --nebula 5000,500,50
+
+Usage: mandelbrot batch <jobs.toml> [options]
+  --threads <n>       threads shared by all jobs (default number of CPUs)
+  --force             render the outputs that are up to date as well
+  The job file is TOML, or JSON when it ends in .json. Its options are render options of every job,
+  each of its jobs has an output, a size or a list of sizes, upper_left and lower_right
+  or center and zoom, and optionally a palette and options of its own:
+    options = \"--max-iter 1000 --smooth\"
+    [[jobs]]
+    output = \"renders/seahorse-{size}.png\"
+    size = [\"1000x750\", \"4000x3000\"]
+    center = \"-0.745,0.105\"
+    zoom = 100
+    palette = \"ultra\"
+  Every job is checked before the first one is rendered. Jobs are rendered one after another
+  in tiles on all the threads, --mode, --threads and --stream don't apply to them. Outputs newer than the job file that keep
+  the view and options of their job are up to date and skipped. A summary comes at the end.
+Example: mandelbrot batch jobs.toml --threads 8";
+
+/// Options that take no value
+const SWITCHES: [&str; 6] = [
+    "--smooth",
+    "--deep",
+    "--cardioid",
+    "--periodicity",
+    "--fill",
+    "--stream",
+];
+
+pub enum Command {
+    Render(Options),
+    Animate(Animation),
+    Serve(Explorer),
+    Colorize(Colorize),
+    Buddhabrot(Buddhabrot),
+    Batch(Batch),
+}
+
+/// Everything a render needs, parse_args builds it from the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub file: String,
+    pub bounds: (usize, usize),
+    /// Relative to the deep zoom center when deep is set
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>,
+    pub mode: Mode,
+    pub threads: usize,
+    pub max_iter: usize,
+    pub fractal: Fractal,
+    pub palette: Palette,
+    pub smooth: bool,
+    pub coloring: Coloring,
+    pub deep: Option<Perturbation>,
+    pub cardioid: bool,
+    pub periodicity: bool,
+    pub fill: bool,
+    pub supersampling: Supersampling,
+    /// Degrees the view is turned counterclockwise around its middle
+    pub rotate: f64,
+    /// Bits per channel of the image, 8 or 16
+    pub bit_depth: u8,
+    /// Rendered and written a band of rows at a time instead of as a whole image
+    pub stream: bool,
+}
+
+impl Options {
+    /// Options of a render of the view between the corners, "re,im" strings that
+    /// keep all their digits for deep zooms, into an 8 bit image that isn't streamed
+    pub fn new(
+        file: String,
+        bounds: (usize, usize),
+        corners: (&str, &str),
+        rotate: f64,
+        render: RenderOptions,
+    ) -> Result<Options, String> {
+        if render.threads == 0 {
+            return Err("--threads must be greater than zero".to_string());
+        }
+        if render.max_iter == 0 {
+            return Err("--max-iter must be greater than zero".to_string());
+        }
+        let fractal = render.fractal;
+        if render.fill && fractal != Fractal::Mandelbrot {
+            return Err(format!("--fill is not supported for {}", fractal));
+        }
+        if render.fill && render.supersampling != Supersampling::Off {
+            return Err("--fill can't be used with --supersample".to_string());
+        }
+        if render.coloring != Coloring::Escape && !fractal.has_derivative() {
+            return Err(format!(
+                "--coloring {} is not supported for {}",
+                render.coloring, fractal
+            ));
+        }
+        if !rotate.is_finite() {
+            return Err(format!("invalid rotation {}", rotate));
+        }
+
+        let upper_left = parse_complex(corners.0)
+            .ok_or_else(|| format!("error parsing upper left dot '{}'", corners.0))?;
+        let lower_right = parse_complex(corners.1)
+            .ok_or_else(|| format!("error parsing lower right dot '{}'", corners.1))?;
+
+        let deep = if render.deep || needs_deep_zoom(bounds, upper_left, lower_right) {
+            if fractal != Fractal::Mandelbrot {
+                return Err(format!("deep zoom is not supported for {}", fractal));
+            }
+            if render.coloring != Coloring::Escape {
+                return Err(format!(
+                    "--coloring {} is not supported with deep zoom",
+                    render.coloring
+                ));
+            }
+            Some(Perturbation::new(
+                corners.0,
+                corners.1,
+                bounds,
+                render.max_iter,
+            )?)
+        } else {
+            None
+        };
+        let (upper_left, lower_right) = match &deep {
+            Some(deep) => (deep.upper_left, deep.lower_right),
+            None => (upper_left, lower_right),
+        };
+
+        Ok(Options {
+            file,
+            bounds,
+            upper_left,
+            lower_right,
+            mode: render.mode,
+            threads: render.threads,
+            max_iter: render.max_iter,
+            fractal,
+            palette: render.palette,
+            smooth: render.smooth,
+            coloring: render.coloring,
+            deep,
+            cardioid: render.cardioid,
+            periodicity: render.periodicity,
+            fill: render.fill,
+            supersampling: render.supersampling,
+            rotate,
+            bit_depth: 8,
+            stream: false,
+        })
+    }
+
+    /// Width of a pixel in dots over its height, the image is stretched unless it is 1
+    pub fn aspect_distortion(&self) -> f64 {
+        let pixel_width = (self.lower_right.re - self.upper_left.re).abs() / self.bounds.0 as f64;
+        let pixel_height = (self.upper_left.im - self.lower_right.im).abs() / self.bounds.1 as f64;
+        pixel_width / pixel_height
+    }
+
+    /// Corners as "re,im" strings, deep corners keep all the digits of the center
+    pub fn corner_args(&self) -> (String, String) {
+        let corner = |dot: Complex<f64>| match &self.deep {
+            Some(deep) => format!(
+                "{},{}",
+                offset_decimal(&deep.center.re.to_string(), dot.re).unwrap(),
+                offset_decimal(&deep.center.im.to_string(), dot.im).unwrap()
+            ),
+            None => format!("{},{}", dot.re, dot.im),
+        };
+        (corner(self.upper_left), corner(self.lower_right))
+    }
+
+    /// Options that change how the image looks, the ones that make it faster are left out
+    pub fn view_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "--fractal".to_string(),
+            self.fractal.to_string(),
+            "--max-iter".to_string(),
+            self.max_iter.to_string(),
+            "--palette".to_string(),
+            self.palette.to_string(),
+        ];
+        if self.smooth {
+            args.push("--smooth".to_string());
+        }
+        if self.coloring != Coloring::Escape {
+            args.extend(["--coloring".to_string(), self.coloring.to_string()]);
+        }
+        if self.deep.is_some() {
+            args.push("--deep".to_string());
+        }
+        if self.supersampling != Supersampling::Off {
+            args.extend(["--supersample".to_string(), self.supersampling.to_string()]);
+        }
+        if self.rotate != 0.0 {
+            args.extend(["--rotate".to_string(), self.rotate.to_string()]);
+        }
+        args
+    }
+
+    /// Render parameters kept in the text chunks of PNG images.
+    /// Corners and Render options are what --from-image renders again.
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        let (upper_left, lower_right) = self.corner_args();
+        [
+            ("Software", "mandelbrot".to_string()),
+            ("Size", format!("{}x{}", self.bounds.0, self.bounds.1)),
+            ("Corners", format!("{} {}", upper_left, lower_right)),
+            ("Fractal", self.fractal.to_string()),
+            ("Max iterations", self.max_iter.to_string()),
+            ("Palette", self.palette.to_string()),
+            ("Render options", self.view_args().join(" ")),
+        ]
+        .into_iter()
+        .map(|(keyword, text)| (keyword.to_string(), text))
+        .collect()
+    }
+}
+
+/// Picks the subcommand by the first argument, no subcommand means a single render
+pub fn parse_command<I>(args: I) -> Result<Command, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter().peekable();
+    match args.peek().map(String::as_str) {
+        Some("animate") => parse_animation(args.skip(1)).map(Command::Animate),
+        Some("serve") => parse_serve(args.skip(1)).map(Command::Serve),
+        Some("colorize") => parse_colorize(args.skip(1)).map(Command::Colorize),
+        Some("buddhabrot") => parse_buddhabrot(args.skip(1)).map(Command::Buddhabrot),
+        Some("batch") => parse_batch(args.skip(1)).map(Command::Batch),
+        _ => parse_args(from_image_args(args.collect())?).map(Command::Render),
+    }
+}
+
+impl Command {
+    pub fn run(&self) -> Result<(), String> {
+        match self {
+            Command::Render(options) => {
+                warn_if_stretched(options);
+                if options.file.ends_with(RAW_EXTENSION) {
+                    raw::export(options)
+                } else {
+                    render_to_file(options)
+                }
+            }
+            Command::Animate(animation) => animation.run(),
+            Command::Serve(explorer) => explorer.run(),
+            Command::Colorize(colorize) => colorize.run(),
+            Command::Buddhabrot(buddhabrot) => buddhabrot.run(),
+            Command::Batch(batch) => batch.run(),
+        }
+    }
+}
+
+/// Corners that don't match the aspect of the image give stretched pixels,
+/// a few percent is hard to notice
+fn warn_if_stretched(options: &Options) {
+    let distortion = options.aspect_distortion();
+    if (distortion - 1.0).abs() > 0.01 {
+        eprintln!(
+            "warning: pixels are {:.1}% {} than they are high, the corners don't have the aspect of {}x{}; --center and --zoom keep it",
+            (distortion - 1.0).abs() * 100.0,
+            if distortion > 1.0 { "wider" } else { "narrower" },
+            options.bounds.0,
+            options.bounds.1
+        );
+    }
+}
+
+/// Renders options.file, streamed or as a whole image
+fn render_to_file(options: &Options) -> Result<(), String> {
+    let bounds = options.bounds;
+    if let Some(deep) = &options.deep {
+        println!(
+            "Deep zoom around {},{} with {} bits of precision",
+            deep.center.re, deep.center.im, deep.precision
+        );
+    }
+    let threads = if options.mode == Mode::Single {
+        1
+    } else {
+        options.threads
+    };
+
+    let started = Instant::now();
+    if options.stream {
+        crate::stream::render_streamed(options, io::stderr().is_terminal())?;
+        println!(
+            "Rendered and wrote {}x{} in {:.3} sec (bands of {} rows, {} threads, {} iterations)",
+            bounds.0,
+            bounds.1,
+            started.elapsed().as_secs_f64(),
+            crate::stream::band_rows(bounds.0),
+            threads,
+            options.max_iter
+        );
+        return Ok(());
+    }
+
+    let pixels = crate::render_image(options, io::stderr().is_terminal());
+    println!(
+        "Rendered {}x{} in {:.3} sec ({:?}, {} threads, {} iterations)",
+        bounds.0,
+        bounds.1,
+        started.elapsed().as_secs_f64(),
+        options.mode,
+        threads,
+        options.max_iter
+    );
+
+    output::write_image(
+        &options.file,
+        &pixels,
+        bounds,
+        options.bit_depth,
+        &options.metadata(),
+    )
+}
+
+/// Parses command line arguments (without the program name).
+/// Flags can go before, after or between the positional arguments.
+pub fn parse_args<I>(args: I) -> Result<Options, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut positional = Vec::new();
+    let mut render = RenderOptions::default();
+    let mut center = None;
+    let mut zoom = None;
+    let mut rotate: f64 = 0.0;
+    let mut bit_depth = 8;
+    let mut stream = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+
+        // Switches without a value
+        match arg.as_str() {
+            "--smooth" => {
+                render.smooth = true;
+                continue;
+            }
+            "--deep" => {
+                render.deep = true;
+                continue;
+            }
+            "--cardioid" => {
+                render.cardioid = true;
+                continue;
+            }
+            "--periodicity" => {
+                render.periodicity = true;
+                continue;
+            }
+            "--fill" => {
+                render.fill = true;
+                continue;
+            }
+            "--stream" => {
+                stream = true;
+                continue;
+            }
+            _ => {}
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+
+        match arg.as_str() {
+            "--mode" => render.mode = parse_flag(&arg, &value)?,
+            "--threads" => render.threads = parse_flag(&arg, &value)?,
+            "--max-iter" => render.max_iter = parse_flag(&arg, &value)?,
+            "--fractal" => render.fractal = parse_flag(&arg, &value)?,
+            "--palette" => render.palette = parse_flag(&arg, &value)?,
+            "--coloring" => render.coloring = parse_flag(&arg, &value)?,
+            "--supersample" => render.supersampling = parse_flag(&arg, &value)?,
+            "--center" => center = Some(value),
+            "--zoom" => zoom = Some(parse_flag::<f64>(&arg, &value)?),
+            "--rotate" => rotate = parse_flag(&arg, &value)?,
+            "--bit-depth" => bit_depth = parse_flag(&arg, &value)?,
+            _ => return Err(format!("unknown flag {}", arg)),
+        }
+    }
+
+    match (&center, zoom) {
+        (None, None) if positional.len() != 4 => {
+            return Err(format!(
+                "expected 4 positional arguments, got {}",
+                positional.len()
+            ))
+        }
+        (Some(_), Some(_)) if positional.len() != 2 => {
+            return Err(format!(
+                "expected file and image dimensions with --center, got {} positional arguments",
+                positional.len()
+            ))
+        }
+        (Some(_), None) | (None, Some(_)) => {
+            return Err("--center and --zoom go together".to_string())
+        }
+        _ => {}
+    }
+
+    if positional[0].ends_with(RAW_EXTENSION) {
+        if render.fill
+            || render.supersampling != Supersampling::Off
+            || render.coloring != Coloring::Escape
+        {
+            return Err(
+                "--fill, --supersample and --coloring can't be used for escape data".to_string(),
+            );
+        }
+    } else {
+        check_bit_depth(&positional[0], bit_depth)?;
+    }
+
+    let bounds = parse_pair::<usize>(&positional[1], 'x')
+        .ok_or_else(|| format!("error parsing image dimensions '{}'", positional[1]))?;
+    if bounds.0 == 0 || bounds.1 == 0 {
+        return Err(format!(
+            "image dimensions '{}' must not be zero",
+            positional[1]
+        ));
+    }
+    // Images too large for memory are streamed when their format allows it
+    let stream = if positional[0].ends_with(RAW_EXTENSION) {
+        if stream {
+            return Err("escape data can't be streamed".to_string());
+        }
+        false
+    } else if Format::from_path(&positional[0])?.has_row_writer() {
+        stream || bounds.0.saturating_mul(bounds.1) > STREAM_PIXELS
+    } else if stream {
+        return Err("--stream writes .png and .ppm images only".to_string());
+    } else {
+        false
+    };
+    if let (Some(center), Some(zoom)) = (center, zoom) {
+        if !(zoom > 0.0 && zoom.is_finite()) {
+            return Err("--zoom must be greater than zero".to_string());
+        }
+        let (upper_left, lower_right) = center
+            .split_once(',')
+            .and_then(|center| view_corners(center, zoom, bounds))
+            .ok_or_else(|| format!("error parsing center '{}'", center))?;
+        positional.extend([upper_left, lower_right]);
+    }
+
+    let options = Options::new(
+        positional[0].clone(),
+        bounds,
+        (&positional[2], &positional[3]),
+        rotate,
+        render,
+    )?;
+    Ok(Options {
+        bit_depth,
+        stream,
+        ..options
+    })
+}
+
+/// Replaces --from-image <file.png> by the options and the corners kept in the image,
+/// the other arguments go after the kept options so that they take their place.
+/// Single renders only, parse_args and the subcommands don't know the flag.
+fn from_image_args(mut args: Vec<String>) -> Result<Vec<String>, String> {
+    let index = match args.iter().position(|arg| arg == "--from-image") {
+        Some(index) => index,
+        None => return Ok(args),
+    };
+    let image = args
+        .get(index + 1)
+        .cloned()
+        .ok_or("missing value for --from-image")?;
+    args.drain(index..index + 2);
+
+    let metadata = output::read_metadata(&image)?;
+    let text = |keyword: &str| {
+        metadata
+            .iter()
+            .find(|(key, _)| key == keyword)
+            .map(|(_, text)| {
+                text.split_whitespace()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            })
+            .ok_or_else(|| format!("image '{}' has no {} to render from", image, keyword))
+    };
+
+    let mut expanded = text("Render options")?;
+    let centered = args.iter().any(|arg| arg == "--center");
+    expanded.extend(args);
+    if !centered {
+        expanded.extend(text("Corners")?);
+    }
+    Ok(expanded)
+}
+
+/// Images take 8 or 16 bits per channel, 16 in the formats that have them
+pub fn check_bit_depth(file: &str, bit_depth: u8) -> Result<(), String> {
+    let format = Format::from_path(file)?;
+    match bit_depth {
+        8 => Ok(()),
+        16 if format.has_16_bit() => Ok(()),
+        16 => Err(format!(
+            "{:?} images can't have 16 bits per channel",
+            format
+        )),
+        _ => Err("--bit-depth must be 8 or 16".to_string()),
+    }
+}
+
+/// Corners of the view around center at zoom, as "re,im" strings that keep
+/// all digits of the center. The height follows the aspect of the image bounds.
+/// None when the center is not a number.
+pub fn view_corners(
+    center: (&str, &str),
+    zoom: f64,
+    bounds: (usize, usize),
+) -> Option<(String, String)> {
+    let width = BASE_WIDTH / zoom;
+    let height = width * bounds.1 as f64 / bounds.0 as f64;
+
+    let upper_left = format!(
+        "{},{}",
+        offset_decimal(center.0, -width / 2.0)?,
+        offset_decimal(center.1, height / 2.0)?
+    );
+    let lower_right = format!(
+        "{},{}",
+        offset_decimal(center.0, width / 2.0)?,
+        offset_decimal(center.1, -height / 2.0)?
+    );
+    Some((upper_left, lower_right))
+}
+
+/// True when pixels are so small that f64 dots of neighbouring pixels
+/// are equal or differ in the last few bits only
+fn needs_deep_zoom(
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+) -> bool {
+    let pixel_size = f64::min(
+        (lower_right.re - upper_left.re).abs() / bounds.0 as f64,
+        (upper_left.im - lower_right.im).abs() / bounds.1 as f64,
+    );
+    let magnitude = [upper_left.re, upper_left.im, lower_right.re, lower_right.im]
+        .iter()
+        .fold(1.0, |max: f64, value| max.max(value.abs()));
+
+    pixel_size < magnitude * 2f64.powi(-45)
+}
+
+/// Parses animate arguments, the render options are kept as they are for the frames
+pub fn parse_animation<I>(args: I) -> Result<Animation, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut positional = Vec::new();
+    let mut target = None;
+    let mut zoom_start = 1.0;
+    let mut zoom_end = None;
+    let mut frames = 100;
+    let mut easing = Easing::Linear;
+    let mut apng = None;
+    let mut gif = None;
+    let mut fps = 30;
+    // Later --mode overrides this one
+    let mut render_args = vec!["--mode".to_string(), "rayon".to_string()];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        if SWITCHES.contains(&arg.as_str()) {
+            render_args.push(arg);
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+
+        match arg.as_str() {
+            "--target" => target = Some(value),
+            "--zoom-start" => zoom_start = parse_flag(&arg, &value)?,
+            "--zoom-end" => zoom_end = Some(parse_flag(&arg, &value)?),
+            "--frames" => frames = parse_flag(&arg, &value)?,
+            "--easing" => easing = parse_flag(&arg, &value)?,
+            "--apng" => apng = Some(value),
+            "--gif" => gif = Some(value),
+            "--fps" => fps = parse_flag(&arg, &value)?,
+            _ => render_args.extend([arg, value]),
+        }
+    }
+
+    if positional.len() != 2 {
+        return Err(format!(
+            "expected directory and image dimensions, got {} positional arguments",
+            positional.len()
+        ));
+    }
+    let bounds = parse_pair(&positional[1], 'x')
+        .ok_or_else(|| format!("error parsing image dimensions '{}'", positional[1]))?;
+    let target = target.ok_or("--target is required")?;
+    let target = target
+        .split_once(',')
+        .map(|(re, im)| (re.to_string(), im.to_string()))
+        .ok_or_else(|| format!("error parsing target '{}'", target))?;
+    let zoom_end = zoom_end.ok_or("--zoom-end is required")?;
+    if !(zoom_start > 0.0 && zoom_end > 0.0) {
+        return Err("zoom must be greater than zero".to_string());
+    }
+    if frames == 0 || fps == 0 {
+        return Err("--frames and --fps must be greater than zero".to_string());
+    }
+
+    let animation = Animation {
+        directory: positional[0].clone(),
+        target,
+        zoom_start,
+        zoom_end,
+        frames,
+        easing,
+        apng,
+        gif,
+        fps,
+        render_args,
+        bounds,
+    };
+
+    // Render options are checked before hours of rendering, not on the last frame
+    if animation.frame_options(0)?.bit_depth != 8 {
+        return Err("animation frames have 8 bits per channel".to_string());
+    }
+    animation.frame_options(frames - 1)?;
+    Ok(animation)
+}
+
+/// Parses serve arguments, the render options are kept as they are for the tiles
+pub fn parse_serve<I>(args: I) -> Result<Explorer, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut host = IpAddr::from([127, 0, 0, 1]);
+    let mut port = 8080;
+    let mut tile_size = 256;
+    let mut cache_size = 1024;
+    let mut render_args = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(format!("unexpected argument '{}'", arg));
+        }
+        if SWITCHES.contains(&arg.as_str()) {
+            render_args.push(arg);
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+
+        match arg.as_str() {
+            "--host" => host = parse_flag(&arg, &value)?,
+            "--port" => port = parse_flag(&arg, &value)?,
+            "--tile-size" => tile_size = parse_flag(&arg, &value)?,
+            "--cache" => cache_size = parse_flag(&arg, &value)?,
+            _ => render_args.extend([arg, value]),
+        }
+    }
+
+    if cache_size == 0 {
+        return Err("--cache must be greater than zero".to_string());
+    }
+    if !(1..=4096).contains(&tile_size) {
+        return Err("--tile-size must be from 1 to 4096".to_string());
+    }
+
+    let explorer = Explorer {
+        host,
+        port,
+        tile_size,
+        cache_size,
+        render_args,
+    };
+    // Render options are checked before the server starts, not on the first tile
+    explorer.tile_options((0, 0, 0))?;
+    Ok(explorer)
+}
+
+/// Parses colorize arguments
+pub fn parse_colorize<I>(args: I) -> Result<Colorize, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut positional = Vec::new();
+    let mut palette = Palette::builtin("gray").unwrap();
+    let mut smooth = false;
+    let mut equalize = false;
+    let mut gamma: f64 = 1.0;
+    let mut bit_depth = 8;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+
+        match arg.as_str() {
+            "--smooth" => {
+                smooth = true;
+                continue;
+            }
+            "--equalize" => {
+                equalize = true;
+                continue;
+            }
+            _ => {}
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+
+        match arg.as_str() {
+            "--palette" => palette = parse_flag(&arg, &value)?,
+            "--gamma" => gamma = parse_flag(&arg, &value)?,
+            "--bit-depth" => bit_depth = parse_flag(&arg, &value)?,
+            _ => return Err(format!("unknown flag {}", arg)),
+        }
+    }
+
+    if positional.len() != 2 {
+        return Err(format!(
+            "expected escape data and image file, got {} positional arguments",
+            positional.len()
+        ));
+    }
+    if !(gamma > 0.0 && gamma.is_finite()) {
+        return Err("--gamma must be greater than zero".to_string());
+    }
+    check_bit_depth(&positional[1], bit_depth)?;
+
+    Ok(Colorize {
+        input: positional[0].clone(),
+        output: positional[1].clone(),
+        palette,
+        smooth,
+        equalize,
+        gamma,
+        bit_depth,
+    })
+}
+
+/// Parses buddhabrot arguments, the others are parsed as a render of the same view
+pub fn parse_buddhabrot<I>(args: I) -> Result<Buddhabrot, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut samples: usize = 50;
+    let mut seed = 0;
+    let mut nebula = None;
+    // Density 0 is the start of the palette, black for fire and white for gray.
+    // Later --palette overrides this one
+    let mut render_args = vec!["--palette".to_string(), "fire".to_string()];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") || SWITCHES.contains(&arg.as_str()) {
+            render_args.push(arg);
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+
+        match arg.as_str() {
+            "--samples" => samples = parse_flag(&arg, &value)?,
+            "--seed" => seed = parse_flag(&arg, &value)?,
+            "--nebula" => {
+                let limits: Vec<usize> = value
+                    .split(',')
+                    .map(|limit| parse_flag(&arg, limit))
+                    .collect::<Result<_, _>>()?;
+                nebula = Some(
+                    limits
+                        .try_into()
+                        .map_err(|_| "--nebula takes three iteration limits r,g,b")?,
+                );
+            }
+            _ => render_args.extend([arg, value]),
+        }
+    }
+
+    for flag in [
+        "--mode",
+        "--smooth",
+        "--coloring",
+        "--deep",
+        "--fill",
+        "--supersample",
+        "--stream",
+        "--from-image",
+    ] {
+        if render_args.iter().any(|arg| arg == flag) {
+            return Err(format!("{} doesn't apply to buddhabrot", flag));
+        }
+    }
+    if samples == 0 {
+        return Err("--samples must be greater than zero".to_string());
+    }
+    if nebula.is_some_and(|limits: [usize; 3]| limits.contains(&0)) {
+        return Err("--nebula limits must be greater than zero".to_string());
+    }
+
+    let options = parse_args(render_args)?;
+    if options.file.ends_with(RAW_EXTENSION) {
+        return Err("buddhabrot has no escape data, it writes images only".to_string());
+    }
+    if let Fractal::Julia(_) = options.fractal {
+        return Err(
+            "buddhabrot traces orbits from zero, julia orbits start at the dot".to_string(),
+        );
+    }
+    if options.deep.is_some() {
+        return Err("buddhabrot orbits are f64, the view is too deep for them".to_string());
+    }
+    let (width, height) = options.bounds;
+    if samples
+        .checked_mul(width)
+        .and_then(|total| total.checked_mul(height))
+        .is_none()
+    {
+        return Err(format!(
+            "--samples {} for {}x{} pixels are too many orbits to count",
+            samples, width, height
+        ));
+    }
+
+    Ok(Buddhabrot {
+        options,
+        samples,
+        seed,
+        nebula,
+    })
+}
+
+/// Parses batch arguments and reads the job file
+pub fn parse_batch<I>(args: I) -> Result<Batch, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut positional = Vec::new();
+    let mut threads = num_cpus::get();
+    let mut force = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        if arg == "--force" {
+            force = true;
+            continue;
+        }
+
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", arg))?;
+
+        match arg.as_str() {
+            "--threads" => threads = parse_flag(&arg, &value)?,
+            _ => return Err(format!("unknown flag {}", arg)),
+        }
+    }
+
+    if positional.len() != 1 {
+        return Err(format!(
+            "expected a job file, got {} positional arguments",
+            positional.len()
+        ));
+    }
+    if threads == 0 {
+        return Err("--threads must be greater than zero".to_string());
+    }
+    Batch::read(&positional[0], threads, force)
+}
+
+fn parse_flag<T>(flag: &str, value: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse::<T>()
+        .map_err(|e| format!("invalid value '{}' for {}: {}", value, flag, e))
+}
+
+#[cfg(test)]
+fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn test_parse_args() {
+    let options = parse_args(args("mandel.png 400x300 -1.08,0.28 -1.03,0.23")).unwrap();
+    assert_eq!(options.file, "mandel.png");
+    assert_eq!(options.bounds, (400, 300));
+    assert_eq!(
+        options.upper_left,
+        Complex {
+            re: -1.08,
+            im: 0.28
+        }
+    );
+    assert_eq!(
+        options.lower_right,
+        Complex {
+            re: -1.03,
+            im: 0.23
+        }
+    );
+    assert_eq!(options.mode, Mode::Single);
+    assert_eq!(options.max_iter, 255);
+    assert_eq!(options.fractal, Fractal::Mandelbrot);
+    assert_eq!(options.palette, Palette::builtin("gray").unwrap());
+    assert!(!options.smooth);
+    assert!(options.deep.is_none());
+
+    let options = parse_args(args(
+        "--mode crossbeam mandel.png 400x300 --threads 3 -1.08,0.28 -1.03,0.23 --max-iter 1000 --smooth --palette fire --fractal tricorn",
+    ))
+    .unwrap();
+    assert_eq!(options.palette, Palette::builtin("fire").unwrap());
+    assert!(options.smooth);
+    assert_eq!(options.mode, Mode::Crossbeam);
+    assert_eq!(options.threads, 3);
+    assert_eq!(options.max_iter, 1000);
+    assert_eq!(options.fractal, Fractal::Tricorn);
+
+    assert!(parse_args(args("mandel.png 400x300 -1.08,0.28")).is_err());
+    assert!(parse_args(args("mandel.png 400x0 -1.08,0.28 -1.03,0.23")).is_err());
+    assert!(parse_args(args("mandel.png 400x300 -1.08,0.28 -1.03,0.23 --mode gpu")).is_err());
+    assert!(parse_args(args("mandel.png 400x300 -1.08,0.28 -1.03,0.23 --threads 0")).is_err());
+    assert!(parse_args(args("mandel.png 400x300 -1.08,0.28 -1.03,0.23 --max-iter")).is_err());
+    assert!(parse_args(args("mandel.png 400x300 -1.08,0.28 -1.03,0.23 --fast 1")).is_err());
+
+    let options = parse_args(args(
+        "mandel.png 400x300 -1.08,0.28 -1.03,0.23 --cardioid --periodicity --fill",
+    ))
+    .unwrap();
+    assert!(options.cardioid && options.periodicity && options.fill);
+    assert!(parse_args(args(
+        "mandel.png 400x300 -1.08,0.28 -1.03,0.23 --fill --fractal tricorn"
+    ))
+    .is_err());
+
+    let options = parse_args(args(
+        "mandel.png 400x300 -1.08,0.28 -1.03,0.23 --supersample adaptive:3x3",
+    ))
+    .unwrap();
+    assert_eq!(options.supersampling, Supersampling::Adaptive(3));
+    assert!(parse_args(args(
+        "mandel.png 400x300 -1.08,0.28 -1.03,0.23 --supersample 3x3 --fill"
+    ))
+    .is_err());
+
+    // The height of a centered view follows the image, the corners are 3 / zoom wide
+    let options = parse_args(args(
+        "mandel.png 400x300 --center -0.75,0 --zoom 2 --rotate 30",
+    ))
+    .unwrap();
+    assert_eq!(
+        options.upper_left,
+        Complex {
+            re: -1.5,
+            im: 0.5625
+        }
+    );
+    assert_eq!(
+        options.lower_right,
+        Complex {
+            re: 0.0,
+            im: -0.5625
+        }
+    );
+    assert_eq!(options.rotate, 30.0);
+    let stretched = parse_args(args("mandel.png 400x300 -2,1 1,-1")).unwrap();
+    assert!((stretched.aspect_distortion() - 0.5625 / 0.5).abs() < 1e-12);
+
+    assert!(parse_args(args("mandel.png 400x300 --center -0.75,0")).is_err());
+    assert!(parse_args(args("mandel.png 400x300 --zoom 2")).is_err());
+    assert!(parse_args(args("mandel.png 400x300 --center -0.75 --zoom 2")).is_err());
+    assert!(parse_args(args("mandel.png 400x300 --center -0.75,0 --zoom 0")).is_err());
+    assert!(parse_args(args(
+        "mandel.png 400x300 --center -0.75,0 --zoom 2 --rotate x"
+    ))
+    .is_err());
+    assert!(parse_args(args(
+        "mandel.png 400x300 -1.08,0.28 -1.03,0.23 --center -0.75,0 --zoom 2"
+    ))
+    .is_err());
+}
+
+#[test]
+fn test_parse_args_deep() {
+    let options = parse_args(args("mandel.png 400x300 -1.08,0.28 -1.03,0.23 --deep")).unwrap();
+    let deep = options.deep.unwrap();
+    assert_eq!(deep.center.re.to_string(), "-1.055");
+    assert!((options.upper_left.re + 0.025).abs() < 1e-15);
+
+    // Too deep for f64
+    let options = parse_args(args(
+        "mandel.png 400x300 -1.7400623825793399052208441670658,0.0281 -1.7400623825793399052208441670648,0.0280999999999999999999999999992",
+    ))
+    .unwrap();
+    let deep = options.deep.unwrap();
+    assert_eq!(
+        deep.center.re.to_string(),
+        "-1.7400623825793399052208441670653"
+    );
+    assert!((options.lower_right.re - 5e-31).abs() < 1e-44);
+
+    assert!(parse_args(args(
+        "mandel.png 400x300 -1.08,0.28 -1.03,0.23 --deep --fractal tricorn"
+    ))
+    .is_err());
+
+    // Centers keep all their digits
+    let options = parse_args(args(
+        "mandel.png 400x300 --center -1.7400623825793399052208441670653,0.0281 --zoom 1e30",
+    ))
+    .unwrap();
+    assert_eq!(
+        options.deep.unwrap().center.re.to_string(),
+        "-1.7400623825793399052208441670653"
+    );
+}
+
+#[test]
+fn test_parse_animation() {
+    let animation = match parse_command(args(
+        "animate frames 64x48 --target -1.7499,0.00001 --zoom-end 1e6 --frames 12 --smooth --easing ease-out --max-iter 500",
+    ))
+    .unwrap()
+    {
+        Command::Animate(animation) => animation,
+        _ => panic!("animate expected"),
+    };
+    assert_eq!(animation.directory, "frames");
+    assert_eq!(animation.bounds, (64, 48));
+    assert_eq!(
+        animation.target,
+        ("-1.7499".to_string(), "0.00001".to_string())
+    );
+    assert_eq!(animation.zoom_end, 1e6);
+    assert_eq!(animation.frames, 12);
+    assert_eq!(animation.easing, Easing::EaseOut);
+
+    let options = animation.frame_options(11).unwrap();
+    assert_eq!(options.mode, Mode::Rayon);
+    assert_eq!(options.max_iter, 500);
+    assert!(options.smooth);
+
+    assert!(parse_animation(args("frames 64x48 --zoom-end 10")).is_err());
+    assert!(parse_animation(args("frames 64x48 --target -1.7499 --zoom-end 10")).is_err());
+    assert!(parse_animation(args("frames 64x48 --target 0,0")).is_err());
+    assert!(parse_animation(args("frames 64x48 --target 0,0 --zoom-end 10 --frames 0")).is_err());
+    assert!(parse_animation(args(
+        "frames 64x48 --target 0,0 --zoom-end 10 --palette plaid"
+    ))
+    .is_err());
+    assert!(parse_animation(args("frames --target 0,0 --zoom-end 10")).is_err());
+}
+
+#[test]
+fn test_parse_serve() {
+    let explorer = match parse_command(args("serve --port 9000 --smooth --cache 10 --max-iter 500"))
+        .unwrap()
+    {
+        Command::Serve(explorer) => explorer,
+        _ => panic!("serve expected"),
+    };
+    assert_eq!(explorer.host, IpAddr::from([127, 0, 0, 1]));
+    assert_eq!(explorer.port, 9000);
+    assert_eq!(explorer.tile_size, 256);
+    assert_eq!(explorer.cache_size, 10);
+    assert_eq!(explorer.render_args, args("--smooth --max-iter 500"));
+    let explorer = parse_serve(args("--host 0.0.0.0")).unwrap();
+    assert_eq!(explorer.host, IpAddr::from([0, 0, 0, 0]));
+
+    assert!(parse_serve(args("--host localhost")).is_err());
+    assert!(parse_serve(args("--tile-size 0")).is_err());
+    assert!(parse_serve(args("--cache 0")).is_err());
+    assert!(parse_serve(args("--fractal newton")).is_err());
+    assert!(parse_serve(args("mandel.png")).is_err());
+}
+
+#[test]
+fn test_parse_colorize() {
+    let colorize = match parse_command(args(
+        "colorize mandel.raw mandel.png --palette fire --equalize --gamma 2.2",
+    ))
+    .unwrap()
+    {
+        Command::Colorize(colorize) => colorize,
+        _ => panic!("colorize expected"),
+    };
+    assert_eq!(colorize.input, "mandel.raw");
+    assert_eq!(colorize.output, "mandel.png");
+    assert_eq!(colorize.palette, Palette::builtin("fire").unwrap());
+    assert!(colorize.equalize && !colorize.smooth);
+    assert_eq!(colorize.gamma, 2.2);
+
+    assert!(parse_colorize(args("mandel.raw")).is_err());
+    assert!(parse_colorize(args("mandel.raw mandel.png --gamma 0")).is_err());
+    assert!(parse_colorize(args("mandel.raw mandel.png --max-iter 10")).is_err());
+    assert!(parse_args(args("mandel.raw 400x300 -1.08,0.28 -1.03,0.23 --fill")).is_err());
+}
+
+#[test]
+fn test_parse_bit_depth() {
+    let view = "mandel 400x300 -1.08,0.28 -1.03,0.23";
+    let parse = |file: &str, flags: &str| {
+        parse_args(args(&format!("{} {}", view.replace("mandel", file), flags)))
+    };
+    assert_eq!(parse("mandel.png", "").unwrap().bit_depth, 8);
+    assert_eq!(parse("mandel.png", "--bit-depth 16").unwrap().bit_depth, 16);
+    assert_eq!(
+        parse("mandel.tiff", "--bit-depth 16").unwrap().bit_depth,
+        16
+    );
+    assert!(parse("mandel.jpg", "--bit-depth 16").is_err());
+    assert!(parse("mandel.png", "--bit-depth 12").is_err());
+    assert!(parse("mandel.gif", "").is_err());
+    assert!(parse_colorize(args("mandel.raw mandel.webp --bit-depth 16")).is_err());
+}
+
+#[test]
+fn test_from_image() {
+    let directory = std::env::temp_dir().join(format!("mandelbrot-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let image = directory.join("old.png").to_string_lossy().into_owned();
+    let parse_render = |args| -> Result<Options, String> {
+        match parse_command(args)? {
+            Command::Render(options) => Ok(options),
+            _ => panic!("render expected"),
+        }
+    };
+
+    let old = parse_args(args(&format!(
+        "{} 40x30 -1.08,0.28 -1.03,0.23 --fractal tricorn --palette fire --smooth --rotate 30",
+        image
+    )))
+    .unwrap();
+    let pixels = crate::render_image(&old, false);
+    output::write_image(&image, &pixels, old.bounds, 8, &old.metadata()).unwrap();
+
+    // Everything but the size and the overridden options comes from the image
+    let new = parse_render(args(&format!(
+        "new.png 80x60 --from-image {} --max-iter 50",
+        image
+    )))
+    .unwrap();
+    assert_eq!(new.bounds, (80, 60));
+    assert_eq!(
+        (new.upper_left, new.lower_right),
+        (old.upper_left, old.lower_right)
+    );
+    assert_eq!(new.fractal, old.fractal);
+    assert_eq!(new.palette, old.palette);
+    assert!(new.smooth);
+    assert_eq!(new.rotate, 30.0);
+    assert_eq!(new.max_iter, 50);
+
+    // A center moves the view, the kept corners are left out
+    let centered = parse_render(args(&format!(
+        "new.png 80x60 --from-image {} --center 0,0 --zoom 2",
+        image
+    )))
+    .unwrap();
+    assert_eq!(centered.fractal, old.fractal);
+    assert!(centered.upper_left != old.upper_left);
+
+    assert!(parse_render(args("new.png 80x60 --from-image missing.png")).is_err());
+    assert!(parse_render(args("new.png 80x60 --from-image")).is_err());
+
+    // Only single renders expand it, subcommands reject it instead of reading the image
+    for command in [
+        format!("serve --from-image {}", image),
+        format!(
+            "animate frames 40x30 --target 0,0 --zoom-end 2 --from-image {}",
+            image
+        ),
+    ] {
+        assert!(parse_command(args(&command)).is_err(), "{}", command);
+    }
+    assert!(parse_args(args(&format!("new.png 80x60 --from-image {}", image))).is_err());
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_parse_stream() {
+    let parse = |line: &str| parse_args(args(line)).map(|options| options.stream);
+    assert_eq!(parse("mandel.png 400x300 -1.08,0.28 -1.03,0.23"), Ok(false));
+    assert_eq!(
+        parse("mandel.png 400x300 -1.08,0.28 -1.03,0.23 --stream"),
+        Ok(true)
+    );
+    assert_eq!(
+        parse("mandel.ppm 400x300 -1.08,0.28 -1.03,0.23 --stream"),
+        Ok(true)
+    );
+    assert!(parse("mandel.jpg 400x300 -1.08,0.28 -1.03,0.23 --stream").is_err());
+    assert!(parse("mandel.raw 400x300 -1.08,0.28 -1.03,0.23 --stream").is_err());
+
+    // Images too large for memory are streamed by themselves
+    assert_eq!(parse("mandel.png 40000x30000 -2.2,1.2 0.8,-1.2"), Ok(true));
+    assert_eq!(
+        parse("mandel.tiff 40000x30000 -2.2,1.2 0.8,-1.2"),
+        Ok(false)
+    );
+}
+
+#[test]
+fn test_parse_coloring() {
+    let view = "mandel.png 400x300 -1.08,0.28 -1.03,0.23";
+    let parse = |flags: &str| parse_args(args(&format!("{} {}", view, flags)));
+    assert_eq!(parse("").unwrap().coloring, Coloring::Escape);
+    assert_eq!(
+        parse("--coloring shaded").unwrap().coloring,
+        Coloring::Shaded
+    );
+    let julia = parse("--coloring distance --fractal julia:-0.8,0.156").unwrap();
+    assert_eq!(julia.coloring, Coloring::Distance);
+    assert!(julia.view_args().join(" ").contains("--coloring distance"));
+
+    assert!(parse("--coloring distance --fractal burning-ship").is_err());
+    assert!(parse("--coloring distance --deep").is_err());
+    assert!(parse("--coloring normal").is_err());
+    assert!(parse_args(args(
+        "mandel.raw 400x300 -1.08,0.28 -1.03,0.23 --coloring distance"
+    ))
+    .is_err());
+}
+
+#[test]
+fn test_parse_buddhabrot() {
+    let buddhabrot = match parse_command(args(
+        "buddhabrot nebula.png 400x300 --center -0.4,0 --zoom 1 --samples 10 --seed 3 --nebula 5000,500,50 --threads 2",
+    ))
+    .unwrap()
+    {
+        Command::Buddhabrot(buddhabrot) => buddhabrot,
+        _ => panic!("buddhabrot expected"),
+    };
+    assert_eq!(buddhabrot.options.file, "nebula.png");
+    assert_eq!(buddhabrot.options.bounds, (400, 300));
+    assert_eq!(buddhabrot.options.threads, 2);
+    assert_eq!(buddhabrot.samples, 10);
+    assert_eq!(buddhabrot.seed, 3);
+    assert_eq!(buddhabrot.nebula, Some([5000, 500, 50]));
+
+    let view = "buddha.png 400x300 -2.2,1.2 0.8,-1.2";
+    let buddhabrot = parse_buddhabrot(args(&format!("{} --cardioid", view))).unwrap();
+    assert_eq!((buddhabrot.samples, buddhabrot.seed), (50, 0));
+    assert!(buddhabrot.nebula.is_none() && buddhabrot.options.cardioid);
+    assert_eq!(
+        buddhabrot.options.palette,
+        Palette::builtin("fire").unwrap()
+    );
+    let buddhabrot = parse_buddhabrot(args(&format!("{} --palette ultra", view))).unwrap();
+    assert_eq!(
+        buddhabrot.options.palette,
+        Palette::builtin("ultra").unwrap()
+    );
+
+    for flags in [
+        "--samples 0",
+        "--nebula 500,50",
+        "--nebula 500,50,0",
+        "--smooth",
+        "--mode rayon",
+        "--supersample 2x2",
+        "--fractal julia:-0.8,0.156",
+        "--samples 4611686018427387904",
+    ] {
+        assert!(
+            parse_buddhabrot(args(&format!("{} {}", view, flags))).is_err(),
+            "{}",
+            flags
+        );
+    }
+    assert!(parse_buddhabrot(args("buddha.raw 400x300 -2.2,1.2 0.8,-1.2")).is_err());
+}
+
+#[test]
+fn test_parse_batch() {
+    let file = std::env::temp_dir().join(format!("mandelbrot-jobs-{}.json", std::process::id()));
+    let file = file.to_string_lossy().into_owned();
+    std::fs::write(
+        &file,
+        r#"{"jobs": [{"output": "a.png", "size": "40x30", "center": "0,0", "zoom": 1}]}"#,
+    )
+    .unwrap();
+
+    let batch = match parse_command(args(&format!("batch {} --threads 3 --force", file))).unwrap() {
+        Command::Batch(batch) => batch,
+        _ => panic!("batch expected"),
+    };
+    assert_eq!((batch.threads, batch.force), (3, true));
+    assert_eq!(batch.jobs.len(), 1);
+    assert_eq!(batch.jobs[0].bounds, (40, 30));
+    assert!(!parse_batch(args(&file)).unwrap().force);
+
+    assert!(parse_batch(args(&format!("{} --threads 0", file))).is_err());
+    assert!(parse_batch(args(&format!("{} --max-iter 10", file))).is_err());
+    assert!(parse_batch(args("")).is_err());
+    assert!(parse_batch(args("missing.toml")).is_err());
+    std::fs::remove_file(&file).unwrap();
+}
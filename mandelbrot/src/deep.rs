@@ -0,0 +1,510 @@
+// Deep zoom by perturbation.
+//
+// Below a pixel size of about 1e-14 neighbouring pixels get the same Complex<f64> dot.
+// So only one reference dot, the center of the view, is iterated in arbitrary precision.
+// Every pixel then iterates its tiny difference from the reference orbit in plain f64,
+// which keeps its precision because f64 exponents go down to 1e-308:
+//
+//   z = Z + dz, c = C + dc
+//   dz' = 2 Z dz + dz^2 + dc
+//
+// When the pixel orbit gets closer to zero than dz, or the reference orbit ends,
+// the pixel continues from the start of the reference orbit ("rebasing"),
+// so the reference never has to be inside the set and there are no glitches.
+use num::bigint::BigInt;
+use num::{Complex, One, Signed, ToPrimitive, Zero};
+use std::fmt;
+
+use crate::{smooth_iteration, SMOOTH_BAILOUT};
+
+/// Smallest pixel size the f64 differences can hold
+const MIN_PIXEL_SIZE: f64 = 1e-300;
+
+/// Bits kept besides the ones needed to tell pixels apart, they absorb rounding errors
+const GUARD_BITS: u32 = 64;
+
+/// Fixed point number: mantissa / 2^precision
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fixed {
+    mantissa: BigInt,
+    precision: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedComplex {
+    pub re: Fixed,
+    pub im: Fixed,
+}
+
+impl Fixed {
+    /// Parses decimal numbers like -1.25, .5 or 3.2e-120 without going through f64.
+    /// Numbers of more than precision decimal digits are far outside any view and are rejected,
+    /// ones too small for the precision are zero.
+    pub fn parse(s: &str, precision: u32) -> Option<Fixed> {
+        let (number, exponent) = match s.find(['e', 'E']) {
+            Some(index) => (&s[..index], s[index + 1..].parse::<i32>().ok()?),
+            None => (s, 0),
+        };
+        let (negative, number) = match number.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, number.strip_prefix('+').unwrap_or(number)),
+        };
+        let (integer, fraction) = match number.find('.') {
+            Some(index) => (&number[..index], &number[index + 1..]),
+            None => (number, ""),
+        };
+        if integer.is_empty() && fraction.is_empty() {
+            return None;
+        }
+        if !integer
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+
+        // value = digits * 10^exponent
+        let digits: BigInt = format!("0{}{}", integer, fraction).parse().ok()?;
+        let exponent = exponent as i64 - fraction.len() as i64;
+
+        // Checked before 10^exponent is computed, it would take forever for exponents like 1e999999.
+        // 10^(precision / 3) is more than 2^precision, so below that every digit rounds away.
+        let digit_count = (integer.len() + fraction.len()) as i64;
+        if exponent + digit_count > precision as i64 {
+            return None;
+        }
+        if -exponent > digit_count + precision as i64 / 3 + 1 {
+            return Some(Fixed {
+                mantissa: BigInt::zero(),
+                precision,
+            });
+        }
+
+        let scaled = digits << precision as usize;
+        let mut mantissa = if exponent >= 0 {
+            scaled * BigInt::from(10).pow(exponent as u32)
+        } else {
+            let divisor = BigInt::from(10).pow((-exponent) as u32);
+            // Rounded to the nearest
+            (scaled + &divisor / 2) / divisor
+        };
+        if negative {
+            mantissa = -mantissa;
+        }
+
+        Some(Fixed {
+            mantissa,
+            precision,
+        })
+    }
+
+    fn from_f64(value: f64, precision: u32) -> Fixed {
+        // Every finite f64 is exactly a decimal, Rust prints it without rounding loss
+        Fixed::parse(&format!("{:e}", value), precision).unwrap_or(Fixed {
+            mantissa: BigInt::zero(),
+            precision,
+        })
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        // Only the top 64 bits of the mantissa matter for an f64
+        let bits = self.mantissa.bits() as i64;
+        let shift = (bits - 64).max(0);
+        let top = (&self.mantissa >> shift as usize).to_f64().unwrap_or(0.0);
+        let exponent = shift - self.precision as i64;
+
+        // Scaled in two steps so that 2^exponent itself can't overflow or underflow
+        let half = (exponent / 2) as i32;
+        top * 2f64.powi(half) * 2f64.powi(exponent as i32 - half)
+    }
+
+    fn add(&self, other: &Fixed) -> Fixed {
+        Fixed {
+            mantissa: &self.mantissa + &other.mantissa,
+            precision: self.precision,
+        }
+    }
+
+    fn sub(&self, other: &Fixed) -> Fixed {
+        Fixed {
+            mantissa: &self.mantissa - &other.mantissa,
+            precision: self.precision,
+        }
+    }
+
+    fn mul(&self, other: &Fixed) -> Fixed {
+        Fixed {
+            mantissa: (&self.mantissa * &other.mantissa) >> self.precision as usize,
+            precision: self.precision,
+        }
+    }
+
+    fn half(&self) -> Fixed {
+        Fixed {
+            mantissa: &self.mantissa >> 1,
+            precision: self.precision,
+        }
+    }
+}
+
+impl fmt::Display for Fixed {
+    /// Decimal with as many digits as the precision can tell apart
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // log10(2) digits per bit, the last bits are rounding noise
+        let digits = self.precision as usize * 30103 / 100000;
+        let one = BigInt::one() << self.precision as usize;
+        let scale = BigInt::from(10).pow(digits as u32);
+        let scaled: BigInt = (self.mantissa.abs() * &scale + &one / 2) / &one;
+
+        let integer: BigInt = &scaled / &scale;
+        let remainder: BigInt = &scaled % &scale;
+        let fraction = format!("{:0>width$}", remainder.to_string(), width = digits);
+        let fraction = fraction.trim_end_matches('0');
+
+        let sign = if self.mantissa.is_negative() && !scaled.is_zero() {
+            "-"
+        } else {
+            ""
+        };
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, integer)
+        } else {
+            write!(f, "{}{}.{}", sign, integer, fraction)
+        }
+    }
+}
+
+impl FixedComplex {
+    pub fn parse(re: &str, im: &str, precision: u32) -> Option<FixedComplex> {
+        Some(FixedComplex {
+            re: Fixed::parse(re, precision)?,
+            im: Fixed::parse(im, precision)?,
+        })
+    }
+
+    fn to_complex(&self) -> Complex<f64> {
+        Complex {
+            re: self.re.to_f64(),
+            im: self.im.to_f64(),
+        }
+    }
+}
+
+/// Decimal s + delta without rounding s to f64, so that views computed
+/// from a center and a size keep all digits of the center
+pub fn offset_decimal(s: &str, delta: f64) -> Option<String> {
+    // Enough bits for every digit of s and for the digits of delta that matter
+    let delta_bits = if delta == 0.0 {
+        0
+    } else {
+        (-delta.abs().log2()).max(0.0) as u32
+    };
+    let precision = GUARD_BITS + 4 * s.len() as u32 + delta_bits;
+    let value = Fixed::parse(s, precision)?;
+    Some(value.add(&Fixed::from_f64(delta, precision)).to_string())
+}
+
+/// Reference orbit of the view center, what every pixel of a deep render is relative to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Perturbation {
+    pub center: FixedComplex,
+    pub precision: u32,
+    /// Upper left and lower right corners relative to the center
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>,
+    orbit: Vec<Complex<f64>>,
+}
+
+impl Perturbation {
+    /// Corners are given as "re,im" strings and parsed at whatever precision the view needs
+    pub fn new(
+        upper_left: &str,
+        lower_right: &str,
+        bounds: (usize, usize),
+        limit: usize,
+    ) -> Result<Perturbation, String> {
+        let parse = |s: &str, precision| {
+            let (re, im) = s.split_once(',')?;
+            FixedComplex::parse(re, im, precision)
+        };
+        let error = |s: &str| format!("error parsing deep zoom corner '{}'", s);
+
+        // Pixel size is measured with the highest supported precision first
+        let max_precision = (-MIN_PIXEL_SIZE.log2()) as u32 + GUARD_BITS;
+        let corner = parse(upper_left, max_precision).ok_or_else(|| error(upper_left))?;
+        let opposite = parse(lower_right, max_precision).ok_or_else(|| error(lower_right))?;
+        let pixel_size = f64::min(
+            opposite.re.sub(&corner.re).to_f64().abs() / bounds.0 as f64,
+            corner.im.sub(&opposite.im).to_f64().abs() / bounds.1 as f64,
+        );
+        if pixel_size < MIN_PIXEL_SIZE {
+            return Err(format!(
+                "pixel size {:e} is too small, deep zoom goes down to {:e}",
+                pixel_size, MIN_PIXEL_SIZE
+            ));
+        }
+
+        let precision = (-pixel_size.log2()).max(0.0) as u32 + GUARD_BITS;
+        let corner = parse(upper_left, precision).ok_or_else(|| error(upper_left))?;
+        let opposite = parse(lower_right, precision).ok_or_else(|| error(lower_right))?;
+        let center = FixedComplex {
+            re: corner.re.add(&opposite.re).half(),
+            im: corner.im.add(&opposite.im).half(),
+        };
+        let relative = |dot: &FixedComplex| Complex {
+            re: dot.re.sub(&center.re).to_f64(),
+            im: dot.im.sub(&center.im).to_f64(),
+        };
+
+        Ok(Perturbation {
+            upper_left: relative(&corner),
+            lower_right: relative(&opposite),
+            orbit: reference_orbit(&center, limit),
+            center,
+            precision,
+        })
+    }
+
+    /// Escape time of the dot center + dc, same as escape_time would give with exact arithmetic
+    pub fn escape_time(&self, dc: Complex<f64>, limit: usize) -> Option<usize> {
+        self.escapes(dc, limit, 4.0, 4.0).map(|(i, _)| i)
+    }
+
+    pub fn smooth_escape_time(&self, dc: Complex<f64>, limit: usize) -> Option<f64> {
+        self.escape_orbit(dc, limit)
+            .map(|(i, norm_sqr)| smooth_iteration(i, norm_sqr, 2.0))
+    }
+
+    /// Iteration and |z|^2 when z leaves the smooth bailout circle
+    pub fn escape_orbit(&self, dc: Complex<f64>, limit: usize) -> Option<(usize, f64)> {
+        self.escape(dc, limit, SMOOTH_BAILOUT * SMOOTH_BAILOUT)
+    }
+
+    /// escape_time and escape_orbit in one pass, see Fractal::escape_counts
+    pub fn escape_counts(
+        &self,
+        dc: Complex<f64>,
+        limit: usize,
+    ) -> Option<(usize, Option<(usize, f64)>)> {
+        self.escapes(dc, limit, 4.0, SMOOTH_BAILOUT * SMOOTH_BAILOUT)
+    }
+
+    /// Iteration and |z|^2 when z leaves the bailout circle
+    fn escape(&self, dc: Complex<f64>, limit: usize, bailout_sqr: f64) -> Option<(usize, f64)> {
+        self.escapes(dc, limit, bailout_sqr, bailout_sqr)
+            .and_then(|(_, orbit)| orbit)
+    }
+
+    /// Iteration when z leaves the first bailout circle, then iteration and |z|^2
+    /// when it leaves the last one, None if it doesn't within the limit
+    fn escapes(
+        &self,
+        dc: Complex<f64>,
+        limit: usize,
+        first_sqr: f64,
+        last_sqr: f64,
+    ) -> Option<(usize, Option<(usize, f64)>)> {
+        let mut dz = Complex { re: 0.0, im: 0.0 };
+        let mut n = 0;
+        let mut escaped = None;
+
+        for i in 0..limit {
+            let z = self.orbit[n] + dz;
+            let norm_sqr = z.norm_sqr();
+            if escaped.is_none() && norm_sqr > first_sqr {
+                escaped = Some(i);
+            }
+            if norm_sqr > last_sqr {
+                return escaped.map(|count| (count, Some((i, norm_sqr))));
+            }
+
+            if norm_sqr < dz.norm_sqr() || n == self.orbit.len() - 1 {
+                dz = z;
+                n = 0;
+            }
+            dz = self.orbit[n] * dz * 2.0 + dz * dz + dc;
+            n += 1;
+        }
+
+        escaped.map(|count| (count, None))
+    }
+}
+
+/// Z(n+1) = Z(n)^2 + C in full precision, kept as f64 because |Z| stays below the bailout.
+/// Runs past the smooth coloring bailout so that both colorings can use it.
+fn reference_orbit(center: &FixedComplex, limit: usize) -> Vec<Complex<f64>> {
+    let zero = Fixed {
+        mantissa: BigInt::zero(),
+        precision: center.re.precision,
+    };
+    let mut z = FixedComplex {
+        re: zero.clone(),
+        im: zero,
+    };
+    let mut orbit = vec![z.to_complex()];
+
+    for _ in 0..limit {
+        let re = z.re.mul(&z.re).sub(&z.im.mul(&z.im)).add(&center.re);
+        let im = z.re.mul(&z.im);
+        let im = im.add(&im).add(&center.im);
+        z = FixedComplex { re, im };
+
+        let value = z.to_complex();
+        orbit.push(value);
+        if value.norm_sqr() > SMOOTH_BAILOUT * SMOOTH_BAILOUT {
+            break;
+        }
+    }
+
+    orbit
+}
+
+#[test]
+fn test_fixed() {
+    let parse = |s| Fixed::parse(s, 128).unwrap();
+    assert_eq!(parse("1.5").to_f64(), 1.5);
+    assert_eq!(parse("-0.25").to_f64(), -0.25);
+    assert_eq!(parse(".5").to_f64(), 0.5);
+    assert_eq!(parse("3e2").to_f64(), 300.0);
+    assert_eq!(parse("-1.25E-1").to_f64(), -0.125);
+    assert_eq!(parse("1.5").mul(&parse("-2")).to_f64(), -3.0);
+    assert_eq!(parse("0.1").to_string(), "0.1");
+    assert_eq!(parse("-1.08").to_string(), "-1.08");
+    assert_eq!(Fixed::from_f64(-0.75, 64).to_string(), "-0.75");
+
+    for bad in [
+        "",
+        "-",
+        ".",
+        "1.2.3",
+        "1e",
+        "0x10",
+        "1,5",
+        "1e999999",
+        "1e2147483647",
+    ] {
+        assert!(Fixed::parse(bad, 64).is_none(), "{}", bad);
+    }
+
+    // Huge exponents are answered without computing 10^exponent
+    let started = std::time::Instant::now();
+    assert_eq!(Fixed::parse("1e-999999", 64).unwrap().to_f64(), 0.0);
+    assert_eq!(Fixed::parse("-5.5e-2147483648", 64).unwrap().to_f64(), 0.0);
+    assert_eq!(Fixed::parse("0.1e-2147483647", 64).unwrap().to_f64(), 0.0);
+    assert!(offset_decimal("1e999999", 0.5).is_none());
+    assert!(started.elapsed().as_secs() < 1);
+
+    // Right at the limits the digits are still there
+    assert_eq!(Fixed::parse("1e63", 64).unwrap().to_f64(), 1e63);
+    assert!(Fixed::parse("1e-21", 64).unwrap().to_f64() == 0.0);
+    assert!(Fixed::parse("1e-19", 64).unwrap().to_f64() > 0.0);
+
+    // Digits far past f64 survive
+    let a = Fixed::parse("-1.7400623825793399052208441670658", 256).unwrap();
+    let b = Fixed::parse("-1.7400623825793399052208441670657", 256).unwrap();
+    assert!((a.sub(&b).to_f64() + 1e-31).abs() < 1e-45);
+    assert!((Fixed::parse("1e-250", 1000).unwrap().to_f64() - 1e-250).abs() < 1e-264);
+
+    assert_eq!(offset_decimal("-0.75", 0.25).unwrap(), "-0.5");
+    assert_eq!(
+        offset_decimal("-1.7400623825793399052208441670658", -0.5e-31).unwrap(),
+        "-1.74006238257933990522084416706585"
+    );
+    assert!(offset_decimal("one", 0.5).is_none());
+}
+
+/// Direct iteration in full precision, the ground truth for the perturbed escape times
+#[cfg(test)]
+fn fixed_escape_time(c: &FixedComplex, limit: usize) -> Option<usize> {
+    let mut z = FixedComplex {
+        re: Fixed::from_f64(0.0, c.re.precision),
+        im: Fixed::from_f64(0.0, c.re.precision),
+    };
+    for i in 0..limit {
+        if z.to_complex().norm_sqr() > 4.0 {
+            return Some(i);
+        }
+        let re = z.re.mul(&z.re).sub(&z.im.mul(&z.im)).add(&c.re);
+        let im = z.re.mul(&z.im);
+        z = FixedComplex {
+            re,
+            im: im.add(&im).add(&c.im),
+        };
+    }
+    None
+}
+
+#[test]
+fn test_perturbation() {
+    // Around the tip of the set at -2, f64 can't tell these pixels apart
+    let upper_left = "-2.0000000000000000000000000000005,3.75e-31";
+    let lower_right = "-1.9999999999999999999999999999995,-3.75e-31";
+    let bounds = (20, 15);
+    let limit = 1000;
+    let perturbation = Perturbation::new(upper_left, lower_right, bounds, limit).unwrap();
+    assert!(perturbation.precision > 100);
+
+    let mut escaped = 0;
+    for (col, row) in [
+        (0, 0),
+        (19, 0),
+        (3, 7),
+        (10, 7),
+        (15, 7),
+        (12, 2),
+        (0, 14),
+        (19, 14),
+    ] {
+        let dc = crate::convert_pixel_to_dot(
+            bounds,
+            (col, row),
+            perturbation.upper_left,
+            perturbation.lower_right,
+        );
+        let c = FixedComplex {
+            re: perturbation
+                .center
+                .re
+                .add(&Fixed::from_f64(dc.re, perturbation.precision)),
+            im: perturbation
+                .center
+                .im
+                .add(&Fixed::from_f64(dc.im, perturbation.precision)),
+        };
+
+        let expected = fixed_escape_time(&c, limit);
+        assert_eq!(
+            perturbation.escape_time(dc, limit),
+            expected,
+            "{} {}",
+            col,
+            row
+        );
+        escaped += expected.is_some() as usize;
+    }
+    assert!(escaped > 0);
+
+    // Shallow views agree with the plain f64 iteration
+    let perturbation = Perturbation::new("-1.2,0.35", "-1.0,0.2", (40, 30), 200).unwrap();
+    for col in 0..40 {
+        let dc = crate::convert_pixel_to_dot(
+            (40, 30),
+            (col, 11),
+            perturbation.upper_left,
+            perturbation.lower_right,
+        );
+        let dot = crate::convert_pixel_to_dot(
+            (40, 30),
+            (col, 11),
+            Complex { re: -1.2, im: 0.35 },
+            Complex { re: -1.0, im: 0.2 },
+        );
+        assert_eq!(
+            perturbation.escape_time(dc, 200),
+            crate::escape_time(dot, 200)
+        );
+    }
+
+    assert!(Perturbation::new("-1,1e-310", "1,-1e-310", (10, 10), 100).is_err());
+}
@@ -0,0 +1,399 @@
+use num::Complex;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::interior::Cycle;
+use crate::{escape_time, parse_complex, smooth_escape_time, smooth_iteration, SMOOTH_BAILOUT};
+
+/// Escape-time fractals, all iterate z until it leaves the circle of radius 2
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fractal {
+    /// z = z^2 + dot, z starts at zero
+    Mandelbrot,
+    /// z = z^2 + constant, z starts at the dot
+    Julia(Complex<f64>),
+    /// z = (|re z| + i|im z|)^2 + dot
+    BurningShip,
+    /// z = conj(z)^2 + dot
+    Tricorn,
+    /// z = z^d + dot
+    Multibrot(u32),
+}
+
+impl Fractal {
+    /// z and c of the first iteration for the dot
+    fn start(&self, dot: Complex<f64>) -> (Complex<f64>, Complex<f64>) {
+        match *self {
+            Fractal::Julia(constant) => (dot, constant),
+            _ => (Complex { re: 0.0, im: 0.0 }, dot),
+        }
+    }
+
+    pub fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        match *self {
+            Fractal::Mandelbrot | Fractal::Julia(_) => z * z + c,
+            Fractal::BurningShip => {
+                let z = Complex {
+                    re: z.re.abs(),
+                    im: z.im.abs(),
+                };
+                z * z + c
+            }
+            Fractal::Tricorn => {
+                let z = z.conj();
+                z * z + c
+            }
+            Fractal::Multibrot(d) => z.powu(d) + c,
+        }
+    }
+
+    /// Exponent of z, the smooth coloring depends on how fast |z| grows
+    pub fn degree(&self) -> f64 {
+        match *self {
+            Fractal::Multibrot(d) => d as f64,
+            _ => 2.0,
+        }
+    }
+
+    /// Same as the Mandelbrot escape_time: None for dots that are still
+    /// in the circle of radius 2 after limit iterations.
+    /// With periodicity set, dots whose orbit comes back to an earlier z stop early.
+    pub fn escape_time(&self, dot: Complex<f64>, limit: usize, periodicity: bool) -> Option<usize> {
+        if *self == Fractal::Mandelbrot && !periodicity {
+            return escape_time(dot, limit);
+        }
+
+        let (mut z, c) = self.start(dot);
+        let mut cycle = Cycle::new();
+        for i in 0..limit {
+            if z.norm_sqr() > 4.0 {
+                return Some(i);
+            }
+            if periodicity && cycle.repeats(i, z) {
+                return None;
+            }
+            z = self.step(z, c);
+        }
+
+        None
+    }
+
+    /// Normalized iteration count, see smooth_escape_time
+    pub fn smooth_escape_time(
+        &self,
+        dot: Complex<f64>,
+        limit: usize,
+        periodicity: bool,
+    ) -> Option<f64> {
+        if *self == Fractal::Mandelbrot && !periodicity {
+            return smooth_escape_time(dot, limit);
+        }
+
+        self.escape_orbit(dot, limit, periodicity)
+            .map(|(i, norm_sqr)| smooth_iteration(i, norm_sqr, self.degree()))
+    }
+
+    /// Iteration and |z|^2 when z leaves the smooth bailout circle,
+    /// what the smooth escape time is computed from
+    pub fn escape_orbit(
+        &self,
+        dot: Complex<f64>,
+        limit: usize,
+        periodicity: bool,
+    ) -> Option<(usize, f64)> {
+        let bailout_sqr = SMOOTH_BAILOUT * SMOOTH_BAILOUT;
+        self.escapes(dot, limit, periodicity, bailout_sqr, bailout_sqr)
+            .and_then(|(_, orbit)| orbit)
+    }
+
+    /// escape_time and escape_orbit in one pass. The orbit is None when z leaves
+    /// the circle of radius 2 but not the smooth bailout circle within the limit.
+    pub fn escape_counts(
+        &self,
+        dot: Complex<f64>,
+        limit: usize,
+        periodicity: bool,
+    ) -> Option<(usize, Option<(usize, f64)>)> {
+        self.escapes(
+            dot,
+            limit,
+            periodicity,
+            4.0,
+            SMOOTH_BAILOUT * SMOOTH_BAILOUT,
+        )
+    }
+
+    /// Iteration when z leaves the first bailout circle, then iteration and |z|^2
+    /// when it leaves the last one, None if it doesn't within the limit
+    fn escapes(
+        &self,
+        dot: Complex<f64>,
+        limit: usize,
+        periodicity: bool,
+        first_sqr: f64,
+        last_sqr: f64,
+    ) -> Option<(usize, Option<(usize, f64)>)> {
+        let (mut z, c) = self.start(dot);
+        let mut cycle = Cycle::new();
+        let mut escaped = None;
+        for i in 0..limit {
+            let norm_sqr = z.norm_sqr();
+            if escaped.is_none() && norm_sqr > first_sqr {
+                escaped = Some(i);
+            }
+            if norm_sqr > last_sqr {
+                return escaped.map(|count| (count, Some((i, norm_sqr))));
+            }
+            if escaped.is_none() && periodicity && cycle.repeats(i, z) {
+                return None;
+            }
+            z = self.step(z, c);
+        }
+
+        escaped.map(|count| (count, None))
+    }
+
+    /// True for the fractals whose z is a smooth function of the dot,
+    /// the distance coloring needs its derivative
+    pub fn has_derivative(&self) -> bool {
+        matches!(
+            self,
+            Fractal::Mandelbrot | Fractal::Julia(_) | Fractal::Multibrot(_)
+        )
+    }
+
+    /// Iteration, z and the derivative of z by the dot when z leaves the smooth bailout
+    /// circle, for the fractals that have the derivative
+    pub fn escape_derivative(
+        &self,
+        dot: Complex<f64>,
+        limit: usize,
+        periodicity: bool,
+    ) -> Option<(usize, Complex<f64>, Complex<f64>)> {
+        assert!(self.has_derivative());
+        let one = Complex { re: 1.0, im: 0.0 };
+        let zero = Complex { re: 0.0, im: 0.0 };
+        // Julia orbits start at the dot, the others add it on every iteration
+        let (mut dz, dc) = match self {
+            Fractal::Julia(_) => (one, zero),
+            _ => (zero, one),
+        };
+        let degree = self.degree() as u32;
+
+        let (mut z, c) = self.start(dot);
+        let mut cycle = Cycle::new();
+        for i in 0..limit {
+            if z.norm_sqr() > SMOOTH_BAILOUT * SMOOTH_BAILOUT {
+                return Some((i, z, dz));
+            }
+            if periodicity && cycle.repeats(i, z) {
+                return None;
+            }
+            // (z^d)' = d z^(d-1) z'
+            dz = z.powu(degree - 1) * degree as f64 * dz + dc;
+            z = self.step(z, c);
+        }
+
+        None
+    }
+}
+
+impl FromStr for Fractal {
+    type Err = String;
+
+    /// mandelbrot, julia:re,im, burning-ship, tricorn or multibrot:d
+    fn from_str(s: &str) -> Result<Fractal, String> {
+        let (name, parameter) = match s.find(':') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+
+        match (name, parameter) {
+            ("mandelbrot", None) => Ok(Fractal::Mandelbrot),
+            ("burning-ship", None) => Ok(Fractal::BurningShip),
+            ("tricorn", None) => Ok(Fractal::Tricorn),
+            ("julia", Some(constant)) => parse_complex(constant)
+                .map(Fractal::Julia)
+                .ok_or_else(|| format!("error parsing julia constant '{}'", constant)),
+            ("multibrot", Some(exponent)) => match exponent.parse::<u32>() {
+                Ok(d) if d >= 2 => Ok(Fractal::Multibrot(d)),
+                _ => Err(format!(
+                    "multibrot exponent '{}' must be a whole number from 2",
+                    exponent
+                )),
+            },
+            _ => Err(format!(
+                "unknown fractal '{}', expected mandelbrot, julia:re,im, burning-ship, tricorn or multibrot:d",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Fractal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fractal::Mandelbrot => write!(f, "mandelbrot"),
+            Fractal::Julia(constant) => write!(f, "julia:{},{}", constant.re, constant.im),
+            Fractal::BurningShip => write!(f, "burning-ship"),
+            Fractal::Tricorn => write!(f, "tricorn"),
+            Fractal::Multibrot(d) => write!(f, "multibrot:{}", d),
+        }
+    }
+}
+
+#[test]
+fn test_fractal_from_str() {
+    assert_eq!("mandelbrot".parse(), Ok(Fractal::Mandelbrot));
+    assert_eq!(
+        "julia:-0.8,0.156".parse(),
+        Ok(Fractal::Julia(Complex {
+            re: -0.8,
+            im: 0.156
+        }))
+    );
+    assert_eq!("burning-ship".parse(), Ok(Fractal::BurningShip));
+    assert_eq!("tricorn".parse(), Ok(Fractal::Tricorn));
+    assert_eq!("multibrot:3".parse(), Ok(Fractal::Multibrot(3)));
+
+    for fractal in [
+        "julia",
+        "julia:1",
+        "multibrot:1",
+        "multibrot:2.5",
+        "mandelbrot:2",
+        "newton",
+    ] {
+        assert!(fractal.parse::<Fractal>().is_err(), "{}", fractal);
+    }
+
+    for fractal in [
+        "mandelbrot",
+        "julia:-0.8,0.156",
+        "burning-ship",
+        "tricorn",
+        "multibrot:3",
+    ] {
+        assert_eq!(fractal.parse::<Fractal>().unwrap().to_string(), fractal);
+    }
+}
+
+/// Golden escape times, computed independently of this code
+#[test]
+fn test_fractal_escape_time() {
+    let dot = |re, im| Complex { re, im };
+    type Golden = (Complex<f64>, Option<usize>);
+    let golden: [(Fractal, [Golden; 4]); 6] = [
+        (
+            Fractal::Mandelbrot,
+            [
+                (dot(-0.5, 0.0), None),
+                (dot(-0.75, 0.1), Some(33)),
+                (dot(-1.2, 0.3), Some(16)),
+                (dot(1.0, 1.0), Some(2)),
+            ],
+        ),
+        (
+            Fractal::Julia(dot(-0.8, 0.156)),
+            [
+                (dot(-0.5, 0.0), Some(31)),
+                (dot(0.1, -0.6), Some(34)),
+                (dot(-1.2, 0.3), Some(5)),
+                (dot(1.0, 1.0), Some(1)),
+            ],
+        ),
+        // Douady rabbit
+        (
+            Fractal::Julia(dot(-0.123, 0.745)),
+            [
+                (dot(-0.2, 0.0), None),
+                (dot(-0.1, 0.9), Some(13)),
+                (dot(0.0, 0.5), Some(9)),
+                (dot(0.2, 0.55), Some(5)),
+            ],
+        ),
+        (
+            Fractal::BurningShip,
+            [
+                (dot(-1.76, -0.02), None),
+                (dot(-1.75, -0.03), Some(22)),
+                (dot(0.26, 0.0), Some(30)),
+                (dot(0.3, 0.5), Some(8)),
+            ],
+        ),
+        (
+            Fractal::Tricorn,
+            [
+                (dot(0.3, 0.5), None),
+                (dot(-0.75, 0.1), Some(30)),
+                (dot(0.2, 0.55), Some(14)),
+                (dot(0.1, -0.6), Some(6)),
+            ],
+        ),
+        (
+            Fractal::Multibrot(3),
+            [
+                (dot(0.3, 0.5), None),
+                (dot(-0.5, 0.0), Some(6)),
+                (dot(-0.75, 0.1), Some(3)),
+                (dot(-1.75, -0.03), Some(2)),
+            ],
+        ),
+    ];
+
+    for (fractal, dots) in golden {
+        for (c, expected) in dots {
+            for periodicity in [false, true] {
+                assert_eq!(
+                    fractal.escape_time(c, 255, periodicity),
+                    expected,
+                    "{} at {}",
+                    fractal,
+                    c
+                );
+                assert_eq!(
+                    fractal.smooth_escape_time(c, 255, periodicity).is_none(),
+                    expected.is_none(),
+                    "{} at {}",
+                    fractal,
+                    c
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_escape_derivative() {
+    // The derivative is close to the difference of z between two close dots
+    let dot = |re, im| Complex { re, im };
+    let h = dot(1e-8, 1e-8);
+    let dots = [
+        (Fractal::Mandelbrot, dot(-0.75, 0.1)),
+        (Fractal::Julia(dot(-0.8, 0.156)), dot(0.1, -0.6)),
+        (Fractal::Multibrot(3), dot(-0.5, 0.0)),
+    ];
+    for (fractal, c) in dots {
+        let (i, z, dz) = fractal.escape_derivative(c, 255, false).unwrap();
+        let (mut near, constant) = fractal.start(c + h);
+        for _ in 0..i {
+            near = fractal.step(near, constant);
+        }
+        let difference = (near - z) / h;
+        assert!(
+            (difference - dz).norm() < 1e-3 * dz.norm(),
+            "{}: {} and {}",
+            fractal,
+            difference,
+            dz
+        );
+        assert_eq!(fractal.escape_derivative(c, 255, true).unwrap(), (i, z, dz));
+    }
+
+    assert_eq!(
+        Fractal::Mandelbrot.escape_derivative(dot(-0.5, 0.0), 255, false),
+        None
+    );
+    assert!(!Fractal::BurningShip.has_derivative());
+    assert!(!Fractal::Tricorn.has_derivative());
+}
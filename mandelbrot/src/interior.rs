@@ -0,0 +1,204 @@
+use num::Complex;
+
+use crate::cli::Options;
+use crate::palette::Rgb16;
+use crate::{escape_color, escape_colors, CHANNELS};
+
+/// True for dots in the main cardioid or in the period 2 bulb, they are in the set
+/// and never escape, so they need no iterations at all
+pub fn in_cardioid_or_bulb(c: Complex<f64>) -> bool {
+    let x = c.re - 0.25;
+    let y_sqr = c.im * c.im;
+    let q = x * x + y_sqr;
+    // Strict comparisons, dots right on the edge are left to the iterations
+    let cardioid = q * (q + x) < y_sqr / 4.0;
+    let bulb = (c.re + 1.0) * (c.re + 1.0) + y_sqr < 1.0 / 16.0;
+    cardioid || bulb
+}
+
+/// Brent's cycle detection: z is saved on iterations 1, 2, 4, 8...
+/// and an orbit that comes back to the saved z exactly repeats forever.
+/// Exact comparison keeps the results of escape_time, the dots found
+/// are the ones that would never escape however many iterations they got.
+pub struct Cycle {
+    saved: Complex<f64>,
+    next_save: usize,
+}
+
+impl Cycle {
+    pub fn new() -> Cycle {
+        Cycle {
+            // NaN is not equal to anything, nothing repeats before the first save
+            saved: Complex {
+                re: f64::NAN,
+                im: f64::NAN,
+            },
+            next_save: 1,
+        }
+    }
+
+    /// Checks z of iteration i against the saved one
+    pub fn repeats(&mut self, i: usize, z: Complex<f64>) -> bool {
+        if z == self.saved {
+            return true;
+        }
+        if i == self.next_save {
+            self.saved = z;
+            self.next_save *= 2;
+        }
+        false
+    }
+}
+
+/// Renders the pixels by rectangle filling (Mariani-Silver): when the whole edge of
+/// a rectangle is in the set, so is its inside, as the Mandelbrot set has no holes.
+/// Other rectangles are split in two until they have no inside left.
+/// Only the edge pixels are checked, so a thin channel outside the set passing between
+/// two of them is missed and the few dots of it inside the rectangle are filled as well.
+pub fn render_filled<F>(pixels: &mut [u16], bounds: (usize, usize), dot_of: F, options: &Options)
+where
+    F: Fn(usize, usize) -> Complex<f64>,
+{
+    assert!(pixels.len() == bounds.0 * bounds.1 * CHANNELS);
+
+    let mut filler = Filler {
+        pixels,
+        width: bounds.0,
+        inside: vec![None; bounds.0 * bounds.1],
+        dot_of,
+        options,
+    };
+    filler.fill(0, 0, bounds.0 - 1, bounds.1 - 1);
+}
+
+struct Filler<'a, F> {
+    pixels: &'a mut [u16],
+    width: usize,
+    /// Whether the pixel is in the set, None until it is rendered
+    inside: Vec<Option<bool>>,
+    dot_of: F,
+    options: &'a Options,
+}
+
+impl<F> Filler<'_, F>
+where
+    F: Fn(usize, usize) -> Complex<f64>,
+{
+    /// Fills the rectangle between the corners, both included
+    fn fill(&mut self, left: usize, top: usize, right: usize, bottom: usize) {
+        let mut edge = Vec::new();
+        for col in left..=right {
+            edge.push((col, top));
+            edge.push((col, bottom));
+        }
+        for row in top + 1..bottom {
+            edge.push((left, row));
+            edge.push((right, row));
+        }
+        let edge_inside = self.render_pixels(&edge);
+
+        if right - left < 2 || bottom - top < 2 {
+            // All pixels are on the edge
+            return;
+        }
+        if edge_inside {
+            let black = escape_color(None, self.options);
+            for row in top + 1..bottom {
+                for col in left + 1..right {
+                    self.set(col, row, None, black);
+                }
+            }
+            return;
+        }
+
+        // Halves share the line between them, it is rendered once
+        if right - left >= bottom - top {
+            let middle = (left + right) / 2;
+            self.fill(left, top, middle, bottom);
+            self.fill(middle, top, right, bottom);
+        } else {
+            let middle = (top + bottom) / 2;
+            self.fill(left, top, right, middle);
+            self.fill(left, middle, right, bottom);
+        }
+    }
+
+    /// Renders the pixels that are not rendered yet, true when all the pixels are in the set
+    fn render_pixels(&mut self, pixels: &[(usize, usize)]) -> bool {
+        let mut missing: Vec<(usize, usize)> = pixels
+            .iter()
+            .filter(|&&(col, row)| self.inside[row * self.width + col].is_none())
+            .cloned()
+            .collect();
+        // Edge corners are listed twice
+        missing.sort_unstable();
+        missing.dedup();
+
+        let dots: Vec<Complex<f64>> = missing
+            .iter()
+            .map(|&(col, row)| (self.dot_of)(col, row))
+            .collect();
+        for (&(col, row), (escape, color)) in missing.iter().zip(escape_colors(&dots, self.options))
+        {
+            self.set(col, row, escape, color);
+        }
+
+        pixels
+            .iter()
+            .all(|&(col, row)| self.inside[row * self.width + col] == Some(true))
+    }
+
+    fn set(&mut self, col: usize, row: usize, escape: Option<f64>, color: Rgb16) {
+        let index = row * self.width + col;
+        self.inside[index] = Some(escape.is_none());
+        self.pixels[index * CHANNELS..(index + 1) * CHANNELS].copy_from_slice(&color);
+    }
+}
+
+#[test]
+fn test_in_cardioid_or_bulb() {
+    let inside = [
+        (0.0, 0.0),
+        (-0.5, 0.3),
+        (0.2, 0.0),
+        (-1.0, 0.0),
+        (-1.2, 0.1),
+    ];
+    for (re, im) in inside {
+        assert!(in_cardioid_or_bulb(Complex { re, im }), "{},{}", re, im);
+    }
+    // Outside the set, in a smaller bulb and on the cusp of the cardioid
+    let not_inside = [
+        (0.3, 0.0),
+        (-2.0, 0.5),
+        (-0.1, 0.9),
+        (-1.3, 0.0),
+        (0.25, 0.0),
+    ];
+    for (re, im) in not_inside {
+        assert!(!in_cardioid_or_bulb(Complex { re, im }), "{},{}", re, im);
+    }
+}
+
+#[test]
+fn test_cycle() {
+    // z goes 0, 1, 0, 1... with period 2
+    let mut cycle = Cycle::new();
+    let orbit = [0.0, 1.0, 0.0, 1.0, 0.0];
+    let found: Vec<bool> = orbit
+        .iter()
+        .enumerate()
+        .map(|(i, &re)| cycle.repeats(i, Complex { re, im: 0.0 }))
+        .collect();
+    assert_eq!(found, [false, false, false, false, true]);
+
+    // An orbit that never repeats
+    let mut cycle = Cycle::new();
+    assert!((0..1000).all(|i| !cycle.repeats(
+        i,
+        Complex {
+            re: i as f64,
+            im: 0.0
+        }
+    )));
+}
@@ -0,0 +1,795 @@
+//! Renders the Mandelbrot set and its relatives into RGB images.
+//!
+//! ```
+//! use mandelbrot::{render, Complex, RenderOptions, Viewport};
+//!
+//! let viewport = Viewport::new((300, 200), Complex::new(-2.2, 1.2), Complex::new(0.8, -1.2));
+//! let options = RenderOptions {
+//!     max_iter: 100,
+//!     smooth: true,
+//!     ..RenderOptions::default()
+//! };
+//! let image = render(&viewport, &options).unwrap();
+//! assert_eq!(image.pixels.len(), 300 * 200 * mandelbrot::CHANNELS);
+//! ```
+//!
+//! The mandelbrot binary is the command line of the crate, see run_command_line.
+
+use animation::BASE_WIDTH;
+use cli::Options;
+use rayon::prelude::*;
+use std::str::FromStr;
+extern crate num_cpus;
+
+pub use distance::Coloring;
+pub use fractal::Fractal;
+pub use num::Complex;
+pub use palette::{Palette, Rgb16};
+pub use supersample::Supersampling;
+
+mod animation;
+mod batch;
+mod buddhabrot;
+mod cli;
+mod deep;
+mod distance;
+mod fractal;
+mod interior;
+mod output;
+mod palette;
+mod raw;
+mod serve;
+mod simd;
+mod stream;
+mod supersample;
+mod tiles;
+
+/// Pixels are stored as RGB with 16 bits per channel, images get 8 of them unless asked for 16
+pub const CHANNELS: usize = 3;
+
+/// Rows of a rayon job with adaptive supersampling. Every job also looks at the escape
+/// counts of the rows around it, single rows would compute them three times over.
+const ADAPTIVE_BAND_ROWS: usize = 16;
+
+/// Render back end, how the image is split between threads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Single,
+    Crossbeam,
+    Rayon,
+    Tiles,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "single" => Ok(Mode::Single),
+            "crossbeam" => Ok(Mode::Crossbeam),
+            "rayon" => Ok(Mode::Rayon),
+            "tiles" => Ok(Mode::Tiles),
+            _ => Err(format!(
+                "unknown mode '{}', expected single, crossbeam, rayon or tiles",
+                s
+            )),
+        }
+    }
+}
+
+/// Part of the plane shown in an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    /// Width and height of the image in pixels
+    pub bounds: (usize, usize),
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>,
+    /// Degrees the view is turned counterclockwise around its middle
+    pub rotate: f64,
+}
+
+impl Viewport {
+    pub fn new(
+        bounds: (usize, usize),
+        upper_left: Complex<f64>,
+        lower_right: Complex<f64>,
+    ) -> Viewport {
+        Viewport {
+            bounds,
+            upper_left,
+            lower_right,
+            rotate: 0.0,
+        }
+    }
+
+    /// View around center, at zoom 1 it is 3 wide and as high as the aspect of bounds says
+    ///
+    /// ```
+    /// use mandelbrot::{Complex, Viewport};
+    ///
+    /// let viewport = Viewport::centered((200, 100), Complex::new(-1.0, 0.5), 1.5);
+    /// assert_eq!(viewport.upper_left, Complex::new(-2.0, 1.0));
+    /// assert_eq!(viewport.lower_right, Complex::new(0.0, 0.0));
+    /// ```
+    pub fn centered(bounds: (usize, usize), center: Complex<f64>, zoom: f64) -> Viewport {
+        let width = BASE_WIDTH / zoom;
+        let height = width * bounds.1 as f64 / bounds.0 as f64;
+        let half = Complex {
+            re: width / 2.0,
+            im: -height / 2.0,
+        };
+        Viewport::new(bounds, center - half, center + half)
+    }
+
+    /// Dot the pixel shows, the rotation included
+    pub fn pixel_to_dot(&self, pixel: (usize, usize)) -> Complex<f64> {
+        let dot = convert_pixel_to_dot(self.bounds, pixel, self.upper_left, self.lower_right);
+        turn(dot, self.upper_left, self.lower_right, self.rotate)
+    }
+}
+
+/// How a view is rendered, the defaults are the ones of the command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOptions {
+    pub mode: Mode,
+    /// Threads of the parallel modes
+    pub threads: usize,
+    /// Iterations before a dot is taken to be in the set
+    pub max_iter: usize,
+    pub fractal: Fractal,
+    pub palette: Palette,
+    /// Colors by normalized iteration count instead of whole iterations
+    pub smooth: bool,
+    pub coloring: Coloring,
+    /// Renders by perturbation with arbitrary precision, it is turned on by itself
+    /// when f64 can't tell the pixels apart
+    pub deep: bool,
+    /// Dots of the main cardioid and the period 2 bulb are not iterated
+    pub cardioid: bool,
+    /// Dots whose orbit repeats stop iterating
+    pub periodicity: bool,
+    /// Rectangles whose edge is in the set are filled without iterating them
+    pub fill: bool,
+    pub supersampling: Supersampling,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            mode: Mode::Single,
+            threads: num_cpus::get(),
+            max_iter: 255,
+            fractal: Fractal::Mandelbrot,
+            palette: Palette::builtin("gray").unwrap(),
+            smooth: false,
+            coloring: Coloring::Escape,
+            deep: false,
+            cardioid: false,
+            periodicity: false,
+            fill: false,
+            supersampling: Supersampling::Off,
+        }
+    }
+}
+
+/// RGB image with 16 bits per channel, CHANNELS values per pixel and rows from the top
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub bounds: (usize, usize),
+    pub pixels: Vec<u16>,
+}
+
+impl Image {
+    pub fn pixel(&self, (col, row): (usize, usize)) -> Rgb16 {
+        let index = (row * self.bounds.0 + col) * CHANNELS;
+        [
+            self.pixels[index],
+            self.pixels[index + 1],
+            self.pixels[index + 2],
+        ]
+    }
+
+    /// Channels rounded to 8 bits, as most image libraries take them
+    pub fn to_8_bit(&self) -> Vec<u8> {
+        output::to_8_bit(&self.pixels)
+    }
+
+    /// Writes the image in the format of the file extension, .png, .jpg, .webp, .tiff
+    /// or .ppm, with 8 or 16 bits per channel
+    pub fn save(&self, filename: &str, bit_depth: u8) -> Result<(), String> {
+        cli::check_bit_depth(filename, bit_depth)?;
+        output::write_image(filename, &self.pixels, self.bounds, bit_depth, &[])
+    }
+}
+
+/// Runs the command line of the mandelbrot binary, args don't include the program name.
+/// Errors are printed to stderr, bad arguments together with the usage.
+/// Returns the exit code of the process.
+pub fn run_command_line<I>(args: I) -> i32
+where
+    I: IntoIterator<Item = String>,
+{
+    let result = match cli::parse_command(args) {
+        Ok(command) => command.run(),
+        Err(message) => {
+            eprintln!("error: {}", message);
+            eprintln!("{}", cli::USAGE);
+            return 1;
+        }
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            1
+        }
+    }
+}
+
+/// Renders the viewport on the back end options.mode picks,
+/// every back end gives the same image
+///
+/// ```
+/// use mandelbrot::{render, Complex, Mode, RenderOptions, Viewport};
+///
+/// let viewport = Viewport::new((60, 40), Complex::new(-2.0, 1.0), Complex::new(1.0, -1.0));
+/// let single = render(&viewport, &RenderOptions::default()).unwrap();
+/// let rayon = RenderOptions {
+///     mode: Mode::Rayon,
+///     threads: 4,
+///     ..RenderOptions::default()
+/// };
+/// assert_eq!(render(&viewport, &rayon).unwrap(), single);
+///
+/// // Dots of the set are black
+/// assert_eq!(viewport.pixel_to_dot((40, 20)), Complex::new(0.0, 0.0));
+/// assert_eq!(single.pixel((40, 20)), [0, 0, 0]);
+/// ```
+pub fn render(viewport: &Viewport, options: &RenderOptions) -> Result<Image, String> {
+    // Corners go through text like the ones of the command line, f64 prints all its digits
+    let corner = |dot: Complex<f64>| format!("{},{}", dot.re, dot.im);
+    let options = Options::new(
+        String::new(),
+        viewport.bounds,
+        (&corner(viewport.upper_left), &corner(viewport.lower_right)),
+        viewport.rotate,
+        options.clone(),
+    )?;
+    Ok(Image {
+        bounds: viewport.bounds,
+        pixels: render_image(&options, false),
+    })
+}
+
+/// RGB pixels of the whole image rendered the way options.mode says,
+/// progress is shown on stderr by the modes that can tell it
+fn render_image(options: &Options, progress: bool) -> Vec<u16> {
+    let bounds = options.bounds;
+    let mut pixels = vec![0; bounds.0 * bounds.1 * CHANNELS];
+
+    match options.mode {
+        // Single threaded
+        Mode::Single => render_single_thread(&mut pixels, options),
+
+        // Multi threaded crossbeam - chunk per thread
+        Mode::Crossbeam => render_multi_thread_crossbeam(&mut pixels, options),
+
+        // Multi threaded rayon - line per thread, bands of lines for adaptive supersampling
+        Mode::Rayon => in_thread_pool(options.threads, || {
+            render_multi_thread_rayon(&mut pixels, options)
+        }),
+
+        // Multi threaded rayon - square tiles, the expensive ones first
+        Mode::Tiles => in_thread_pool(options.threads, || {
+            tiles::render_multi_thread_tiles(&mut pixels, options, progress)
+        }),
+    }
+
+    pixels
+}
+
+/// Runs rayon work on a pool of the requested size instead of the global one
+fn in_thread_pool<F: FnOnce() + Send>(threads: usize, work: F) {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .expect("error creating thread pool")
+        .install(work);
+}
+
+fn render_multi_thread_rayon(pixels: &mut [u16], options: &Options) {
+    let width = options.bounds.0;
+    let rows = match options.supersampling {
+        Supersampling::Adaptive(_) => ADAPTIVE_BAND_ROWS,
+        _ => 1,
+    };
+    let lines: Vec<(usize, &mut [u16])> = pixels
+        .chunks_mut(rows * width * CHANNELS)
+        .enumerate()
+        .collect();
+
+    lines
+        .into_par_iter()
+        .for_each(|(i, line)| render_part(line, i * rows, options));
+}
+
+fn render_multi_thread_crossbeam(pixels: &mut [u16], options: &Options) {
+    let bounds = options.bounds;
+    let threads = options.threads;
+    let rows_in_part = bounds.1 / threads + 1;
+
+    {
+        let parts: Vec<&mut [u16]> = pixels
+            .chunks_mut(rows_in_part * bounds.0 * CHANNELS)
+            .collect();
+        crossbeam::scope(|thread_spawner| {
+            for (i, part) in parts.into_iter().enumerate() {
+                thread_spawner.spawn(move |_| render_part(part, rows_in_part * i, options));
+            }
+        })
+        .unwrap();
+    }
+}
+
+fn render_single_thread(pixels: &mut [u16], options: &Options) {
+    render_part(pixels, 0, options);
+}
+
+#[test]
+fn test_render_modes() {
+    let fractals = [
+        "mandelbrot",
+        "julia:-0.8,0.156",
+        "burning-ship",
+        "tricorn",
+        "multibrot:3",
+    ];
+    for fractal in fractals {
+        let options = cli::parse_args(
+            format!(
+                "mandel.png 150x100 -1.2,0.35 -1.0,0.2 --threads 3 --max-iter 100 --fractal {}",
+                fractal
+            )
+            .split_whitespace()
+            .map(String::from),
+        )
+        .unwrap();
+        let mut expected = vec![0; 150 * 100 * CHANNELS];
+        render_single_thread(&mut expected, &options);
+
+        let renderers: [fn(&mut [u16], &Options); 4] = [
+            render_multi_thread_crossbeam,
+            render_multi_thread_rayon,
+            |pixels, options| tiles::render_multi_thread_tiles(pixels, options, false),
+            |pixels, options| tiles::render_multi_thread_tiles(pixels, options, true),
+        ];
+        for renderer in renderers {
+            let mut pixels = vec![0; 150 * 100 * CHANNELS];
+            renderer(&mut pixels, &options);
+            assert!(pixels == expected, "{}", fractal);
+        }
+    }
+}
+
+/// --fill can differ from the plain render where a thin channel outside the set passes
+/// between edge pixels, these views have none
+#[test]
+fn test_interior_optimizations() {
+    let views = [
+        "mandel.png 150x100 -2.2,1.2 0.8,-1.2 --max-iter 500",
+        "mandel.png 120x90 -0.8,0.2 -0.7,0.1 --max-iter 1000 --smooth",
+        "mandel.png 90x60 -1.3,0.1 -1.2,0.0 --max-iter 300 --fractal julia:-0.8,0.156",
+    ];
+    for view in views {
+        let plain = cli::parse_args(view.split_whitespace().map(String::from)).unwrap();
+        let expected = render_image(&plain, false);
+
+        for flags in [
+            "--cardioid",
+            "--periodicity",
+            "--fill",
+            "--cardioid --periodicity --fill --mode tiles --threads 3",
+        ] {
+            if flags.contains("--fill") && plain.fractal != Fractal::Mandelbrot {
+                continue;
+            }
+            let options = cli::parse_args(
+                format!("{} {}", view, flags)
+                    .split_whitespace()
+                    .map(String::from),
+            )
+            .unwrap();
+            assert!(
+                render_image(&options, false) == expected,
+                "{} {}",
+                view,
+                flags
+            );
+        }
+    }
+}
+
+/// Compares the render modes on the benchmark view, where the set fills part of the rows only:
+/// cargo test --release bench_render_modes -- --ignored --nocapture
+#[test]
+#[ignore]
+fn bench_render_modes() {
+    for mode in ["single", "crossbeam", "rayon", "tiles"] {
+        let options = cli::parse_args(
+            format!(
+                "mandel.png 2000x1500 -1.08,0.28 -1.03,0.23 --max-iter 1000 --mode {}",
+                mode
+            )
+            .split_whitespace()
+            .map(String::from),
+        )
+        .unwrap();
+
+        // Best of 3, the first run also warms up the caches
+        let best = (0..3)
+            .map(|_| {
+                let started = std::time::Instant::now();
+                render_image(&options, false);
+                started.elapsed().as_secs_f64()
+            })
+            .fold(f64::INFINITY, f64::min);
+        println!("{:<10} {:.3} sec ({} threads)", mode, best, options.threads);
+    }
+}
+
+/// Render the rows of the image from top on into RGB colors of the palette.
+/// Dots are those of the whole image, so every split of it gets the same ones.
+fn render_part(pixels: &mut [u16], top: usize, options: &Options) {
+    let width = options.bounds.0;
+    let height = pixels.len() / (width * CHANNELS);
+    assert!(pixels.len() == width * height * CHANNELS);
+    let (bounds, upper_left, lower_right) =
+        (options.bounds, options.upper_left, options.lower_right);
+
+    if options.fill {
+        let dot_of =
+            |col, row| convert_pixel_to_dot(bounds, (col, top + row), upper_left, lower_right);
+        interior::render_filled(pixels, (width, height), dot_of, options);
+        return;
+    }
+    if options.supersampling != Supersampling::Off {
+        let dot_of = |col, row| {
+            convert_subpixel_to_dot(bounds, (col, top as f64 + row), upper_left, lower_right)
+        };
+        supersample::render_supersampled(pixels, (width, height), dot_of, options);
+        return;
+    }
+
+    for (row, line) in pixels.chunks_mut(width * CHANNELS).enumerate() {
+        let dots: Vec<Complex<f64>> = (0..width)
+            .map(|col| convert_pixel_to_dot(bounds, (col, top + row), upper_left, lower_right))
+            .collect();
+        render_dots(line, &dots, options);
+    }
+}
+
+/// Colors a line of dots
+fn render_dots(pixels: &mut [u16], dots: &[Complex<f64>], options: &Options) {
+    assert!(pixels.len() == dots.len() * CHANNELS);
+
+    for (pixel, (_, color)) in pixels
+        .chunks_mut(CHANNELS)
+        .zip(escape_colors(dots, options))
+    {
+        pixel.copy_from_slice(&color);
+    }
+}
+
+/// Escape times of the dots with their colors in options.coloring
+fn escape_colors(dots: &[Complex<f64>], options: &Options) -> Vec<(Option<f64>, Rgb16)> {
+    match options.coloring {
+        Coloring::Escape => escapes(dots, options)
+            .into_iter()
+            .map(|escape| (escape, escape_color(escape, options)))
+            .collect(),
+        Coloring::Distance | Coloring::Shaded => distance::escape_colors(dots, options),
+    }
+}
+
+/// Escape times the dots are colored by, None for the dots of the set.
+/// Mandelbrot escape times are computed several dots at a time by the SIMD kernel.
+fn escapes(dots: &[Complex<f64>], options: &Options) -> Vec<Option<f64>> {
+    let rotated: Vec<Complex<f64>>;
+    let dots = if options.rotate != 0.0 {
+        rotated = dots.iter().map(|&dot| rotate_dot(dot, options)).collect();
+        &rotated
+    } else {
+        dots
+    };
+
+    let mandelbrot = options.fractal == Fractal::Mandelbrot && options.deep.is_none();
+    // Dots of the cardioid and the bulb are in the set without iterating them
+    let inside: Vec<bool> = dots
+        .iter()
+        .map(|&dot| options.cardioid && mandelbrot && interior::in_cardioid_or_bulb(dot))
+        .collect();
+
+    if mandelbrot && !options.smooth {
+        let iterated: Vec<Complex<f64>> = dots
+            .iter()
+            .zip(&inside)
+            .filter(|(_, &inside)| !inside)
+            .map(|(&dot, _)| dot)
+            .collect();
+        let mut times =
+            simd::escape_times(&iterated, options.max_iter, options.periodicity).into_iter();
+        inside
+            .iter()
+            .map(|&inside| {
+                if inside {
+                    None
+                } else {
+                    times.next().unwrap().map(|count| count as f64)
+                }
+            })
+            .collect()
+    } else {
+        dots.iter()
+            .zip(&inside)
+            .map(|(&dot, &inside)| if inside { None } else { escape(dot, options) })
+            .collect()
+    }
+}
+
+fn escape(dot: Complex<f64>, options: &Options) -> Option<f64> {
+    // Deep zoom dots are relative to the reference orbit
+    let (limit, periodicity) = (options.max_iter, options.periodicity);
+    match (&options.deep, options.smooth) {
+        (Some(deep), true) => deep.smooth_escape_time(dot, limit),
+        (Some(deep), false) => deep.escape_time(dot, limit).map(|count| count as f64),
+        (None, true) => options.fractal.smooth_escape_time(dot, limit, periodicity),
+        (None, false) => options
+            .fractal
+            .escape_time(dot, limit, periodicity)
+            .map(|count| count as f64),
+    }
+}
+
+/// Turns the dot of a pixel around the middle of the view by options.rotate degrees,
+/// renderers work on the view as if it was not turned and every dot goes through here
+fn rotate_dot(dot: Complex<f64>, options: &Options) -> Complex<f64> {
+    turn(dot, options.upper_left, options.lower_right, options.rotate)
+}
+
+/// Turns the dot around the middle of the corners by degrees counterclockwise
+fn turn(
+    dot: Complex<f64>,
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    degrees: f64,
+) -> Complex<f64> {
+    if degrees == 0.0 {
+        return dot;
+    }
+    let middle = (upper_left + lower_right) / 2.0;
+    middle + (dot - middle) * Complex::from_polar(1.0, degrees.to_radians())
+}
+
+#[test]
+fn test_rotate_dot() {
+    let options = cli::parse_args(
+        "mandel.png 200x100 --center -1,0.5 --zoom 1.5 --rotate 90"
+            .split_whitespace()
+            .map(String::from),
+    )
+    .unwrap();
+    assert_eq!(options.upper_left, Complex { re: -2.0, im: 1.0 });
+    assert_eq!(options.lower_right, Complex { re: 0.0, im: 0.0 });
+    assert_eq!(options.aspect_distortion(), 1.0);
+
+    // The upper left corner goes to the lower left one, the middle stays where it is
+    let near = |a: Complex<f64>, b: Complex<f64>| (a - b).norm() < 1e-12;
+    let turned = rotate_dot(options.upper_left, &options);
+    assert!(near(turned, Complex { re: -1.5, im: -0.5 }), "{}", turned);
+    let middle = Complex { re: -1.0, im: 0.5 };
+    assert!(near(rotate_dot(middle, &options), middle));
+
+    let options = Options {
+        rotate: 0.0,
+        ..options
+    };
+    assert_eq!(rotate_dot(options.upper_left, &options), options.upper_left);
+}
+
+/// Dots of the set are black, the others get the palette color of their escape time
+/// relative to the iteration limit
+fn escape_color(escape: Option<f64>, options: &Options) -> Rgb16 {
+    match escape {
+        None => [0, 0, 0],
+        Some(count) => options.palette.color(count / options.max_iter as f64),
+    }
+}
+
+/// converts from pixel space to dot space knowing boundary boxes for both
+///
+/// ```
+/// use mandelbrot::{convert_pixel_to_dot, Complex};
+///
+/// let dot = convert_pixel_to_dot(
+///     (100, 200),
+///     (25, 175),
+///     Complex::new(-1.0, 1.0),
+///     Complex::new(1.0, -1.0),
+/// );
+/// assert_eq!(dot, Complex::new(-0.5, -0.75));
+/// ```
+pub fn convert_pixel_to_dot(
+    pixel_frame_col_row: (usize, usize),
+    pixel_col_row: (usize, usize),
+    dot_left_upper: Complex<f64>,
+    dot_right_lower: Complex<f64>,
+) -> Complex<f64> {
+    convert_subpixel_to_dot(
+        pixel_frame_col_row,
+        (pixel_col_row.0 as f64, pixel_col_row.1 as f64),
+        dot_left_upper,
+        dot_right_lower,
+    )
+}
+
+/// convert_pixel_to_dot for positions between the pixels, whole positions give the same dots
+fn convert_subpixel_to_dot(
+    pixel_frame_col_row: (usize, usize),
+    pixel_col_row: (f64, f64),
+    dot_left_upper: Complex<f64>,
+    dot_right_lower: Complex<f64>,
+) -> Complex<f64> {
+    let dot_frame_width = dot_right_lower.re - dot_left_upper.re;
+    let dot_frame_height = dot_left_upper.im - dot_right_lower.im;
+    let dot_re_relative = pixel_col_row.0 * dot_frame_width / pixel_frame_col_row.0 as f64;
+    let dot_im_relative = pixel_col_row.1 * dot_frame_height / pixel_frame_col_row.1 as f64;
+
+    Complex {
+        re: dot_left_upper.re + dot_re_relative,
+        im: dot_left_upper.im - dot_im_relative,
+    }
+}
+
+#[test]
+fn test_convert_pixel_to_dot() {
+    assert_eq!(
+        convert_pixel_to_dot(
+            (100, 200),
+            (25, 175),
+            Complex { re: -1.0, im: 1.0 },
+            Complex { re: 1.0, im: -1.0 }
+        ),
+        Complex {
+            re: -0.5,
+            im: -0.75
+        }
+    );
+}
+
+/// Parses pairs of T separated by separator char.
+/// None if could not parse
+/// (T,T) if parsing was successful
+///
+/// ```
+/// use mandelbrot::parse_pair;
+///
+/// assert_eq!(parse_pair::<usize>("1000x750", 'x'), Some((1000, 750)));
+/// assert_eq!(parse_pair::<usize>("1000x", 'x'), None);
+/// ```
+pub fn parse_pair<T: FromStr>(s: &str, separator: char) -> Option<(T, T)> {
+    match s.find(separator) {
+        None => None,
+        Some(index) => match (T::from_str(&s[..index]), T::from_str(&s[index + 1..])) {
+            (Ok(left), Ok(right)) => Some((left, right)),
+            _ => None,
+        },
+    }
+}
+
+#[test]
+fn test_parse_pair() {
+    assert_eq!(parse_pair::<i32>("", ','), None);
+    assert_eq!(parse_pair::<i32>("10,", ','), None);
+    assert_eq!(parse_pair::<i32>(",10", ','), None);
+    assert_eq!(parse_pair::<i32>("10,20", ','), Some((10, 20)));
+    assert_eq!(parse_pair::<i32>("10,20zz", ','), None);
+    assert_eq!(parse_pair::<f64>("0.5x", 'x'), None);
+    assert_eq!(parse_pair::<f64>("0.5x0.6", 'x'), Some((0.5, 0.6)));
+}
+
+/// parse complex number like 1.2,4.5 from a string
+///
+/// ```
+/// use mandelbrot::{parse_complex, Complex};
+///
+/// assert_eq!(parse_complex("-1.08,0.28"), Some(Complex::new(-1.08, 0.28)));
+/// assert_eq!(parse_complex("-1.08"), None);
+/// ```
+pub fn parse_complex(s: &str) -> Option<Complex<f64>> {
+    parse_pair(s, ',').map(|(re, im)| Complex { re, im })
+}
+
+#[test]
+fn test_parse_complex() {
+    assert_eq!(
+        parse_complex("1.25,-0.635"),
+        Some(Complex {
+            re: 1.25,
+            im: -0.635
+        })
+    );
+    assert_eq!(parse_complex(",1"), None);
+}
+
+/// None when after limit iterations we still think c is in Mandelbrot set
+/// (it doesn't go into infinity). Otherwise return iteration number when we
+/// found out that it is not in the set (it stays farther away than circle
+/// with radius 2 - it is proven that such points will move into infinity
+/// on later iterations)
+///
+/// ```
+/// use mandelbrot::{escape_time, Complex};
+///
+/// assert_eq!(escape_time(Complex::new(-1.0, 0.0), 255), None);
+/// assert_eq!(escape_time(Complex::new(1.0, 0.0), 255), Some(3));
+/// ```
+pub fn escape_time(c: Complex<f64>, limit: usize) -> Option<usize> {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+
+    for i in 0..limit {
+        if z.norm_sqr() > 4.0 {
+            return Some(i);
+        }
+        z = z * z + c;
+    }
+
+    None
+}
+
+/// Bailout radius of the smooth escape time, the larger it is
+/// the closer the fractional part gets to a continuous function
+pub const SMOOTH_BAILOUT: f64 = 256.0;
+
+/// Normalized iteration count: escape time with a fractional part taken from
+/// how far past the bailout radius the final z landed, so colors don't form bands
+pub fn smooth_escape_time(c: Complex<f64>, limit: usize) -> Option<f64> {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+
+    for i in 0..limit {
+        let norm_sqr = z.norm_sqr();
+        if norm_sqr > SMOOTH_BAILOUT * SMOOTH_BAILOUT {
+            return Some(smooth_iteration(i, norm_sqr, 2.0));
+        }
+        z = z * z + c;
+    }
+
+    None
+}
+
+/// Fractional iteration count of a dot that escaped on iteration i
+/// with |z|^2 = norm_sqr, for z = z^degree + c
+pub fn smooth_iteration(i: usize, norm_sqr: f64, degree: f64) -> f64 {
+    // ln |z| = ln(|z|^2) / 2
+    let fraction = (norm_sqr.ln() / 2.0).ln() / degree.ln();
+    (i as f64 + 1.0 - fraction).max(0.0)
+}
+
+#[test]
+fn test_smooth_escape_time() {
+    // Inside the set
+    assert_eq!(smooth_escape_time(Complex { re: -0.5, im: 0.0 }, 100), None);
+
+    // Smooth value grows continuously as the dot gets closer to the set
+    let mut last = smooth_escape_time(Complex { re: 0.5, im: 0.0 }, 1000).unwrap();
+    for step in 1..50 {
+        let dot = Complex {
+            re: 0.5 - step as f64 * 0.002,
+            im: 0.0,
+        };
+        let escape = smooth_escape_time(dot, 1000).unwrap();
+        assert!(escape >= last);
+        assert!(escape - last < 1.0);
+        last = escape;
+    }
+}
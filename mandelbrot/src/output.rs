@@ -0,0 +1,307 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::tiff::TiffEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{ExtendedColorType, ImageEncoder};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::CHANNELS;
+
+/// Quality of JPEG images, high enough that the fine filaments survive
+const JPEG_QUALITY: u8 = 92;
+
+/// Image file formats, picked by the file extension
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Png,
+    Jpeg,
+    WebP,
+    Tiff,
+    Ppm,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Result<Format, String> {
+        let extension = path
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_ascii_lowercase())
+            .unwrap_or_default();
+        match extension.as_str() {
+            "png" => Ok(Format::Png),
+            "jpg" | "jpeg" => Ok(Format::Jpeg),
+            "webp" => Ok(Format::WebP),
+            "tif" | "tiff" => Ok(Format::Tiff),
+            "ppm" => Ok(Format::Ppm),
+            _ => Err(format!(
+                "unknown image format of '{}', expected .png, .jpg, .webp, .tiff or .ppm",
+                path
+            )),
+        }
+    }
+
+    /// True for the formats RowWriter can write
+    pub fn has_row_writer(&self) -> bool {
+        matches!(self, Format::Png | Format::Ppm)
+    }
+
+    /// True for the formats that can store 16 bits per channel
+    pub fn has_16_bit(&self) -> bool {
+        matches!(self, Format::Png | Format::Tiff | Format::Ppm)
+    }
+}
+
+/// Writes the pixels in the format of the file extension with bit_depth bits per channel.
+/// Metadata goes into PNG text chunks, the other formats leave it out.
+pub fn write_image(
+    filename: &str,
+    pixels: &[u16],
+    bounds: (usize, usize),
+    bit_depth: u8,
+    metadata: &[(String, String)],
+) -> Result<(), String> {
+    assert!(pixels.len() == bounds.0 * bounds.1 * CHANNELS);
+
+    let format = Format::from_path(filename)?;
+    let error = |e: String| format!("can't write image '{}': {}", filename, e);
+    let output = BufWriter::new(File::create(filename).map_err(|e| error(e.to_string()))?);
+    if format.has_row_writer() {
+        let mut writer =
+            RowWriter::new(output, format, bounds, bit_depth, metadata).map_err(error)?;
+        writer.write_rows(pixels).map_err(error)?;
+        return writer.finish().map_err(error);
+    }
+
+    // The encoders of image take the 16 bit channels in native byte order
+    let (bytes, color_type) = if bit_depth == 16 {
+        let bytes: Vec<u8> = pixels
+            .iter()
+            .flat_map(|channel| channel.to_ne_bytes())
+            .collect();
+        (bytes, ExtendedColorType::Rgb16)
+    } else {
+        (to_8_bit(pixels), ExtendedColorType::Rgb8)
+    };
+    let (width, height) = (bounds.0 as u32, bounds.1 as u32);
+    let result = match format {
+        Format::Jpeg => JpegEncoder::new_with_quality(output, JPEG_QUALITY)
+            .write_image(&bytes, width, height, color_type),
+        Format::WebP => {
+            WebPEncoder::new_lossless(output).write_image(&bytes, width, height, color_type)
+        }
+        Format::Tiff => TiffEncoder::new(output).write_image(&bytes, width, height, color_type),
+        Format::Png | Format::Ppm => unreachable!(),
+    };
+    result.map_err(|e| error(e.to_string()))
+}
+
+/// PNG of the pixels with bit_depth bits per channel and a text chunk per metadata entry
+pub fn encode_png<W: Write>(
+    output: W,
+    pixels: &[u16],
+    bounds: (usize, usize),
+    bit_depth: u8,
+    metadata: &[(String, String)],
+) -> Result<(), String> {
+    assert!(pixels.len() == bounds.0 * bounds.1 * CHANNELS);
+
+    let mut writer = png_writer(output, bounds, bit_depth, metadata)?;
+    writer
+        .write_image_data(&big_endian_bytes(pixels, bit_depth))
+        .map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())
+}
+
+/// PNG encoder that has written the header and the text chunks
+fn png_writer<W: Write>(
+    output: W,
+    bounds: (usize, usize),
+    bit_depth: u8,
+    metadata: &[(String, String)],
+) -> Result<png::Writer<W>, String> {
+    let mut encoder = png::Encoder::new(output, bounds.0 as u32, bounds.1 as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(if bit_depth == 16 {
+        png::BitDepth::Sixteen
+    } else {
+        png::BitDepth::Eight
+    });
+    for (keyword, text) in metadata {
+        encoder
+            .add_text_chunk(keyword.clone(), text.clone())
+            .map_err(|e| e.to_string())?;
+    }
+    encoder.write_header().map_err(|e| e.to_string())
+}
+
+/// Channels as PNG and PPM store them, 16 bit ones big endian
+fn big_endian_bytes(pixels: &[u16], bit_depth: u8) -> Vec<u8> {
+    if bit_depth == 16 {
+        pixels
+            .iter()
+            .flat_map(|channel| channel.to_be_bytes())
+            .collect()
+    } else {
+        to_8_bit(pixels)
+    }
+}
+
+/// Writes an image a band of rows at a time, the whole image never has to be in memory.
+/// Only PNG and PPM images can be written this way.
+pub struct RowWriter<W: Write + 'static> {
+    encoder: RowEncoder<W>,
+    bit_depth: u8,
+    /// Channels of the rows not written yet
+    remaining: usize,
+}
+
+enum RowEncoder<W: Write + 'static> {
+    Png(Box<png::StreamWriter<'static, W>>),
+    /// Binary PPM, image only writes 8 bit ones
+    Ppm(W),
+}
+
+impl<W: Write + 'static> RowWriter<W> {
+    /// Writes the header of the image, metadata goes into PNG text chunks
+    pub fn new(
+        mut output: W,
+        format: Format,
+        bounds: (usize, usize),
+        bit_depth: u8,
+        metadata: &[(String, String)],
+    ) -> Result<RowWriter<W>, String> {
+        let encoder = match format {
+            Format::Png => {
+                let writer = png_writer(output, bounds, bit_depth, metadata)?;
+                RowEncoder::Png(Box::new(
+                    writer.into_stream_writer().map_err(|e| e.to_string())?,
+                ))
+            }
+            Format::Ppm => {
+                let max_value = if bit_depth == 16 { u16::MAX } else { 255 };
+                write!(output, "P6\n{} {}\n{}\n", bounds.0, bounds.1, max_value)
+                    .map_err(|e| e.to_string())?;
+                RowEncoder::Ppm(output)
+            }
+            _ => {
+                return Err(format!(
+                "{:?} images can't be written a band of rows at a time, only .png and .ppm ones",
+                format
+            ))
+            }
+        };
+
+        Ok(RowWriter {
+            encoder,
+            bit_depth,
+            remaining: bounds.0 * bounds.1 * CHANNELS,
+        })
+    }
+
+    /// Writes the next rows of the image
+    pub fn write_rows(&mut self, pixels: &[u16]) -> Result<(), String> {
+        assert!(pixels.len() <= self.remaining);
+        self.remaining -= pixels.len();
+
+        let bytes = big_endian_bytes(pixels, self.bit_depth);
+        let output: &mut dyn Write = match &mut self.encoder {
+            RowEncoder::Png(writer) => writer,
+            RowEncoder::Ppm(output) => output,
+        };
+        output.write_all(&bytes).map_err(|e| e.to_string())
+    }
+
+    /// Ends the image once all of its rows are written
+    pub fn finish(self) -> Result<(), String> {
+        assert!(self.remaining == 0, "image is missing rows");
+        match self.encoder {
+            RowEncoder::Png(writer) => writer.finish().map_err(|e| e.to_string()),
+            RowEncoder::Ppm(mut output) => output.flush().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Text chunks of a PNG as keyword and text
+pub fn read_metadata(filename: &str) -> Result<Vec<(String, String)>, String> {
+    let error = |e: String| format!("can't read image '{}': {}", filename, e);
+    let file = File::open(filename).map_err(|e| error(e.to_string()))?;
+    let reader = png::Decoder::new(file)
+        .read_info()
+        .map_err(|e| error(e.to_string()))?;
+
+    Ok(reader
+        .info()
+        .uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+        .collect())
+}
+
+/// Rounds 16 bit channels to the nearest 8 bit value
+pub fn to_8_bit(pixels: &[u16]) -> Vec<u8> {
+    pixels
+        .iter()
+        .map(|&channel| ((channel as u32 * 255 + 32767) / 65535) as u8)
+        .collect()
+}
+
+#[test]
+fn test_format_from_path() {
+    assert_eq!(Format::from_path("mandel.png"), Ok(Format::Png));
+    assert_eq!(Format::from_path("out/mandel.JPG"), Ok(Format::Jpeg));
+    assert_eq!(Format::from_path("mandel.jpeg"), Ok(Format::Jpeg));
+    assert_eq!(Format::from_path("mandel.webp"), Ok(Format::WebP));
+    assert_eq!(Format::from_path("mandel.tif"), Ok(Format::Tiff));
+    assert_eq!(Format::from_path("mandel.ppm"), Ok(Format::Ppm));
+    assert!(Format::from_path("mandel.gif").is_err());
+    assert!(Format::from_path("mandel").is_err());
+}
+
+#[test]
+fn test_to_8_bit() {
+    assert_eq!(
+        to_8_bit(&[0, 257, 128 * 257, 65535, 385]),
+        [0, 1, 128, 255, 1]
+    );
+    assert_eq!(to_8_bit(&[384]), [1]);
+    assert_eq!(to_8_bit(&[128]), [0]);
+}
+
+#[test]
+fn test_write_image() {
+    let directory = std::env::temp_dir().join(format!("mandelbrot-output-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let pixels: Vec<u16> = (0..4 * 3 * CHANNELS as u16).map(|i| i * 1000).collect();
+    let metadata = vec![("Size".to_string(), "4x3".to_string())];
+
+    // PNGs are read with png, image only has the decoders of the other formats
+    for name in ["a.jpg", "a.webp", "a.tiff", "a.ppm"] {
+        let path = directory.join(name).to_string_lossy().into_owned();
+        write_image(&path, &pixels, (4, 3), 8, &metadata).unwrap();
+        let image = image::open(&path).unwrap();
+        assert_eq!((image.width(), image.height()), (4, 3), "{}", name);
+        assert_eq!(image.color(), image::ColorType::Rgb8, "{}", name);
+
+        if Format::from_path(&path).unwrap().has_16_bit() {
+            write_image(&path, &pixels, (4, 3), 16, &metadata).unwrap();
+            let image = image::open(&path).unwrap();
+            assert_eq!(image.into_rgb16().into_raw(), pixels, "{}", name);
+        }
+    }
+
+    // 16 bit PNGs keep the channels and the metadata
+    let path = directory.join("a.png").to_string_lossy().into_owned();
+    write_image(&path, &pixels, (4, 3), 16, &metadata).unwrap();
+    assert_eq!(read_metadata(&path), Ok(metadata));
+    let mut reader = png::Decoder::new(File::open(&path).unwrap())
+        .read_info()
+        .unwrap();
+    let mut bytes = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut bytes).unwrap();
+    let channels: Vec<u16> = bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    assert_eq!(channels, pixels);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,199 @@
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+pub type Rgb = [u8; 3];
+
+/// Rgb with 16 bits per channel
+pub type Rgb16 = [u16; 3];
+
+/// Built-in palettes as (name, gradient stops from fast escaping dots to the slow ones)
+const BUILTIN: [(&str, &[Rgb]); 5] = [
+    ("gray", &[[255, 255, 255], [0, 0, 0]]),
+    (
+        "fire",
+        &[
+            [0, 0, 0],
+            [128, 0, 0],
+            [255, 80, 0],
+            [255, 200, 0],
+            [255, 255, 220],
+        ],
+    ),
+    (
+        "ocean",
+        &[
+            [0, 7, 30],
+            [0, 60, 120],
+            [0, 150, 190],
+            [160, 230, 240],
+            [255, 255, 255],
+        ],
+    ),
+    // Gradient popularized by Ultra Fractal
+    (
+        "ultra",
+        &[
+            [0, 7, 100],
+            [32, 107, 203],
+            [237, 255, 255],
+            [255, 170, 0],
+            [0, 2, 0],
+        ],
+    ),
+    (
+        "rainbow",
+        &[
+            [148, 0, 211],
+            [0, 0, 255],
+            [0, 255, 0],
+            [255, 255, 0],
+            [255, 127, 0],
+            [255, 0, 0],
+        ],
+    ),
+];
+
+/// Gradient between evenly spaced color stops
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    stops: Vec<Rgb>,
+}
+
+impl Palette {
+    pub fn builtin(name: &str) -> Option<Palette> {
+        BUILTIN
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, stops)| Palette {
+                stops: stops.to_vec(),
+            })
+    }
+
+    pub fn names() -> Vec<&'static str> {
+        BUILTIN.iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Palette file has a color per line as rrggbb hex, optionally starting with #.
+    /// Empty lines and lines starting with // are skipped.
+    pub fn from_file(path: &str) -> Result<Palette, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("can't read palette file '{}': {}", path, e))?;
+        Palette::parse(&text).map_err(|e| format!("invalid palette file '{}': {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let mut stops = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            let color = parse_hex_color(line)
+                .ok_or_else(|| format!("line {}: '{}' is not a rrggbb color", number + 1, line))?;
+            stops.push(color);
+        }
+
+        if stops.len() < 2 {
+            return Err("at least two colors are needed".to_string());
+        }
+        Ok(Palette { stops })
+    }
+
+    /// Color at position t of the gradient with 16 bits per channel,
+    /// t is clamped to 0..=1
+    pub fn color(&self, t: f64) -> Rgb16 {
+        let position = t.clamp(0.0, 1.0) * (self.stops.len() - 1) as f64;
+        let index = (position as usize).min(self.stops.len() - 2);
+        let fraction = position - index as f64;
+
+        let (from, to) = (self.stops[index], self.stops[index + 1]);
+        let mut color = [0; 3];
+        for channel in 0..3 {
+            // 257 maps 255 to 65535, the gradient gets 257 times as many steps
+            let (from, to) = (from[channel] as f64 * 257.0, to[channel] as f64 * 257.0);
+            color[channel] = (from + (to - from) * fraction).round() as u16;
+        }
+        color
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// Name of a built-in palette, path to a palette file or rrggbb colors separated by commas
+    fn from_str(s: &str) -> Result<Palette, String> {
+        match Palette::builtin(s) {
+            Some(palette) => Ok(palette),
+            None if fs::metadata(s).is_ok() => Palette::from_file(s),
+            None if s.contains(',') => Palette::parse(&s.replace(',', "\n")),
+            None => Err(format!(
+                "'{}' is neither a palette file nor one of {}",
+                s,
+                Palette::names().join(", ")
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Palette {
+    /// Name of the built-in palette or the colors separated by commas, as FromStr takes them
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let builtin = BUILTIN
+            .iter()
+            .find(|(_, stops)| *stops == self.stops.as_slice());
+        match builtin {
+            Some((name, _)) => write!(f, "{}", name),
+            None => {
+                let colors: Vec<String> = self
+                    .stops
+                    .iter()
+                    .map(|color| format!("{:02x}{:02x}{:02x}", color[0], color[1], color[2]))
+                    .collect();
+                write!(f, "{}", colors.join(","))
+            }
+        }
+    }
+}
+
+fn parse_hex_color(s: &str) -> Option<Rgb> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[test]
+fn test_palette_color() {
+    let gray = Palette::builtin("gray").unwrap();
+    assert_eq!(gray.color(0.0), [65535, 65535, 65535]);
+    assert_eq!(gray.color(1.0), [0, 0, 0]);
+    assert_eq!(gray.color(0.5), [32768, 32768, 32768]);
+    assert_eq!(gray.color(7.0), [0, 0, 0]);
+
+    let fire = Palette::builtin("fire").unwrap();
+    assert_eq!(fire.color(0.25), [128 * 257, 0, 0]);
+    assert_eq!(fire.color(0.125), [64 * 257, 0, 0]);
+
+    assert!(Palette::builtin("plaid").is_none());
+    assert!("plaid".parse::<Palette>().is_err());
+}
+
+#[test]
+fn test_palette_parse() {
+    let palette = Palette::parse("// sunset\n#ff0000\n\n00ff80\n").unwrap();
+    assert_eq!(palette.stops, vec![[255, 0, 0], [0, 255, 128]]);
+
+    assert!(Palette::parse("#ff0000").is_err());
+    assert!(Palette::parse("#ff0000\nred").is_err());
+    assert!(Palette::parse("#ff0000\n#ff00").is_err());
+
+    // Colors on the command line and back
+    let palette: Palette = "ff0000,#00ff80".parse().unwrap();
+    assert_eq!(palette, Palette::parse("ff0000\n00ff80").unwrap());
+    assert_eq!(palette.to_string(), "ff0000,00ff80");
+    assert_eq!(Palette::builtin("ultra").unwrap().to_string(), "ultra");
+}
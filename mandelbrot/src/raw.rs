@@ -0,0 +1,385 @@
+use num::Complex;
+use rayon::prelude::*;
+use std::fs;
+use std::time::Instant;
+
+use crate::cli::Options;
+use crate::fractal::Fractal;
+use crate::interior;
+use crate::output::write_image;
+use crate::palette::Palette;
+use crate::{convert_pixel_to_dot, in_thread_pool, rotate_dot, smooth_iteration, CHANNELS};
+
+/// Renders to files with this extension save the escape data instead of colors
+pub const RAW_EXTENSION: &str = ".raw";
+
+/// Escape data files start with it, the last byte is the version of the format
+const MAGIC: &[u8; 8] = b"MANDRAW2";
+
+/// MAGIC, width, height, max_iter and degree
+const HEADER_SIZE: usize = 8 + 4 + 4 + 4 + 8;
+
+/// Bytes of a pixel, the count, the smooth count and |z|
+const PIXEL_SIZE: usize = 4 + 4 + 4;
+
+/// Count of the dots of the set in the file, max_iter is kept below it
+const INSIDE: u32 = u32::MAX;
+
+/// Where the dot of a pixel left the circle of radius 2 and the smooth bailout circle
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Escape {
+    /// Escape time of the plain coloring
+    pub count: u32,
+    /// Iteration and final |z| at the smooth bailout, None if z didn't get there
+    /// within max_iter. f32 is plenty for the fraction of the smooth coloring.
+    pub smooth: Option<(u32, f32)>,
+}
+
+/// Escape data of a render, it can be colored any number of times without iterating again.
+/// Files are little endian: MAGIC, width, height and max_iter as u32 and degree as f64,
+/// then the pixels row by row as the count u32, the smooth count u32 and the final |z| f32.
+/// Dots of the set have count INSIDE, dots that stop short of the smooth bailout
+/// have smooth count INSIDE.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EscapeData {
+    pub bounds: (usize, usize),
+    pub max_iter: usize,
+    /// Exponent of z in the fractal, the smooth coloring needs it
+    pub degree: f64,
+    /// None for the dots of the set
+    pub pixels: Vec<Option<Escape>>,
+}
+
+impl EscapeData {
+    /// Renders the escape data of the image line by line on options.threads threads
+    pub fn render(options: &Options) -> EscapeData {
+        let bounds = options.bounds;
+        let mut pixels = vec![None; bounds.0 * bounds.1];
+
+        in_thread_pool(options.threads, || {
+            pixels
+                .par_chunks_mut(bounds.0)
+                .enumerate()
+                .for_each(|(row, line)| {
+                    for (col, pixel) in line.iter_mut().enumerate() {
+                        let dot = convert_pixel_to_dot(
+                            bounds,
+                            (col, row),
+                            options.upper_left,
+                            options.lower_right,
+                        );
+                        *pixel = escape(dot, options);
+                    }
+                });
+        });
+
+        EscapeData {
+            bounds,
+            max_iter: options.max_iter,
+            degree: options.fractal.degree(),
+            pixels,
+        }
+    }
+
+    /// Escape time of the pixel, with the fraction of the smooth coloring when smooth is set.
+    /// None for the dots the smooth render leaves in the set.
+    pub fn escape_time(&self, escape: Escape, smooth: bool) -> Option<f64> {
+        if !smooth {
+            return Some(escape.count as f64);
+        }
+        escape.smooth.map(|(count, norm)| {
+            let norm = norm as f64;
+            smooth_iteration(count as usize, norm * norm, self.degree)
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.pixels.len() * PIXEL_SIZE);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(self.bounds.0 as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.bounds.1 as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.max_iter as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.degree.to_le_bytes());
+        for pixel in &self.pixels {
+            let (count, (smooth_count, norm)) = match pixel {
+                Some(escape) => (escape.count, escape.smooth.unwrap_or((INSIDE, 0.0))),
+                None => (INSIDE, (INSIDE, 0.0)),
+            };
+            bytes.extend_from_slice(&count.to_le_bytes());
+            bytes.extend_from_slice(&smooth_count.to_le_bytes());
+            bytes.extend_from_slice(&norm.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<EscapeData, String> {
+        if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+            return Err("not an escape data file".to_string());
+        }
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let bounds = (u32_at(8) as usize, u32_at(12) as usize);
+        let max_iter = u32_at(16) as usize;
+        let degree = f64::from_le_bytes(bytes[20..28].try_into().unwrap());
+
+        let data = &bytes[HEADER_SIZE..];
+        let size = bounds
+            .0
+            .checked_mul(bounds.1)
+            .and_then(|pixels| pixels.checked_mul(PIXEL_SIZE));
+        if size != Some(data.len()) {
+            return Err(format!(
+                "expected {}x{} pixels, the pixel data is {} bytes long",
+                bounds.0,
+                bounds.1,
+                data.len()
+            ));
+        }
+        let pixels = data
+            .chunks(PIXEL_SIZE)
+            .map(|pixel| {
+                let u32_at = |offset: usize| {
+                    u32::from_le_bytes(pixel[offset..offset + 4].try_into().unwrap())
+                };
+                let (count, smooth_count) = (u32_at(0), u32_at(4));
+                let norm = f32::from_le_bytes(pixel[8..].try_into().unwrap());
+                (count != INSIDE).then_some(Escape {
+                    count,
+                    smooth: (smooth_count != INSIDE).then_some((smooth_count, norm)),
+                })
+            })
+            .collect();
+
+        Ok(EscapeData {
+            bounds,
+            max_iter,
+            degree,
+            pixels,
+        })
+    }
+
+    pub fn read(path: &str) -> Result<EscapeData, String> {
+        let bytes = fs::read(path).map_err(|e| format!("can't read '{}': {}", path, e))?;
+        EscapeData::decode(&bytes)
+            .map_err(|e| format!("invalid escape data file '{}': {}", path, e))
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.encode()).map_err(|e| format!("can't write '{}': {}", path, e))
+    }
+}
+
+/// Same dots as escapes, the orbits go on to the bailout of the smooth coloring
+fn escape(dot: Complex<f64>, options: &Options) -> Option<Escape> {
+    let dot = rotate_dot(dot, options);
+    let mandelbrot = options.fractal == Fractal::Mandelbrot && options.deep.is_none();
+    if options.cardioid && mandelbrot && interior::in_cardioid_or_bulb(dot) {
+        return None;
+    }
+
+    let counts = match &options.deep {
+        Some(deep) => deep.escape_counts(dot, options.max_iter),
+        None => options
+            .fractal
+            .escape_counts(dot, options.max_iter, options.periodicity),
+    };
+    counts.map(|(count, orbit)| Escape {
+        count: count as u32,
+        smooth: orbit.map(|(count, norm_sqr)| (count as u32, norm_sqr.sqrt() as f32)),
+    })
+}
+
+/// Renders the escape data of options into options.file
+pub fn export(options: &Options) -> Result<(), String> {
+    let (width, height) = options.bounds;
+    if width > u32::MAX as usize || height > u32::MAX as usize {
+        return Err("escape data files are at most 4294967295 pixels wide and high".to_string());
+    }
+    // Counts stay below max_iter, so none of them is taken for INSIDE
+    if options.max_iter > INSIDE as usize {
+        return Err("escape data files take --max-iter up to 4294967295".to_string());
+    }
+    let started = Instant::now();
+    let data = EscapeData::render(options);
+    println!(
+        "Rendered escape data of {}x{} in {:.3} sec ({} threads, {} iterations)",
+        options.bounds.0,
+        options.bounds.1,
+        started.elapsed().as_secs_f64(),
+        options.threads,
+        options.max_iter
+    );
+    data.write(&options.file)
+}
+
+/// Colors escape data into a PNG
+#[derive(Debug, Clone, PartialEq)]
+pub struct Colorize {
+    pub input: String,
+    pub output: String,
+    pub palette: Palette,
+    pub smooth: bool,
+    /// Spreads the palette evenly over the pixels instead of over the iterations
+    pub equalize: bool,
+    /// Palette positions are raised to 1 / gamma, above 1 more of the palette
+    /// goes to the dots that escape fast
+    pub gamma: f64,
+    /// Bits per channel of the image, 8 or 16
+    pub bit_depth: u8,
+}
+
+impl Colorize {
+    pub fn run(&self) -> Result<(), String> {
+        let data = EscapeData::read(&self.input)?;
+        write_image(
+            &self.output,
+            &self.colors(&data),
+            data.bounds,
+            self.bit_depth,
+            &[],
+        )
+    }
+
+    /// RGB pixels of the escape data. Without equalize and gamma they are the colors
+    /// of a render, escape time relative to max_iter picks the palette color.
+    pub fn colors(&self, data: &EscapeData) -> Vec<u16> {
+        let times: Vec<Option<f64>> = data
+            .pixels
+            .iter()
+            .map(|pixel| pixel.and_then(|escape| data.escape_time(escape, self.smooth)))
+            .collect();
+
+        let mut sorted: Vec<f64> = times.iter().flatten().cloned().collect();
+        if self.equalize {
+            sorted.sort_unstable_by(f64::total_cmp);
+        }
+        // Share of the escaped pixels that escaped at the same time or faster
+        let rank =
+            |time: f64| sorted.partition_point(|&other| other <= time) as f64 / sorted.len() as f64;
+
+        let mut pixels = Vec::with_capacity(times.len() * CHANNELS);
+        for time in times {
+            let color = match time {
+                None => [0, 0, 0],
+                Some(time) => {
+                    let t = if self.equalize {
+                        rank(time)
+                    } else {
+                        time / data.max_iter as f64
+                    };
+                    self.palette.color(t.clamp(0.0, 1.0).powf(1.0 / self.gamma))
+                }
+            };
+            pixels.extend_from_slice(&color);
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+fn test_data() -> EscapeData {
+    let options = crate::cli::parse_args(
+        "mandel.raw 40x30 -2.2,1.2 0.8,-1.2 --max-iter 100 --threads 2"
+            .split_whitespace()
+            .map(String::from),
+    )
+    .unwrap();
+    EscapeData::render(&options)
+}
+
+#[test]
+fn test_escape_data_encode() {
+    let data = test_data();
+    assert!(data.pixels.iter().any(Option::is_none));
+    assert!(data.pixels.iter().flatten().all(|escape| escape
+        .smooth
+        .is_some_and(|(count, norm)| { count > escape.count && norm > 256.0 })));
+
+    let bytes = data.encode();
+    assert_eq!(bytes.len(), HEADER_SIZE + 40 * 30 * PIXEL_SIZE);
+    assert_eq!(EscapeData::decode(&bytes), Ok(data));
+
+    assert!(EscapeData::decode(&bytes[..bytes.len() - 1]).is_err());
+    assert!(EscapeData::decode(b"MANDRAW").is_err());
+    assert!(EscapeData::decode(&[0; HEADER_SIZE]).is_err());
+
+    // Sizes that overflow are rejected, not wrapped around
+    let mut huge = bytes[..HEADER_SIZE].to_vec();
+    huge[8..16].copy_from_slice(&[0xff; 8]);
+    assert!(EscapeData::decode(&huge).is_err());
+}
+
+#[test]
+fn test_export_limits() {
+    let parse = |flags: &str| {
+        crate::cli::parse_args(
+            format!("mandel.raw 4x3 -2.2,1.2 0.8,-1.2 {}", flags)
+                .split_whitespace()
+                .map(String::from),
+        )
+        .unwrap()
+    };
+    assert!(export(&parse("--max-iter 4294967296")).is_err());
+    let mut options = parse("");
+    options.bounds = (1 << 32, 1);
+    assert!(export(&options).is_err());
+}
+
+#[test]
+fn test_colorize() {
+    let data = test_data();
+    let colorize = Colorize {
+        input: String::new(),
+        output: String::new(),
+        palette: Palette::builtin("fire").unwrap(),
+        smooth: true,
+        equalize: false,
+        gamma: 1.0,
+        bit_depth: 8,
+    };
+
+    // Smooth colors are the ones of a smooth render, up to the f32 |z| that moves them
+    // by a few of the 65535 steps of a channel
+    let options = crate::cli::parse_args(
+        "mandel.png 40x30 -2.2,1.2 0.8,-1.2 --max-iter 100 --smooth --palette fire"
+            .split_whitespace()
+            .map(String::from),
+    )
+    .unwrap();
+    let rendered = crate::render_image(&options, false);
+    let colors = colorize.colors(&data);
+    assert!(colors
+        .iter()
+        .zip(&rendered)
+        .all(|(&a, &b)| (a as i32 - b as i32).abs() <= 16));
+
+    // Plain colors are exactly the ones of a plain render
+    let options = crate::cli::parse_args(
+        "mandel.png 40x30 -2.2,1.2 0.8,-1.2 --max-iter 100 --palette fire"
+            .split_whitespace()
+            .map(String::from),
+    )
+    .unwrap();
+    let plain = Colorize {
+        smooth: false,
+        ..colorize.clone()
+    };
+    assert!(plain.colors(&data) == crate::render_image(&options, false));
+
+    // Equalized, the palette is spread over the pixels: the slowest dots get its end
+    let equalized = Colorize {
+        equalize: true,
+        ..colorize.clone()
+    }
+    .colors(&data);
+    let end = colorize.palette.color(1.0);
+    assert!(equalized.chunks(CHANNELS).any(|pixel| pixel == end));
+
+    let brighter = Colorize {
+        gamma: 2.0,
+        ..colorize.clone()
+    }
+    .colors(&data);
+    let brightness = |pixels: &[u16]| pixels.iter().map(|&channel| channel as u64).sum::<u64>();
+    assert!(brightness(&brighter) > brightness(&colors));
+}
@@ -0,0 +1,323 @@
+use lru::LruCache;
+use num::Complex;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::animation::BASE_WIDTH;
+use crate::cli::{self, Options};
+use crate::output::encode_png;
+use crate::{convert_pixel_to_dot, render_image};
+
+/// Middle of the square covered by the only tile of zoom 0
+const WORLD_CENTER: Complex<f64> = Complex { re: -0.75, im: 0.0 };
+
+/// Deepest zoom served. Tile corners stay exact in f64 well past it,
+/// the limit is the page, which positions tiles with f64 as well.
+pub const MAX_ZOOM: u32 = 40;
+
+const EXPLORER_PAGE: &str = include_str!("explorer.html");
+
+/// Tile coordinates as in slippy maps: zoom z has 2^z by 2^z tiles,
+/// x grows to the right and y grows downwards
+type TileKey = (u32, u64, u64);
+
+/// Browser explorer of the set, tiles are rendered on demand and kept in a cache
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explorer {
+    /// Address listened on, loopback unless the explorer is meant for other machines
+    pub host: IpAddr,
+    pub port: u16,
+    /// Side of the square tiles in pixels
+    pub tile_size: usize,
+    /// Number of rendered tiles kept in memory
+    pub cache_size: usize,
+    /// Render arguments without the positional ones, shared by all tiles
+    pub render_args: Vec<String>,
+}
+
+impl Explorer {
+    /// Tiles of a zoom together make up one square image 2^zoom tiles wide,
+    /// the corners of a tile are the dots of its corner pixels in that image
+    pub fn tile_corners(&self, (zoom, x, y): TileKey) -> (Complex<f64>, Complex<f64>) {
+        let world_pixels = (self.tile_size as u64) << zoom;
+        let world_bounds = (world_pixels as usize, world_pixels as usize);
+        let (upper_left, lower_right) = world_corners();
+
+        let pixel = |x: u64, y: u64| (x as usize * self.tile_size, y as usize * self.tile_size);
+        (
+            convert_pixel_to_dot(world_bounds, pixel(x, y), upper_left, lower_right),
+            convert_pixel_to_dot(world_bounds, pixel(x + 1, y + 1), upper_left, lower_right),
+        )
+    }
+
+    /// Render options of the tile, built from the same arguments as a single render.
+    /// The corners are exact in f64 and are passed on as text,
+    /// so tiles too deep for f64 are rendered by perturbation.
+    pub fn tile_options(&self, tile: TileKey) -> Result<Options, String> {
+        let (upper_left, lower_right) = self.tile_corners(tile);
+        let mut args = vec![
+            "tile.png".to_string(),
+            format!("{}x{}", self.tile_size, self.tile_size),
+            format!("{},{}", upper_left.re, upper_left.im),
+            format!("{},{}", lower_right.re, lower_right.im),
+        ];
+        args.extend(self.render_args.iter().cloned());
+        cli::parse_args(args)
+    }
+
+    /// PNG of the tile
+    pub fn render_tile(&self, tile: TileKey) -> Result<Vec<u8>, String> {
+        let options = self.tile_options(tile)?;
+        let pixels = render_image(&options, false);
+
+        let mut png = Vec::new();
+        encode_png(&mut png, &pixels, options.bounds, 8, &[])?;
+        Ok(png)
+    }
+
+    /// Serves the explorer until the process is stopped
+    pub fn run(&self) -> Result<(), String> {
+        let address = SocketAddr::new(self.host, self.port);
+        let server =
+            Server::http(address).map_err(|e| format!("can't listen on {}: {}", address, e))?;
+        println!("Explorer is at http://{}, Ctrl+C stops it", address);
+        self.serve(&server, num_cpus::get());
+        Ok(())
+    }
+
+    /// Answers requests on a pool of workers, each tile is rendered by one of them.
+    /// Returns when every worker was unblocked.
+    fn serve(&self, server: &Server, workers: usize) {
+        let cache = Mutex::new(LruCache::new(
+            NonZeroUsize::new(self.cache_size).expect("cache size must not be zero"),
+        ));
+
+        crossbeam::scope(|thread_spawner| {
+            for _ in 0..workers {
+                thread_spawner.spawn(|_| {
+                    while let Ok(request) = server.recv() {
+                        self.respond(request, &cache);
+                    }
+                });
+            }
+        })
+        .unwrap();
+    }
+
+    fn respond(&self, request: Request, cache: &Mutex<LruCache<TileKey, Arc<Vec<u8>>>>) {
+        let url = request.url().to_string();
+        let result = if url == "/" {
+            request.respond(
+                Response::from_string(self.page())
+                    .with_header(content_type("text/html; charset=utf-8")),
+            )
+        } else if let Some(tile) = parse_tile_url(&url) {
+            match self.cached_tile(tile, cache) {
+                Ok(png) => request.respond(
+                    Response::from_data(png.as_slice()).with_header(content_type("image/png")),
+                ),
+                Err(message) => {
+                    eprintln!("error rendering tile {}: {}", url, message);
+                    request.respond(Response::from_string(message).with_status_code(500))
+                }
+            }
+        } else {
+            request.respond(Response::from_string("not found").with_status_code(404))
+        };
+
+        if let Err(e) = result {
+            eprintln!("error answering {}: {}", url, e);
+        }
+    }
+
+    /// Tile from the cache, rendered when it isn't there.
+    /// The lock is not held while rendering, so other workers keep serving.
+    fn cached_tile(
+        &self,
+        tile: TileKey,
+        cache: &Mutex<LruCache<TileKey, Arc<Vec<u8>>>>,
+    ) -> Result<Arc<Vec<u8>>, String> {
+        if let Some(png) = cache.lock().unwrap().get(&tile) {
+            return Ok(png.clone());
+        }
+
+        let png = Arc::new(self.render_tile(tile)?);
+        cache.lock().unwrap().put(tile, png.clone());
+        Ok(png)
+    }
+
+    fn page(&self) -> String {
+        let (upper_left, lower_right) = world_corners();
+        EXPLORER_PAGE
+            .replace("$TILE_SIZE", &self.tile_size.to_string())
+            .replace("$MAX_ZOOM", &MAX_ZOOM.to_string())
+            .replace("$WORLD_LEFT", &upper_left.re.to_string())
+            .replace("$WORLD_TOP", &upper_left.im.to_string())
+            .replace(
+                "$WORLD_WIDTH",
+                &(lower_right.re - upper_left.re).to_string(),
+            )
+    }
+}
+
+/// Corners of the square covered by zoom 0
+fn world_corners() -> (Complex<f64>, Complex<f64>) {
+    let half = BASE_WIDTH / 2.0;
+    (
+        Complex {
+            re: WORLD_CENTER.re - half,
+            im: WORLD_CENTER.im + half,
+        },
+        Complex {
+            re: WORLD_CENTER.re + half,
+            im: WORLD_CENTER.im - half,
+        },
+    )
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes("Content-Type", value).unwrap()
+}
+
+/// Tile of /tiles/z/x/y.png, None for other urls and tiles outside of the zoom
+fn parse_tile_url(url: &str) -> Option<TileKey> {
+    let path = url.strip_prefix("/tiles/")?.strip_suffix(".png")?;
+    let mut parts = path.split('/');
+    let zoom: u32 = parts.next()?.parse().ok()?;
+    let x: u64 = parts.next()?.parse().ok()?;
+    let y: u64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || zoom > MAX_ZOOM || x >> zoom != 0 || y >> zoom != 0 {
+        return None;
+    }
+    Some((zoom, x, y))
+}
+
+#[cfg(test)]
+fn test_explorer() -> Explorer {
+    Explorer {
+        host: IpAddr::from([127, 0, 0, 1]),
+        port: 0,
+        tile_size: 32,
+        cache_size: 2,
+        render_args: vec!["--max-iter".to_string(), "50".to_string()],
+    }
+}
+
+#[test]
+fn test_parse_tile_url() {
+    assert_eq!(parse_tile_url("/tiles/0/0/0.png"), Some((0, 0, 0)));
+    assert_eq!(parse_tile_url("/tiles/3/7/2.png"), Some((3, 7, 2)));
+    assert_eq!(parse_tile_url("/tiles/3/8/2.png"), None);
+    assert_eq!(parse_tile_url("/tiles/41/0/0.png"), None);
+    assert_eq!(parse_tile_url("/tiles/1/0.png"), None);
+    assert_eq!(parse_tile_url("/tiles/1/0/0/0.png"), None);
+    assert_eq!(parse_tile_url("/tiles/1/-1/0.png"), None);
+    assert_eq!(parse_tile_url("/favicon.ico"), None);
+}
+
+#[test]
+fn test_tile_corners() {
+    let explorer = test_explorer();
+    let (upper_left, lower_right) = explorer.tile_corners((0, 0, 0));
+    assert_eq!(upper_left, Complex { re: -2.25, im: 1.5 });
+    assert_eq!(lower_right, Complex { re: 0.75, im: -1.5 });
+
+    // Tiles of a zoom are pieces of one image, neighbours share their edges
+    let world = ((32 << 3) as usize, (32 << 3) as usize);
+    for (x, y) in [(0, 0), (3, 5), (7, 7)] {
+        let (upper_left, lower_right) = explorer.tile_corners((3, x, y));
+        assert_eq!(
+            upper_left,
+            convert_pixel_to_dot(
+                world,
+                (x as usize * 32, y as usize * 32),
+                Complex { re: -2.25, im: 1.5 },
+                Complex { re: 0.75, im: -1.5 }
+            )
+        );
+        assert_eq!(explorer.tile_corners((3, x + 1, y)).0.re, lower_right.re);
+        assert_eq!(explorer.tile_corners((3, x, y + 1)).0.im, lower_right.im);
+        assert_eq!(explorer.tile_corners((4, 2 * x, 2 * y)).0, upper_left);
+    }
+
+    // Deep tiles are exact and go to the perturbation render
+    let explorer = Explorer {
+        tile_size: 256,
+        ..explorer
+    };
+    let options = explorer.tile_options((MAX_ZOOM, 1 << 39, 1 << 39)).unwrap();
+    assert!(options.deep.is_some());
+}
+
+#[test]
+fn test_cached_tile() {
+    let explorer = test_explorer();
+    let cache = Mutex::new(LruCache::new(NonZeroUsize::new(2).unwrap()));
+    let first = explorer.cached_tile((1, 0, 0), &cache).unwrap();
+    assert!(Arc::ptr_eq(
+        &first,
+        &explorer.cached_tile((1, 0, 0), &cache).unwrap()
+    ));
+
+    // The least recently used tile goes first
+    explorer.cached_tile((1, 1, 0), &cache).unwrap();
+    explorer.cached_tile((1, 0, 0), &cache).unwrap();
+    explorer.cached_tile((1, 0, 1), &cache).unwrap();
+    let cache = cache.lock().unwrap();
+    assert!(cache.contains(&(1, 0, 0)));
+    assert!(!cache.contains(&(1, 1, 0)));
+}
+
+#[test]
+fn test_serve() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let explorer = test_explorer();
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let address = server.server_addr().to_ip().unwrap();
+    let get = |url: &str| {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\n\r\n", url).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&response[..split]).into_owned();
+        (head, response[split + 4..].to_vec())
+    };
+
+    crossbeam::scope(|thread_spawner| {
+        thread_spawner.spawn(|_| explorer.serve(&server, 2));
+
+        let (head, page) = get("/");
+        assert!(head.starts_with("HTTP/1.0 200"), "{}", head);
+        let page = String::from_utf8(page).unwrap();
+        assert!(page.contains("const TILE_SIZE = 32;"));
+        assert!(!page.contains('$'));
+
+        // A tile is the same image as a single render of its corners
+        let (head, png) = get("/tiles/2/1/2.png");
+        assert!(head.contains("image/png"), "{}", head);
+        let options = explorer.tile_options((2, 1, 2)).unwrap();
+        let mut expected = Vec::new();
+        encode_png(
+            &mut expected,
+            &render_image(&options, false),
+            (32, 32),
+            8,
+            &[],
+        )
+        .unwrap();
+        assert!(png == expected);
+        assert!(get("/tiles/2/1/2.png").1 == expected);
+
+        assert!(get("/tiles/2/4/0.png").0.starts_with("HTTP/1.0 404"));
+        assert!(get("/mandel.png").0.starts_with("HTTP/1.0 404"));
+
+        server.unblock();
+        server.unblock();
+    })
+    .unwrap();
+}
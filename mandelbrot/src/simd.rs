@@ -0,0 +1,225 @@
+use num::Complex;
+
+use crate::fractal::Fractal;
+
+/// Dots iterated at once, two AVX registers of f64 per coordinate
+pub const LANES: usize = 8;
+
+/// Same as escape_time for every dot, LANES dots at a time when the CPU has vector
+/// instructions for them and one at a time otherwise.
+/// Results are exactly the scalar ones: the same f64 operations run in the same order,
+/// each lane just stops changing once its dot escaped.
+/// With periodicity set, lanes whose orbit came back to an earlier z stop early, see Cycle.
+pub fn escape_times(dots: &[Complex<f64>], limit: usize, periodicity: bool) -> Vec<Option<usize>> {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") {
+        // Safe, the CPU has just been checked for AVX2
+        return unsafe { escape_times_avx2(dots, limit, periodicity) };
+    }
+
+    escape_times_scalar(dots, limit, periodicity)
+}
+
+fn escape_times_scalar(
+    dots: &[Complex<f64>],
+    limit: usize,
+    periodicity: bool,
+) -> Vec<Option<usize>> {
+    dots.iter()
+        .map(|&dot| Fractal::Mandelbrot.escape_time(dot, limit, periodicity))
+        .collect()
+}
+
+/// The lanes code compiled with AVX2, the compiler turns the lane loops into vector instructions
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn escape_times_avx2(
+    dots: &[Complex<f64>],
+    limit: usize,
+    periodicity: bool,
+) -> Vec<Option<usize>> {
+    escape_times_lanes(dots, limit, periodicity)
+}
+
+/// Splits the dots into groups of LANES, the last group is padded with a dot
+/// that escapes right away
+#[inline(always)]
+fn escape_times_lanes(
+    dots: &[Complex<f64>],
+    limit: usize,
+    periodicity: bool,
+) -> Vec<Option<usize>> {
+    let mut times = Vec::with_capacity(dots.len());
+    for chunk in dots.chunks(LANES) {
+        let mut re = [3.0; LANES];
+        let mut im = [0.0; LANES];
+        for (lane, dot) in chunk.iter().enumerate() {
+            re[lane] = dot.re;
+            im[lane] = dot.im;
+        }
+        let lanes = if periodicity {
+            escape_time_lanes::<true>(re, im, limit)
+        } else {
+            escape_time_lanes::<false>(re, im, limit)
+        };
+        times.extend_from_slice(&lanes[..chunk.len()]);
+    }
+    times
+}
+
+/// escape_time of LANES dots, the loop ends as soon as none of them is left
+#[inline(always)]
+fn escape_time_lanes<const PERIODICITY: bool>(
+    c_re: [f64; LANES],
+    c_im: [f64; LANES],
+    limit: usize,
+) -> [Option<usize>; LANES] {
+    let mut z_re = [0.0; LANES];
+    let mut z_im = [0.0; LANES];
+    let mut iterations = [0u64; LANES];
+    // Masks as in vector compares, all bits set while the lane is still iterated
+    // and once the dot of the lane escaped
+    let mut active = [u64::MAX; LANES];
+    let mut escaped = [0u64; LANES];
+    // Brent's cycle detection as in Cycle, all lanes save z on the same iterations
+    let mut saved_re = [f64::NAN; LANES];
+    let mut saved_im = [f64::NAN; LANES];
+    let mut next_save = 1;
+
+    // Each step is a loop over the lanes on its own, the shape the compiler vectorizes
+    for i in 0..limit {
+        // Written as in Complex: norm_sqr and z * z + c
+        let mut norm_sqr = [0.0; LANES];
+        for lane in 0..LANES {
+            norm_sqr[lane] = z_re[lane] * z_re[lane] + z_im[lane] * z_im[lane];
+        }
+        for lane in 0..LANES {
+            // Not <= 4.0, a NaN dot never escapes in escape_time either
+            let outside = mask(norm_sqr[lane] > 4.0);
+            escaped[lane] |= active[lane] & outside;
+            active[lane] &= !outside;
+        }
+        if PERIODICITY {
+            for lane in 0..LANES {
+                let repeats = z_re[lane] == saved_re[lane] && z_im[lane] == saved_im[lane];
+                active[lane] &= !mask(repeats);
+            }
+            if i == next_save {
+                saved_re = z_re;
+                saved_im = z_im;
+                next_save *= 2;
+            }
+        }
+        if active.iter().fold(0, |any, &mask| any | mask) == 0 {
+            break;
+        }
+
+        for lane in 0..LANES {
+            let (re, im) = (z_re[lane], z_im[lane]);
+            let next_re = re * re - im * im + c_re[lane];
+            let next_im = re * im + im * re + c_im[lane];
+            z_re[lane] = select(active[lane], next_re, re);
+            z_im[lane] = select(active[lane], next_im, im);
+            iterations[lane] += active[lane] & 1;
+        }
+    }
+
+    // Lanes still active after limit iterations might be in the set
+    let mut times = [None; LANES];
+    for lane in 0..LANES {
+        if escaped[lane] != 0 {
+            times[lane] = Some(iterations[lane] as usize);
+        }
+    }
+    times
+}
+
+/// All bits set for true
+#[inline(always)]
+fn mask(condition: bool) -> u64 {
+    (condition as u64).wrapping_neg()
+}
+
+/// if_set where the mask bits are set and otherwise where they are not
+#[inline(always)]
+fn select(mask: u64, if_set: f64, otherwise: f64) -> f64 {
+    f64::from_bits(if_set.to_bits() & mask | otherwise.to_bits() & !mask)
+}
+
+#[cfg(test)]
+fn test_dots() -> Vec<Complex<f64>> {
+    let mut dots = Vec::new();
+    // Whole set, the seahorse valley and an odd number of dots, so the last group is padded
+    for (upper_left, lower_right, bounds) in [
+        ((-2.2, 1.2), (0.8, -1.2), (61, 37)),
+        ((-0.76, 0.11), (-0.74, 0.09), (33, 29)),
+        ((-2.0, 0.001), (-1.99, -0.001), (7, 3)),
+    ] {
+        for row in 0..bounds.1 {
+            for col in 0..bounds.0 {
+                dots.push(Complex {
+                    re: upper_left.0
+                        + col as f64 * (lower_right.0 - upper_left.0) / bounds.0 as f64,
+                    im: upper_left.1
+                        - row as f64 * (upper_left.1 - lower_right.1) / bounds.1 as f64,
+                });
+            }
+        }
+    }
+    // On the escape radius, outside of it from the start and not a number
+    dots.extend([
+        Complex { re: 2.0, im: 0.0 },
+        Complex { re: -2.0, im: 0.0 },
+        Complex { re: 0.0, im: 2.0 },
+        Complex { re: 5.0, im: -5.0 },
+        Complex { re: 0.25, im: 0.0 },
+        Complex {
+            re: f64::NAN,
+            im: 0.0,
+        },
+    ]);
+    dots
+}
+
+#[test]
+fn test_escape_times() {
+    let dots = test_dots();
+    for limit in [1, 2, 17, 255, 1000] {
+        let expected: Vec<_> = dots
+            .iter()
+            .map(|&dot| crate::escape_time(dot, limit))
+            .collect();
+        for periodicity in [false, true] {
+            assert_eq!(
+                escape_times(&dots, limit, periodicity),
+                expected,
+                "limit {}",
+                limit
+            );
+            assert_eq!(
+                escape_times_scalar(&dots, limit, periodicity),
+                expected,
+                "limit {}",
+                limit
+            );
+            assert_eq!(
+                escape_times_lanes(&dots, limit, periodicity),
+                expected,
+                "limit {}",
+                limit
+            );
+
+            #[cfg(target_arch = "x86_64")]
+            if is_x86_feature_detected!("avx2") {
+                assert_eq!(
+                    unsafe { escape_times_avx2(&dots, limit, periodicity) },
+                    expected,
+                    "limit {}",
+                    limit
+                );
+            }
+        }
+    }
+
+    assert!(escape_times(&[], 100, true).is_empty());
+}
@@ -0,0 +1,154 @@
+use crossbeam::channel;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+use crate::cli::Options;
+use crate::output::{Format, RowWriter};
+use crate::{in_thread_pool, tiles, Mode, CHANNELS};
+
+/// Images with more pixels than this are streamed even without --stream,
+/// their pixels alone would take 800 MB
+pub const STREAM_PIXELS: usize = 1 << 27;
+
+/// Pixels of a band, a few bands are in memory at a time
+const BAND_PIXELS: usize = 1 << 22;
+
+/// Rows of a band, at least one however wide the image is
+pub fn band_rows(width: usize) -> usize {
+    (BAND_PIXELS / width).max(1)
+}
+
+/// Renders the image into options.file a band of rows at a time, so memory use stays
+/// the same however large the image is. Bands are rendered in tiles on the threads
+/// while the band before is compressed and written on a thread of its own.
+/// With progress set, the share of the rows done and the ETA are shown on stderr.
+pub fn render_streamed(options: &Options, progress: bool) -> Result<(), String> {
+    let error = |e: String| format!("can't write image '{}': {}", options.file, e);
+    let format = Format::from_path(&options.file)?;
+    let file = File::create(&options.file).map_err(|e| error(e.to_string()))?;
+    let writer = RowWriter::new(
+        BufWriter::new(file),
+        format,
+        options.bounds,
+        options.bit_depth,
+        &options.metadata(),
+    )
+    .map_err(error)?;
+
+    stream_bands(writer, band_rows(options.bounds.0), options, progress).map_err(error)
+}
+
+fn stream_bands<W: Write + Send>(
+    mut writer: RowWriter<W>,
+    band_rows: usize,
+    options: &Options,
+    progress: bool,
+) -> Result<(), String> {
+    let (width, height) = options.bounds;
+    let threads = if options.mode == Mode::Single {
+        1
+    } else {
+        options.threads
+    };
+    let started = Instant::now();
+    // One band waits to be written while the next one is rendered
+    let (sender, receiver) = channel::bounded::<Vec<u16>>(1);
+
+    crossbeam::scope(|thread_spawner| {
+        let written = thread_spawner.spawn(move |_| {
+            for band in receiver {
+                writer.write_rows(&band)?;
+            }
+            writer.finish()
+        });
+
+        in_thread_pool(threads, || {
+            for top in (0..height).step_by(band_rows) {
+                let rows = top..(top + band_rows).min(height);
+                let mut band = vec![0; width * rows.len() * CHANNELS];
+                tiles::render_rows_in_tiles(&mut band, rows.clone(), options, false);
+                // The writer only hangs up when it failed, it returns the error
+                if sender.send(band).is_err() {
+                    break;
+                }
+                if progress {
+                    print_progress(rows.end, height, started);
+                }
+            }
+            drop(sender);
+        });
+        if progress {
+            eprint!("\r{:40}\r", "");
+        }
+
+        written.join().unwrap()
+    })
+    .unwrap()
+}
+
+fn print_progress(rows_done: usize, height: usize, started: Instant) {
+    let fraction = rows_done as f64 / height as f64;
+    let elapsed = started.elapsed().as_secs_f64();
+    eprint!(
+        "\rRendering {:5.1}%, ETA {:.1} sec   ",
+        fraction * 100.0,
+        elapsed * (1.0 - fraction) / fraction
+    );
+}
+
+#[test]
+fn test_band_rows() {
+    assert_eq!(band_rows(4096), 1024);
+    assert_eq!(band_rows(100_000), 41);
+    assert_eq!(band_rows(10_000_000), 1);
+}
+
+#[test]
+fn test_stream_bands() {
+    let directory = std::env::temp_dir().join(format!("mandelbrot-stream-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    // Bands see the dots of the whole image, the adaptive samples and the rotation
+    // across band edges come out the same
+    for flags in [
+        "--max-iter 100",
+        "--smooth --supersample adaptive:2x2 --rotate 30 --mode single",
+        "--fill --bit-depth 16",
+    ] {
+        let view = "60x40 -2.2,1.2 0.8,-1.2 --palette fire --threads 2";
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+        let options = crate::cli::parse_args(
+            format!("{} {} {}", path("expected.ppm"), view, flags)
+                .split_whitespace()
+                .map(String::from),
+        )
+        .unwrap();
+        let pixels = crate::render_image(&options, false);
+        crate::output::write_image(
+            &options.file,
+            &pixels,
+            options.bounds,
+            options.bit_depth,
+            &[],
+        )
+        .unwrap();
+
+        for band_rows in [1, 16, 40, 64] {
+            let output = File::create(path("streamed.ppm")).unwrap();
+            let writer =
+                RowWriter::new(output, Format::Ppm, options.bounds, options.bit_depth, &[])
+                    .unwrap();
+            stream_bands(writer, band_rows, &options, false).unwrap();
+            assert!(
+                std::fs::read(path("streamed.ppm")).unwrap()
+                    == std::fs::read(path("expected.ppm")).unwrap(),
+                "{} in bands of {}",
+                flags,
+                band_rows
+            );
+        }
+    }
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
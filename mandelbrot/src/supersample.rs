@@ -0,0 +1,284 @@
+use num::Complex;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cli::Options;
+use crate::palette::Rgb16;
+use crate::{escape_colors, CHANNELS};
+
+/// How many dots a pixel is sampled at, their colors are averaged
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Supersampling {
+    /// One dot per pixel
+    Off,
+    /// n by n dots evenly spread over the pixel
+    Grid(usize),
+    /// n dots at random places in the pixel, trades the aliasing patterns of the grid for noise
+    Jitter(usize),
+    /// n by n dots for pixels next to a pixel with another escape count, one dot elsewhere
+    Adaptive(usize),
+}
+
+impl Supersampling {
+    /// Sample offsets in pixels from the dot of the pixel, they stay within half a pixel
+    /// of it, so the pixel keeps its place. Jitter offsets are picked by the seed.
+    fn offsets(&self, seed: u64) -> Vec<(f64, f64)> {
+        match *self {
+            Supersampling::Off => vec![(0.0, 0.0)],
+            Supersampling::Grid(n) | Supersampling::Adaptive(n) => {
+                let offset = |i: usize| (i as f64 + 0.5) / n as f64 - 0.5;
+                (0..n * n).map(|i| (offset(i % n), offset(i / n))).collect()
+            }
+            Supersampling::Jitter(n) => {
+                let mut state = seed;
+                let mut random = || (split_mix(&mut state) >> 11) as f64 / (1u64 << 53) as f64;
+                (0..n).map(|_| (random() - 0.5, random() - 0.5)).collect()
+            }
+        }
+    }
+}
+
+impl FromStr for Supersampling {
+    type Err = String;
+
+    /// off, NxN, jitter:n or adaptive:NxN
+    fn from_str(s: &str) -> Result<Supersampling, String> {
+        let grid = |s: &str| match s.split_once('x') {
+            Some((n, m)) if n == m => n.parse::<usize>().ok().filter(|n| (2..=8).contains(n)),
+            _ => None,
+        };
+        let error = || {
+            format!(
+                "unknown supersampling '{}', expected off, NxN, jitter:n or adaptive:NxN with N from 2 to 8 and n from 2 to 64",
+                s
+            )
+        };
+
+        if s == "off" {
+            Ok(Supersampling::Off)
+        } else if let Some(count) = s.strip_prefix("jitter:") {
+            match count.parse::<usize>() {
+                Ok(n) if (2..=64).contains(&n) => Ok(Supersampling::Jitter(n)),
+                _ => Err(error()),
+            }
+        } else if let Some(size) = s.strip_prefix("adaptive:") {
+            grid(size).map(Supersampling::Adaptive).ok_or_else(error)
+        } else {
+            grid(s).map(Supersampling::Grid).ok_or_else(error)
+        }
+    }
+}
+
+impl fmt::Display for Supersampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Supersampling::Off => write!(f, "off"),
+            Supersampling::Grid(n) => write!(f, "{}x{}", n, n),
+            Supersampling::Jitter(n) => write!(f, "jitter:{}", n),
+            Supersampling::Adaptive(n) => write!(f, "adaptive:{}x{}", n, n),
+        }
+    }
+}
+
+/// SplitMix64, a small generator whose every seed gives a good sequence
+pub fn split_mix(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Renders the pixels with options.supersampling, dot_of maps pixel coordinates
+/// to dots and takes coordinates between the pixels and outside of the frame as well
+pub fn render_supersampled<F>(
+    pixels: &mut [u16],
+    bounds: (usize, usize),
+    dot_of: F,
+    options: &Options,
+) where
+    F: Fn(f64, f64) -> Complex<f64>,
+{
+    assert!(pixels.len() == bounds.0 * bounds.1 * CHANNELS);
+
+    match options.supersampling {
+        Supersampling::Adaptive(_) => render_adaptive(pixels, bounds, &dot_of, options),
+        _ => {
+            for (row, line) in pixels.chunks_mut(bounds.0 * CHANNELS).enumerate() {
+                let cols: Vec<usize> = (0..bounds.0).collect();
+                render_samples(line, &cols, row, &dot_of, options);
+            }
+        }
+    }
+}
+
+/// Pixels on the edges of escape count bands and of the set get all the samples.
+/// Pixels just outside the frame are looked at too, so the frame edges
+/// are sampled the same as the rest of the image.
+fn render_adaptive<F>(pixels: &mut [u16], bounds: (usize, usize), dot_of: &F, options: &Options)
+where
+    F: Fn(f64, f64) -> Complex<f64>,
+{
+    let (width, height) = (bounds.0 + 2, bounds.1 + 2);
+    let dots: Vec<Complex<f64>> = (0..width * height)
+        .map(|i| dot_of((i % width) as f64 - 1.0, (i / width) as f64 - 1.0))
+        .collect();
+    let escape = escape_colors(&dots, options);
+    // Smooth escape times are compared by their whole iterations
+    let band = |col: usize, row: usize| escape[row * width + col].0.map(|count| count.floor());
+
+    for (row, line) in pixels.chunks_mut(bounds.0 * CHANNELS).enumerate() {
+        let mut edges = Vec::new();
+        for col in 0..bounds.0 {
+            let center = band(col + 1, row + 1);
+            let on_edge = (0..3)
+                .flat_map(|y| (0..3).map(move |x| (col + x, row + y)))
+                .any(|(x, y)| band(x, y) != center);
+            if on_edge {
+                edges.push(col);
+            } else {
+                let (_, color) = escape[(row + 1) * width + col + 1];
+                line[col * CHANNELS..(col + 1) * CHANNELS].copy_from_slice(&color);
+            }
+        }
+        render_samples(line, &edges, row, dot_of, options);
+    }
+}
+
+/// Renders the pixels of the columns of a line with all their samples,
+/// the samples of the whole line are iterated together
+fn render_samples<F>(line: &mut [u16], cols: &[usize], row: usize, dot_of: &F, options: &Options)
+where
+    F: Fn(f64, f64) -> Complex<f64>,
+{
+    let mut dots = Vec::new();
+    let mut counts = Vec::with_capacity(cols.len());
+    for &col in cols {
+        let pixel_dot = dot_of(col as f64, row as f64);
+        // Jitter is seeded by the dot, so a pixel gets the same samples in every render mode
+        let seed = pixel_dot.re.to_bits() ^ pixel_dot.im.to_bits().rotate_left(32);
+        let offsets = options.supersampling.offsets(seed);
+        counts.push(offsets.len());
+        for (x, y) in offsets {
+            dots.push(dot_of(col as f64 + x, row as f64 + y));
+        }
+    }
+
+    let mut samples = escape_colors(&dots, options).into_iter();
+    for (&col, &count) in cols.iter().zip(&counts) {
+        let colors: Vec<Rgb16> = samples
+            .by_ref()
+            .take(count)
+            .map(|(_, color)| color)
+            .collect();
+        line[col * CHANNELS..(col + 1) * CHANNELS].copy_from_slice(&average(&colors));
+    }
+}
+
+fn average(colors: &[Rgb16]) -> Rgb16 {
+    let mut sum = [0usize; 3];
+    for color in colors {
+        for channel in 0..3 {
+            sum[channel] += color[channel] as usize;
+        }
+    }
+    let count = colors.len();
+    sum.map(|total| ((total + count / 2) / count) as u16)
+}
+
+#[test]
+fn test_supersampling_from_str() {
+    assert_eq!("off".parse(), Ok(Supersampling::Off));
+    assert_eq!("2x2".parse(), Ok(Supersampling::Grid(2)));
+    assert_eq!("3x3".parse(), Ok(Supersampling::Grid(3)));
+    assert_eq!("jitter:16".parse(), Ok(Supersampling::Jitter(16)));
+    assert_eq!("adaptive:3x3".parse(), Ok(Supersampling::Adaptive(3)));
+    for s in ["off", "2x2", "jitter:16", "adaptive:3x3"] {
+        assert_eq!(s.parse::<Supersampling>().unwrap().to_string(), s);
+    }
+    for s in ["1x1", "2x3", "9x9", "jitter:1", "jitter", "adaptive", "4"] {
+        assert!(s.parse::<Supersampling>().is_err(), "{}", s);
+    }
+}
+
+#[test]
+fn test_supersampling_offsets() {
+    assert_eq!(
+        Supersampling::Grid(2).offsets(0),
+        vec![(-0.25, -0.25), (0.25, -0.25), (-0.25, 0.25), (0.25, 0.25)]
+    );
+
+    let jitter = Supersampling::Jitter(32);
+    assert_eq!(jitter.offsets(7), jitter.offsets(7));
+    assert_ne!(jitter.offsets(7), jitter.offsets(8));
+    assert!(jitter
+        .offsets(7)
+        .iter()
+        .all(|&(x, y)| (-0.5..0.5).contains(&x) && (-0.5..0.5).contains(&y)));
+}
+
+#[test]
+fn test_average() {
+    assert_eq!(average(&[[0, 0, 0], [255, 255, 255]]), [128, 128, 128]);
+    assert_eq!(average(&[[10, 20, 30]; 9]), [10, 20, 30]);
+}
+
+#[cfg(test)]
+fn test_render(view: &str, flags: &str) -> Vec<u16> {
+    let options = crate::cli::parse_args(
+        format!("{} {}", view, flags)
+            .split_whitespace()
+            .map(String::from),
+    )
+    .unwrap();
+    crate::render_image(&options, false)
+}
+
+#[test]
+fn test_render_supersampled() {
+    let view = "mandel.png 90x60 -1.3,0.2 -1.0,0.0 --max-iter 200 --palette fire";
+    let plain = test_render(view, "");
+    let grid = test_render(view, "--supersample 3x3");
+    assert!(grid != plain);
+
+    // Every render mode samples the same dots
+    for flags in ["jitter:5", "adaptive:3x3", "3x3 --smooth"] {
+        let expected = test_render(view, &format!("--supersample {}", flags));
+        for mode in ["crossbeam", "rayon", "tiles"] {
+            let pixels = test_render(
+                view,
+                &format!("--supersample {} --mode {} --threads 3", flags, mode),
+            );
+            assert!(pixels == expected, "{} {}", flags, mode);
+        }
+    }
+
+    // Adaptive pixels are either the plain ones or the grid ones, with some of both
+    let adaptive = test_render(view, "--supersample adaptive:3x3");
+    let pixels = |image: &[u16]| {
+        image
+            .chunks(CHANNELS)
+            .map(<[u16]>::to_vec)
+            .collect::<Vec<_>>()
+    };
+    let (plain, grid, adaptive) = (pixels(&plain), pixels(&grid), pixels(&adaptive));
+    let mut sampled = 0;
+    for i in 0..adaptive.len() {
+        assert!(
+            adaptive[i] == plain[i] || adaptive[i] == grid[i],
+            "pixel {}",
+            i
+        );
+        if adaptive[i] != plain[i] {
+            sampled += 1;
+        }
+    }
+    assert!(sampled > 0 && sampled < adaptive.len() / 2);
+
+    // Inside the set there are no edges to smooth
+    let inside = "mandel.png 30x20 -0.2,0.1 0.1,-0.1 --max-iter 100";
+    for flags in ["2x2", "jitter:4", "adaptive:2x2"] {
+        let pixels = test_render(inside, &format!("--supersample {}", flags));
+        assert!(pixels.iter().all(|&channel| channel == 0), "{}", flags);
+    }
+}
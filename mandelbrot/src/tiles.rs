@@ -0,0 +1,261 @@
+use crossbeam::channel::{self, RecvTimeoutError};
+use num::Complex;
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::cli::Options;
+use crate::interior;
+use crate::supersample::{self, Supersampling};
+use crate::{convert_pixel_to_dot, convert_subpixel_to_dot, render_dots, rotate_dot, CHANNELS};
+
+/// Largest side of the square tiles in pixels
+const TILE_SIZE: usize = 64;
+
+/// Smallest side tiles are split down to for small images or many threads
+const MIN_TILE_SIZE: usize = 16;
+
+/// Tiles per thread wanted, so the cheap tiles at the end fill the gaps between threads
+const TILES_PER_THREAD: usize = 8;
+
+/// Dots per side of the grid probed to estimate how expensive a tile is
+const PROBES: usize = 3;
+
+/// How often progress is printed
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Rectangle of the image rendered on its own
+struct Tile {
+    left: usize,
+    top: usize,
+    bounds: (usize, usize),
+    /// Estimated iterations needed to render the tile
+    cost: u64,
+    pixels: Vec<u16>,
+}
+
+/// Estimated work done so far, shared by the workers and the thread printing progress
+struct Progress {
+    done: AtomicU64,
+    total: u64,
+    started: Instant,
+}
+
+impl Progress {
+    fn print(&self) {
+        let done = self.done.load(Ordering::Relaxed);
+        let fraction = done as f64 / self.total as f64;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if done == 0 {
+            eprint!("\rRendering   0.0%, ETA unknown   ");
+        } else {
+            eprint!(
+                "\rRendering {:5.1}%, ETA {:.1} sec   ",
+                fraction * 100.0,
+                elapsed * (1.0 - fraction) / fraction
+            );
+        }
+    }
+}
+
+/// Renders the image in square tiles handed out to the threads as they become free,
+/// the most expensive tiles first, so no thread is left with a slow tile at the end.
+/// Rows inside the set cost far more than the others, equal bands of rows balance badly.
+/// With progress set, the share of the estimated work done and the ETA are shown on stderr.
+pub fn render_multi_thread_tiles(pixels: &mut [u16], options: &Options, progress: bool) {
+    render_rows_in_tiles(pixels, 0..options.bounds.1, options, progress);
+}
+
+/// render_multi_thread_tiles for some of the rows of the image, pixels has just those rows
+pub fn render_rows_in_tiles(
+    pixels: &mut [u16],
+    rows: Range<usize>,
+    options: &Options,
+    progress: bool,
+) {
+    assert!(pixels.len() == options.bounds.0 * rows.len() * CHANNELS);
+
+    let mut tiles = split_into_tiles(options, rows.clone());
+    tiles.sort_by_key(|tile| Reverse(tile.cost));
+
+    let progress = progress.then(|| Progress {
+        done: AtomicU64::new(0),
+        total: tiles.iter().map(|tile| tile.cost).sum(),
+        started: Instant::now(),
+    });
+    let (finished, finished_receiver) = channel::bounded::<()>(0);
+
+    crossbeam::scope(|thread_spawner| {
+        if let Some(progress) = &progress {
+            thread_spawner.spawn(move |_| loop {
+                // Dropping the sender wakes the thread up as soon as the render is done
+                match finished_receiver.recv_timeout(PROGRESS_INTERVAL) {
+                    Err(RecvTimeoutError::Timeout) => progress.print(),
+                    _ => {
+                        eprint!("\r{:40}\r", "");
+                        break;
+                    }
+                }
+            });
+        }
+
+        // par_bridge hands the tiles out in order as the threads ask for work
+        tiles.iter_mut().par_bridge().for_each(|tile| {
+            render_tile(tile, options);
+            if let Some(progress) = &progress {
+                progress.done.fetch_add(tile.cost, Ordering::Relaxed);
+            }
+        });
+        drop(finished);
+    })
+    .unwrap();
+
+    // Tiles are not contiguous in the image, so each one is rendered
+    // into its own buffer and copied in afterwards
+    let width = options.bounds.0;
+    for tile in tiles {
+        for (row, line) in tile.pixels.chunks(tile.bounds.0 * CHANNELS).enumerate() {
+            let start = ((tile.top - rows.start + row) * width + tile.left) * CHANNELS;
+            pixels[start..start + line.len()].copy_from_slice(line);
+        }
+    }
+}
+
+/// Tiles are made smaller until every thread gets several of them
+fn tile_size(bounds: (usize, usize), threads: usize) -> usize {
+    let mut size = TILE_SIZE;
+    while size > MIN_TILE_SIZE
+        && bounds.0.div_ceil(size) * bounds.1.div_ceil(size) < threads * TILES_PER_THREAD
+    {
+        size /= 2;
+    }
+    size
+}
+
+fn split_into_tiles(options: &Options, rows: Range<usize>) -> Vec<Tile> {
+    let bounds = options.bounds;
+    let size = tile_size((bounds.0, rows.len()), options.threads);
+
+    let mut tiles = Vec::new();
+    for top in rows.clone().step_by(size) {
+        for left in (0..bounds.0).step_by(size) {
+            let mut tile = Tile {
+                left,
+                top,
+                bounds: (size.min(bounds.0 - left), size.min(rows.end - top)),
+                cost: 0,
+                pixels: Vec::new(),
+            };
+            tile.cost = estimate_cost(&tile, options);
+            tiles.push(tile);
+        }
+    }
+    tiles
+}
+
+/// Iterations of a grid of dots of the tile scaled up to the whole tile,
+/// dots in the set cost the whole iteration limit
+fn estimate_cost(tile: &Tile, options: &Options) -> u64 {
+    let mut iterations = 0;
+    for row in 0..PROBES {
+        for col in 0..PROBES {
+            let pixel = (
+                tile.left + col * (tile.bounds.0 - 1) / (PROBES - 1),
+                tile.top + row * (tile.bounds.1 - 1) / (PROBES - 1),
+            );
+            let dot = rotate_dot(dot_of(pixel, options), options);
+            let escape = match &options.deep {
+                Some(deep) => deep.escape_time(dot, options.max_iter),
+                None => options.fractal.escape_time(dot, options.max_iter, false),
+            };
+            // Escaping dots still cost a bit to color
+            iterations += escape.unwrap_or(options.max_iter) as u64 + 1;
+        }
+    }
+
+    let pixels = (tile.bounds.0 * tile.bounds.1) as u64;
+    iterations * pixels / (PROBES * PROBES) as u64
+}
+
+/// Dots are computed from the whole image bounds, as in the single thread render,
+/// tile corners would give slightly different dots on the tile edges
+fn dot_of(pixel: (usize, usize), options: &Options) -> Complex<f64> {
+    convert_pixel_to_dot(
+        options.bounds,
+        pixel,
+        options.upper_left,
+        options.lower_right,
+    )
+}
+
+fn render_tile(tile: &mut Tile, options: &Options) {
+    tile.pixels = vec![0; tile.bounds.0 * tile.bounds.1 * CHANNELS];
+    if options.fill {
+        let tile_dot = |col, row| dot_of((tile.left + col, tile.top + row), options);
+        interior::render_filled(&mut tile.pixels, tile.bounds, tile_dot, options);
+        return;
+    }
+    if options.supersampling != Supersampling::Off {
+        let tile_dot = |col: f64, row: f64| {
+            convert_subpixel_to_dot(
+                options.bounds,
+                (tile.left as f64 + col, tile.top as f64 + row),
+                options.upper_left,
+                options.lower_right,
+            )
+        };
+        supersample::render_supersampled(&mut tile.pixels, tile.bounds, tile_dot, options);
+        return;
+    }
+
+    for (row, line) in tile.pixels.chunks_mut(tile.bounds.0 * CHANNELS).enumerate() {
+        let dots: Vec<Complex<f64>> = (0..tile.bounds.0)
+            .map(|col| dot_of((tile.left + col, tile.top + row), options))
+            .collect();
+        render_dots(line, &dots, options);
+    }
+}
+
+#[test]
+fn test_tile_size() {
+    assert_eq!(tile_size((4000, 3000), 16), TILE_SIZE);
+    assert_eq!(tile_size((200, 200), 4), 32);
+    assert_eq!(tile_size((10, 10), 64), MIN_TILE_SIZE);
+}
+
+#[test]
+fn test_split_into_tiles() {
+    let options = crate::cli::parse_args(
+        "mandel.png 150x100 -2.2,1.2 0.8,-1.2 --threads 2"
+            .split_whitespace()
+            .map(String::from),
+    )
+    .unwrap();
+    let tiles = split_into_tiles(&options, 0..100);
+
+    // Every pixel is in exactly one tile
+    let mut covered = vec![0; 150 * 100];
+    for tile in &tiles {
+        for row in tile.top..tile.top + tile.bounds.1 {
+            for col in tile.left..tile.left + tile.bounds.0 {
+                covered[row * 150 + col] += 1;
+            }
+        }
+    }
+    assert!(covered.iter().all(|&count| count == 1));
+
+    // Tiles over the set are the expensive ones
+    let cost_at = |pixel: (usize, usize)| {
+        tiles
+            .iter()
+            .find(|tile| {
+                (tile.left..tile.left + tile.bounds.0).contains(&pixel.0)
+                    && (tile.top..tile.top + tile.bounds.1).contains(&pixel.1)
+            })
+            .unwrap()
+            .cost
+    };
+    assert!(cost_at((85, 50)) > 10 * cost_at((5, 5)));
+}
@@ -0,0 +1,447 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_std::prelude::*;
+use async_std::{io, net, task};
+use hdrhistogram::Histogram;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use web_chat::{ClientPacket, ServerPacket, utils};
+use web_chat::utils::AppResult;
+
+/// cargo run --release --bin server -- 127.0.0.1:8088 --queue-capacity 1000
+/// cargo run --release --bin chat-bench -- 127.0.0.1:8088 --clients 2000 --groups 50 --rate 5000
+///
+/// Thousands of clients need as many open sockets, raise the limit first:
+/// ulimit -n 65536
+const USAGE: &str = "\
+Usage: chat-bench <SERVER ADDRESS>:<PORT> [options]
+  --clients <n>               simulated clients, default 1000
+  --groups <n>                groups the clients spread over, default 10
+  --groups-per-client <n>     groups every client joins, default 1
+  --distribution <d>          uniform or zipf:<exponent>, how popular groups are, default uniform
+  --rate <n>                  messages per second sent by all clients together, default 1000
+  --duration <seconds>        how long to send, default 10
+  --message-size <bytes>      payload size of every message, default 64
+  --seed <n>                  random seed for group choice, default 0";
+
+/// Benchmark messages start with it, everything else the server sends is ignored
+const MESSAGE_PREFIX: &str = "bench";
+
+/// Time given to the in-flight messages to arrive after sending stops
+const DRAIN_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Debug, PartialEq)]
+struct Options
+{
+    address: String,
+    clients: usize,
+    groups: usize,
+    groups_per_client: usize,
+    distribution: Distribution,
+    rate: f64,
+    duration: Duration,
+    message_size: usize,
+    seed: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum Distribution
+{
+    Uniform,
+    Zipf(f64),      // group i is joined with weight 1 / (i + 1)^exponent
+}
+
+/// What a single client observed
+struct Report
+{
+    sent: u64,
+    delivered: u64,
+    lag_dropped: u64,
+    errors: u64,
+    latency: Histogram<u64>,    // microseconds
+}
+
+fn main() -> AppResult<()>
+{
+    let options = match parse_options(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    task::block_on(run(options))
+}
+
+async fn run(options: Options) -> AppResult<()>
+{
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let weights = group_weights(&options.distribution, options.groups);
+    let payload = Arc::new("x".repeat(options.message_size));
+
+    // Every client sends once per interval, together they make up the target rate
+    let interval = Duration::from_secs_f64(options.clients as f64 / options.rate);
+    let start = Instant::now();
+
+    println!("connecting {} clients to {}", options.clients, options.address);
+
+    let mut clients = Vec::with_capacity(options.clients);
+    for _ in 0..options.clients {
+        let groups = pick_groups(&mut rng, &weights, options.groups_per_client);
+
+        // Connecting one by one, thousands of simultaneous connects overflow the listen backlog
+        let stream = net::TcpStream::connect(&options.address).await?;
+        stream.set_nodelay(true)?;
+
+        let offset = interval.mul_f64(rng.gen::<f64>());
+        clients.push((stream, groups, offset));
+    }
+
+    // Joins are done before sending, otherwise early messages would have fewer receivers
+    for (stream, groups, _) in clients.iter_mut() {
+        for group in groups.iter() {
+            utils::send_packet(stream, &ClientPacket::Join { group: group.clone() }).await?;
+        }
+        stream.flush().await?;
+    }
+
+    println!("sending {} messages per second for {:?}", options.rate, options.duration);
+
+    let send_start = Instant::now();
+    let send_end = send_start + options.duration;
+    let tasks: Vec<_> = clients
+        .into_iter()
+        .map(|(stream, groups, offset)| {
+            let payload = payload.clone();
+            task::spawn(simulate_client(stream, groups, start, send_start + offset, send_end, interval, payload))
+        })
+        .collect();
+
+    let mut total = Report::new();
+    for client in tasks {
+        total.merge(client.await?)?;
+    }
+
+    print_report(&total, options.duration);
+    Ok(())
+}
+
+async fn simulate_client(
+    stream: net::TcpStream,
+    groups: Vec<Arc<String>>,
+    start: Instant,
+    first_send: Instant,
+    send_end: Instant,
+    interval: Duration,
+    payload: Arc<String>,
+) -> AppResult<Report>
+{
+    let receiver = task::spawn(receive_messages(stream.clone(), start));
+
+    let mut sent = 0;
+    let mut writer = stream.clone();
+    let mut next_send = first_send;
+
+    while next_send < send_end {
+        let now = Instant::now();
+        if next_send > now {
+            task::sleep(next_send - now).await;
+        }
+
+        let group = groups[sent as usize % groups.len()].clone();
+        let sent_at = start.elapsed().as_micros();
+        let message = Arc::new(format!("{} {} {}", MESSAGE_PREFIX, sent_at, payload));
+
+        utils::send_packet(&mut writer, &ClientPacket::Send { group, message, reply_to: None }).await?;
+        writer.flush().await?;
+        sent += 1;
+
+        // Fixed schedule instead of sleeping the interval keeps the rate when the server is slow
+        next_send += interval;
+    }
+
+    task::sleep(send_end.saturating_duration_since(Instant::now()) + DRAIN_TIME).await;
+
+    // Closing the socket ends the receiver loop
+    stream.shutdown(std::net::Shutdown::Both)?;
+    let mut report = receiver.await?;
+    report.sent = sent;
+    Ok(report)
+}
+
+async fn receive_messages(stream: net::TcpStream, start: Instant) -> AppResult<Report>
+{
+    let mut report = Report::new();
+    let mut packets = utils::receive_packet(io::BufReader::new(stream));
+
+    while let Some(packet) = packets.next().await {
+        // Shutdown during a read is the normal way to stop
+        let packet = match packet {
+            Ok(packet) => packet,
+            Err(_) => break,
+        };
+
+        match packet {
+            ServerPacket::Message { message, .. } => {
+                // Timestamps from the future come from other runs or other users, they are not ours
+                let latency = parse_sent_at(&message)
+                    .and_then(|sent_at| (start.elapsed().as_micros() as u64).checked_sub(sent_at));
+                if let Some(latency) = latency {
+                    report.latency.saturating_record(latency);
+                    report.delivered += 1;
+                }
+            }
+            ServerPacket::Error(message) => match parse_dropped(&message) {
+                Some(dropped) => report.lag_dropped += dropped,
+                None => report.errors += 1,
+            },
+            _ => {}
+        }
+    }
+
+    Ok(report)
+}
+
+impl Report
+{
+    fn new() -> Report
+    {
+        Report {
+            sent: 0,
+            delivered: 0,
+            lag_dropped: 0,
+            errors: 0,
+            // Anything slower than a minute is recorded as a minute
+            latency: Histogram::new_with_bounds(1, 60_000_000, 3).expect("bounds are valid"),
+        }
+    }
+
+    fn merge(&mut self, other: Report) -> AppResult<()>
+    {
+        self.sent += other.sent;
+        self.delivered += other.delivered;
+        self.lag_dropped += other.lag_dropped;
+        self.errors += other.errors;
+        self.latency.add(&other.latency)?;
+        Ok(())
+    }
+}
+
+fn print_report(report: &Report, duration: Duration)
+{
+    let seconds = duration.as_secs_f64();
+    let latency = &report.latency;
+
+    println!("# Results");
+    println!("sent:         {} messages, {:.0}/s", report.sent, report.sent as f64 / seconds);
+    println!("delivered:    {} messages, {:.0}/s", report.delivered, report.delivered as f64 / seconds);
+    println!("lag dropped:  {} messages", report.lag_dropped);
+    println!("errors:       {}", report.errors);
+    println!();
+    println!("# Delivery latency");
+    for quantile in [0.5, 0.9, 0.99, 0.999] {
+        println!("p{:<11} {:.3} ms", quantile * 100.0, latency.value_at_quantile(quantile) as f64 / 1000.0);
+    }
+    println!("max          {:.3} ms", latency.max() as f64 / 1000.0);
+    println!();
+    println!("# Latency histogram");
+    for bucket in latency.iter_log(1000, 2.0) {
+        if bucket.count_since_last_iteration() == 0 {
+            continue;
+        }
+        println!(
+            "<= {:>10.3} ms {:>10}",
+            bucket.value_iterated_to() as f64 / 1000.0,
+            bucket.count_since_last_iteration());
+    }
+}
+
+fn parse_sent_at(message: &str) -> Option<u64>
+{
+    let mut tokens = message.splitn(3, ' ');
+    if tokens.next()? != MESSAGE_PREFIX {
+        return None;
+    }
+    tokens.next()?.parse().ok()
+}
+
+/// Server reports lagging subscribers as "Dropped N messages from group"
+fn parse_dropped(message: &str) -> Option<u64>
+{
+    message.strip_prefix("Dropped ")?.split(' ').next()?.parse().ok()
+}
+
+fn group_weights(distribution: &Distribution, groups: usize) -> Vec<f64>
+{
+    (0..groups)
+        .map(|i| match distribution {
+            Distribution::Uniform => 1.0,
+            Distribution::Zipf(exponent) => 1.0 / ((i + 1) as f64).powf(*exponent),
+        })
+        .collect()
+}
+
+/// Picks distinct groups according to their weights
+fn pick_groups(rng: &mut impl Rng, weights: &[f64], count: usize) -> Vec<Arc<String>>
+{
+    let mut weights = weights.to_vec();
+    let mut picked = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let total: f64 = weights.iter().sum();
+        let mut target = rng.gen::<f64>() * total;
+
+        let index = weights
+            .iter()
+            .position(|weight| {
+                target -= weight;
+                target < 0.0
+            })
+            // Rounding can leave a bit of the target, the last group not picked yet takes it
+            .or_else(|| weights.iter().rposition(|&weight| weight > 0.0))
+            .unwrap_or(weights.len() - 1);
+
+        weights[index] = 0.0;
+        picked.push(Arc::new(format!("bench-{}", index)));
+    }
+
+    picked
+}
+
+fn parse_options<I>(args: I) -> AppResult<Options>
+where
+    I: IntoIterator<Item = String>
+{
+    let mut options = Options {
+        address: String::new(),
+        clients: 1000,
+        groups: 10,
+        groups_per_client: 1,
+        distribution: Distribution::Uniform,
+        rate: 1000.0,
+        duration: Duration::from_secs(10),
+        message_size: 64,
+        seed: 0,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.address = arg;
+            continue;
+        }
+
+        let value = args.next().ok_or_else(|| format!("missing value for {}", arg))?;
+        let invalid = |e: &dyn std::fmt::Display| format!("invalid value '{}' for {}: {}", value, arg, e);
+
+        match arg.as_str() {
+            "--clients" => options.clients = value.parse().map_err(|e| invalid(&e))?,
+            "--groups" => options.groups = value.parse().map_err(|e| invalid(&e))?,
+            "--groups-per-client" => options.groups_per_client = value.parse().map_err(|e| invalid(&e))?,
+            "--distribution" => options.distribution = parse_distribution(&value).map_err(|e| invalid(&e))?,
+            "--rate" => options.rate = value.parse().map_err(|e| invalid(&e))?,
+            "--duration" => options.duration = Duration::try_from_secs_f64(value.parse().map_err(|e| invalid(&e))?).map_err(|e| invalid(&e))?,
+            "--message-size" => options.message_size = value.parse().map_err(|e| invalid(&e))?,
+            "--seed" => options.seed = value.parse().map_err(|e| invalid(&e))?,
+            _ => return Err(format!("unknown flag {}", arg).into()),
+        }
+    }
+
+    if options.address.is_empty() {
+        return Err("server address is missing".into());
+    }
+    if options.clients == 0 || options.groups == 0 || options.groups_per_client == 0 {
+        return Err("clients, groups and groups per client must be greater than zero".into());
+    }
+    if options.groups_per_client > options.groups {
+        return Err("a client can't join more groups than there are".into());
+    }
+    if !options.rate.is_finite() || options.rate <= 0.0 {
+        return Err("rate must be a number greater than zero".into());
+    }
+    if options.duration.is_zero() {
+        return Err("duration must be greater than zero".into());
+    }
+
+    // Clients would send without pause on a zero interval
+    match Duration::try_from_secs_f64(options.clients as f64 / options.rate) {
+        Ok(interval) if !interval.is_zero() => {}
+        _ => return Err(format!("rate {} is out of range for {} clients", options.rate, options.clients).into()),
+    }
+
+    Ok(options)
+}
+
+fn parse_distribution(text: &str) -> Result<Distribution, String>
+{
+    if text == "uniform" {
+        return Ok(Distribution::Uniform);
+    }
+
+    match text.strip_prefix("zipf:").map(str::parse::<f64>) {
+        Some(Ok(exponent)) if exponent >= 0.0 => Ok(Distribution::Zipf(exponent)),
+        _ => Err("expected uniform or zipf:<exponent>".to_string()),
+    }
+}
+
+#[test]
+fn test_parse_options()
+{
+    let args = |line: &str| line.split_whitespace().map(String::from).collect::<Vec<_>>();
+
+    let options = parse_options(args("127.0.0.1:8088 --clients 5 --groups 3 --groups-per-client 2 --distribution zipf:1.5 --rate 20.5")).unwrap();
+    assert_eq!("127.0.0.1:8088", options.address);
+    assert_eq!(5, options.clients);
+    assert_eq!(2, options.groups_per_client);
+    assert_eq!(Distribution::Zipf(1.5), options.distribution);
+    assert_eq!(20.5, options.rate);
+
+    assert!(parse_options(args("--clients 5")).is_err());
+    assert!(parse_options(args("a:1 --groups 2 --groups-per-client 3")).is_err());
+    assert!(parse_options(args("a:1 --distribution normal")).is_err());
+    assert!(parse_options(args("a:1 --rate 0")).is_err());
+    assert!(parse_options(args("a:1 --rate inf")).is_err());
+    assert!(parse_options(args("a:1 --rate NaN")).is_err());
+    assert!(parse_options(args("a:1 --rate 1e300")).is_err());
+    assert!(parse_options(args("a:1 --rate 1e-300")).is_err());
+    assert!(parse_options(args("a:1 --duration -1")).is_err());
+    assert!(parse_options(args("a:1 --duration NaN")).is_err());
+    assert!(parse_options(args("a:1 --duration 0")).is_err());
+    assert_eq!(Duration::from_millis(1500), parse_options(args("a:1 --duration 1.5")).unwrap().duration);
+}
+
+#[test]
+fn test_pick_groups()
+{
+    let mut rng = StdRng::seed_from_u64(1);
+
+    // Groups of a client are distinct
+    let weights = group_weights(&Distribution::Uniform, 3);
+    let mut groups = pick_groups(&mut rng, &weights, 3);
+    groups.sort();
+    assert_eq!(vec!["bench-0", "bench-1", "bench-2"], groups.iter().map(|g| g.as_str()).collect::<Vec<_>>());
+
+    // Zipf prefers the first groups
+    let weights = group_weights(&Distribution::Zipf(2.0), 100);
+    let first = (0..1000)
+        .filter(|_| pick_groups(&mut rng, &weights, 1)[0].as_str() == "bench-0")
+        .count();
+    assert!(first > 500, "first group was picked {} times", first);
+
+    // Draws at the very end of the range stay distinct when rounding leaves some of the target
+    let mut rng = rand::rngs::mock::StepRng::new(u64::MAX, 0);
+    let mut groups = pick_groups(&mut rng, &[0.3, 0.7, 0.7, 0.9], 4);
+    groups.sort();
+    assert_eq!(vec!["bench-0", "bench-1", "bench-2", "bench-3"], groups.iter().map(|g| g.as_str()).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_parse_server_messages()
+{
+    assert_eq!(Some(1234), parse_sent_at("bench 1234 xxxx"));
+    assert_eq!(None, parse_sent_at("hello 1234 xxxx"));
+    assert_eq!(Some(17), parse_dropped("Dropped 17 messages from bench-3"));
+    assert_eq!(None, parse_dropped("Can't send message"));
+}
@@ -1,23 +1,38 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use async_std::prelude::*;
 use async_std::{io, net};
-use web_chat::{ClientPacket, ServerPacket, utils};
+use web_chat::{ClientPacket, Member, SealedKey, ServerPacket, ThreadMessage, e2e, utils};
 use web_chat::utils::{AppResult};
 
+// Both tasks write to the server: the user input and the key rotation replies
+type ServerWriter = Arc<async_std::sync::Mutex<net::TcpStream>>;
+
 fn main() -> AppResult<()>
 {
-    let address = std::env::args().nth(1).expect("Usage: client.exe <SERVER ADDRESS>:<PORT>");
+    let address = std::env::args().nth(1).expect("Usage: client.exe <SERVER ADDRESS>:<PORT> [<USER NAME>]");
+
+    // Server remembers named users and delivers what they missed while disconnected
+    let user = std::env::args().nth(2);
 
     async_std::task::block_on(async {
         let server_stream = net::TcpStream::connect(address).await?;
         server_stream.set_nodelay(true)?;
 
+        let writer = Arc::new(async_std::sync::Mutex::new(server_stream.clone()));
+        let keys = Arc::new(Mutex::new(KeyRing::new()));
+
+        if let Some(user) = user {
+            let token = Arc::new(load_token(&user)?);
+            write_packet(&writer, &ClientPacket::Hello { user: Arc::new(user), token }).await?;
+        }
+
         // These two tasks are running in parrallel forever
         // Messages to server can be terminated if user closes stdio via Ctrl+Z (end-of-file indicator)
         // Messages from server can be terminated if server closes the connection.
-        let messages_to_server = send_packet(server_stream.clone());
-        let messages_from_server = receive_packet(server_stream);
+        let messages_to_server = send_packet(writer.clone(), keys.clone());
+        let messages_from_server = receive_packet(server_stream, writer, keys);
 
         // If we used `messages_to_server.await?; messages_from_server.await?;' that would mean
         // that client.exe exists when both tasks are terminated. But we rather want to terminate
@@ -27,15 +42,46 @@ fn main() -> AppResult<()>
     })
 }
 
+/// Token of the user is kept in <USER NAME>.token in the current directory,
+/// the first connection of the user creates it
+fn load_token(user: &str) -> AppResult<String>
+{
+    let path = format!("{}.token", user);
+    match std::fs::read_to_string(&path) {
+        Ok(token) => Ok(token.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let token = e2e::generate_token();
+            std::fs::write(&path, &token).map_err(|e| format!("can't save token to '{}': {}", path, e))?;
+            Ok(token)
+        }
+        Err(e) => Err(format!("can't read token from '{}': {}", path, e).into()),
+    }
+}
+
+async fn write_packet(server: &ServerWriter, packet: &ClientPacket) -> AppResult<()>
+{
+    let mut guarded_stream = server.lock().await;
+    utils::send_packet(&mut *guarded_stream, packet).await?;
+    guarded_stream.flush().await?;
+    Ok(())
+}
+
 // was send_commands
 // to test this we'll need to depend on trait instead
 // and test would pass in mock struct that implements the same trait
-async fn send_packet(mut server: net::TcpStream) -> AppResult<()>
+async fn send_packet(server: ServerWriter, keys: Arc<Mutex<KeyRing>>) -> AppResult<()>
 {
     println!(
         "# Awailable commands\n\
         - J group_name - join chat group with that name\n\
+        - E group_name - join end-to-end encrypted chat group with that name\n\
         - S group_name message_text - send chat group with that name the message\n\
+        - A group_name seq message_text - answer message seq of that group, replies form threads\n\
+        - F group_name seq - fetch the whole thread started by message seq of that group\n\
+        - T group_name - tell chat group with that name that you are typing\n\
+        - R group_name seq - mark messages of that group up to seq as read\n\
+        - W group_name seq - ask who has read messages of that group up to seq\n\
+        - D user_name message_text - send the user a direct message, it gets it even if it is offline\n\
         - Ctrl+Z - close connection and exit the client app");
 
     let mut input = io::BufReader::new(io::stdin()).lines();
@@ -43,40 +89,316 @@ async fn send_packet(mut server: net::TcpStream) -> AppResult<()>
     while let Some(line_read) = input.next().await {
         let line = line_read?;
 
-        let packet = match command_to_packet(&line) {
+        // Lock is released before the await, std mutex can't be held across it
+        let packet = match command_to_packet(&line, &mut keys.lock().unwrap()) {
             Some(packet) => packet,
             None => continue,
         };
 
-        utils::send_packet(&mut server, &packet).await?;
-        server.flush().await?;
+        write_packet(&server, &packet).await?;
     }
 
     Ok(())
 }
 
 // was: handle_replies
-async fn receive_packet(server: net::TcpStream) -> AppResult<()>
+async fn receive_packet(server: net::TcpStream, writer: ServerWriter, keys: Arc<Mutex<KeyRing>>) -> AppResult<()>
 {
     let reader = io::BufReader::new(server);
     let mut stream = utils::receive_packet(reader);
 
+    // How deep in its thread every seen message is, roots are 0
+    let mut depths: HashMap<(Arc<String>, u64), usize> = HashMap::new();
+
     while let Some(packet) = stream.next().await {
         match packet? {
-            ServerPacket::Message{ group, message } => {
-                println!("{}: {}", group, message);
+            ServerPacket::Message{ group, seq, reply_to, message, .. } => {
+                let depth = match reply_to {
+                    Some(parent) => depths.get(&(group.clone(), parent)).map_or(1, |depth| depth + 1),
+                    None => 0,
+                };
+                println!("{}{}", indent(depth), format_message(&group, seq, reply_to, &message));
+                depths.insert((group.clone(), seq), depth);
+
+                // Shown is read, the server would deliver it again on reconnect otherwise
+                write_packet(&writer, &ClientPacket::Read { group, upto_seq: seq }).await?;
+            }
+            ServerPacket::Missed { group, skipped } => {
+                println!("# {} older messages in {} were posted while you were away and are skipped", skipped, group);
+            }
+            ServerPacket::Direct { from, seq, message } => {
+                println!("{} (direct): {}", from, message);
+                write_packet(&writer, &ClientPacket::ReadDirect { upto_seq: seq }).await?;
+            }
+            ServerPacket::MissedDirect { skipped } => {
+                println!("# {} older direct messages were sent while you were away and are skipped", skipped);
+            }
+            ServerPacket::Thread { group, messages, .. } => {
+                for line in thread_lines(&group, &messages) {
+                    println!("{}", line);
+                }
             }
             ServerPacket::Error(message) => {
                 eprintln!("error: server replied with error message: {}", message)
             }
+            ServerPacket::Members { group, epoch, members } => {
+                println!("# {} now has {} members, rotating key", group, members.len());
+                let share = keys.lock().unwrap().rotate(&group, epoch, &members);
+                if let Some(packet) = share {
+                    write_packet(&writer, &packet).await?;
+                }
+            }
+            ServerPacket::GroupKey { group, epoch, from_public_key, key } => {
+                if let Err(message) = keys.lock().unwrap().accept(&group, epoch, &from_public_key, &key) {
+                    eprintln!("error: can't use key for {}: {}", group, message);
+                }
+            }
+            ServerPacket::Encrypted { group, seq, epoch, ciphertext } => {
+                match keys.lock().unwrap().decrypt(&group, epoch, &ciphertext) {
+                    Ok(message) => println!("{} #{}: {}", group, seq, message),
+                    Err(message) => eprintln!("error: can't decrypt message in {}: {}", group, message),
+                }
+            }
+            ServerPacket::Typing { group, member } => {
+                println!("# member {} is typing in {}", member, group);
+            }
+            ServerPacket::ReadBy { group, seq, members } => {
+                println!("# {} #{} was read by {} members: {:?}", group, seq, members.len(), members);
+            }
         }
     }
 
     Ok(())
 }
 
+fn indent(depth: usize) -> String
+{
+    "    ".repeat(depth)
+}
+
+fn format_message(group: &str, seq: u64, reply_to: Option<u64>, message: &str) -> String
+{
+    match reply_to {
+        Some(parent) => format!("{} #{} re #{}: {}", group, seq, parent, message),
+        None => format!("{} #{}: {}", group, seq, message),
+    }
+}
+
+/// Prints thread as a tree, every reply goes right under its parent one level deeper
+fn thread_lines(group: &str, messages: &[ThreadMessage]) -> Vec<String>
+{
+    let mut children: HashMap<Option<u64>, Vec<&ThreadMessage>> = HashMap::new();
+    for message in messages {
+        // Root's parent is None, replies to messages missing from the thread are shown as roots too
+        let parent = message.reply_to.filter(|parent| messages.iter().any(|m| m.seq == *parent));
+        children.entry(parent).or_default().push(message);
+    }
+
+    let mut lines = Vec::with_capacity(messages.len());
+    let mut stack: Vec<(&ThreadMessage, usize)> = children
+        .get(&None)
+        .map(|roots| roots.iter().rev().map(|root| (*root, 0)).collect())
+        .unwrap_or_default();
+
+    while let Some((message, depth)) = stack.pop() {
+        lines.push(format!("{}{}", indent(depth), format_message(group, message.seq, message.reply_to, &message.message)));
+
+        // Reversed so that the earliest reply is printed first
+        if let Some(replies) = children.get(&Some(message.seq)) {
+            stack.extend(replies.iter().rev().map(|reply| (*reply, depth + 1)));
+        }
+    }
+
+    lines
+}
+
+#[test]
+fn test_thread_lines()
+{
+    let message = |seq, reply_to, text: &str| ThreadMessage { seq, reply_to, message: Arc::new(text.to_string()) };
+    let messages = vec![
+        message(1, None, "cats?"),
+        message(2, Some(1), "yes"),
+        message(4, Some(1), "no"),
+        message(5, Some(2), "why yes"),
+    ];
+
+    assert_eq!(
+        vec![
+            "cats #1: cats?",
+            "    cats #2 re #1: yes",
+            "        cats #5 re #2: why yes",
+            "    cats #4 re #1: no",
+        ],
+        thread_lines("cats", &messages));
+}
+
+/// How many previous group keys are kept for messages that were in flight during rotation
+const KEPT_EPOCHS: u64 = 4;
+
+/// Client side key management for end-to-end encrypted groups.
+/// Secrets never leave this struct, the server only sees the public key and sealed data.
+struct KeyRing
+{
+    secret: e2e::MemberSecret,
+    public_key: Arc<String>,
+    groups: HashMap<Arc<String>, GroupKeys>,
+}
+
+#[derive(Default)]
+struct GroupKeys
+{
+    epoch: u64,
+    keys: HashMap<u64, e2e::GroupKey>,
+    leaders: HashMap<u64, Arc<String>>,     // epoch -> public key of the member that shares its key
+}
+
+impl KeyRing
+{
+    fn new() -> KeyRing
+    {
+        let secret = e2e::generate_member_secret();
+        let public_key = Arc::new(e2e::public_key_string(&secret));
+        KeyRing { secret, public_key, groups: HashMap::new() }
+    }
+
+    /// The group is recorded once the server confirms the join, a rejected join leaves it plain
+    fn join(&self, group: Arc<String>) -> ClientPacket
+    {
+        ClientPacket::JoinEncrypted { group, public_key: self.public_key.clone() }
+    }
+
+    fn is_encrypted(&self, group: &String) -> bool
+    {
+        self.groups.contains_key(group)
+    }
+
+    fn encrypt(&self, group: Arc<String>, message: &str) -> AppResult<ClientPacket>
+    {
+        let group_keys = &self.groups[&group];
+        let key = group_keys.keys
+            .get(&group_keys.epoch)
+            .ok_or("group key was not received yet, try again in a moment")?;
+
+        Ok(ClientPacket::SendEncrypted {
+            group,
+            epoch: group_keys.epoch,
+            ciphertext: Arc::new(e2e::encrypt(key, message)?),
+        })
+    }
+
+    /// The member with the smallest id generates the key of a new epoch
+    /// and seals a copy for every member, itself included
+    fn rotate(&mut self, group: &Arc<String>, epoch: u64, members: &[Member]) -> Option<ClientPacket>
+    {
+        // Being listed among the members confirms the join
+        if !members.iter().any(|member| member.public_key == self.public_key) {
+            return None;
+        }
+        let group_keys = self.groups.entry(group.clone()).or_default();
+        group_keys.epoch = epoch;
+        group_keys.keys.retain(|&kept, _| kept + KEPT_EPOCHS > epoch);
+        group_keys.leaders.retain(|&kept, _| kept + KEPT_EPOCHS > epoch);
+
+        let leader = members.first()?.public_key.clone();
+        group_keys.leaders.insert(epoch, leader.clone());
+        if leader != self.public_key {
+            return None;
+        }
+
+        let key = e2e::generate_group_key();
+        let keys = members
+            .iter()
+            .filter_map(|member| {
+                let sealed = e2e::seal_group_key(&key, &self.secret, &member.public_key).ok()?;
+                Some(SealedKey { member: member.id, key: sealed })
+            })
+            .collect();
+
+        Some(ClientPacket::ShareKey { group: group.clone(), epoch, keys })
+    }
+
+    /// Only the first key of the epoch leader is taken, so other members can't
+    /// split the group onto different keys of the same epoch
+    fn accept(&mut self, group: &str, epoch: u64, from_public_key: &str, sealed: &str) -> AppResult<()>
+    {
+        let group_keys = self.groups
+            .get_mut(&Arc::new(group.to_owned()))
+            .ok_or("key for a group that was not joined")?;
+        if group_keys.leaders.get(&epoch).map(|leader| leader.as_str()) != Some(from_public_key) {
+            return Err(format!("key for epoch {} is not from its leader", epoch).into());
+        }
+
+        let key = e2e::open_group_key(sealed, from_public_key, &self.secret)?;
+        group_keys.keys.entry(epoch).or_insert(key);
+        Ok(())
+    }
+
+    fn decrypt(&self, group: &String, epoch: u64, ciphertext: &str) -> AppResult<String>
+    {
+        let key = self.groups
+            .get(group)
+            .and_then(|group_keys| group_keys.keys.get(&epoch))
+            .ok_or_else(|| format!("no key for epoch {}", epoch))?;
+
+        e2e::decrypt(key, ciphertext)
+    }
+}
+
+#[test]
+fn test_key_ring_rotation()
+{
+    let group = Arc::new("cats".to_string());
+    let mut leader = KeyRing::new();
+    let mut member = KeyRing::new();
+    leader.join(group.clone());
+    member.join(group.clone());
+
+    let members = vec![
+        Member { id: 1, public_key: leader.public_key.clone() },
+        Member { id: 2, public_key: member.public_key.clone() },
+    ];
+
+    // Groups stay plain until the server lists the client as a member
+    assert!(!member.is_encrypted(&group));
+    assert_eq!(None, member.rotate(&group, 1, &members[..1]));
+    assert!(!member.is_encrypted(&group));
+
+    // Only the first member shares the key
+    assert_eq!(None, member.rotate(&group, 1, &members));
+    let keys = match leader.rotate(&group, 1, &members) {
+        Some(ClientPacket::ShareKey { keys, .. }) => keys,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert_eq!(2, keys.len());
+
+    leader.accept(&group, 1, &leader.public_key.clone(), &keys[0].key).unwrap();
+
+    // Keys from anyone but the leader of the epoch are refused
+    let mut other = KeyRing::new();
+    let forged = match other.rotate(&group, 1, &[Member { id: 2, public_key: other.public_key.clone() }, members[1].clone()]) {
+        Some(ClientPacket::ShareKey { keys, .. }) => keys,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert!(member.accept(&group, 1, &other.public_key.clone(), &forged[1].key).is_err());
+
+    member.accept(&group, 1, &leader.public_key.clone(), &keys[1].key).unwrap();
+
+    let ciphertext = match member.encrypt(group.clone(), "myau").unwrap() {
+        ClientPacket::SendEncrypted { epoch, ciphertext, .. } => { assert_eq!(1, epoch); ciphertext }
+        other => panic!("unexpected {:?}", other),
+    };
+    assert_eq!("myau", leader.decrypt(&group, 1, &ciphertext).unwrap());
+
+    // Next epoch has no key until the leader shares it
+    member.rotate(&group, 2, &members[1..]);
+    assert!(member.encrypt(group.clone(), "myau").is_err());
+}
+
 // was: parse_command
-fn command_to_packet(line: &str) -> Option<ClientPacket>
+// Arms return early in the original style of this function
+#[allow(clippy::needless_return)]
+fn command_to_packet(line: &str, keys: &mut KeyRing) -> Option<ClientPacket>
 {
     let (token, leftover) = get_next_token(line)?;
 
@@ -92,13 +414,100 @@ fn command_to_packet(line: &str) -> Option<ClientPacket>
                 group: Arc::new(group.to_string()),
             });
         },
+        "E" => {
+            // Join end-to-end encrypted group
+            let (group, leftover) = get_next_token(leftover)?;
+            if !leftover.trim_start().is_empty() {
+                eprintln!("Error: Incorrect join command arguments. Should be 'E group_name'.");
+                return None;
+            }
+            Some(keys.join(Arc::new(group.to_string())))
+        },
         "S" => {
             // Send message to group
             let (group, message) = get_next_token(leftover)?;
-            return Some(ClientPacket::Send {
+            let group = Arc::new(group.to_string());
+            let message = message.trim_start();
+
+            if !keys.is_encrypted(&group) {
+                return Some(ClientPacket::Send { group, message: Arc::new(message.to_string()), reply_to: None });
+            }
+
+            match keys.encrypt(group, message) {
+                Ok(packet) => Some(packet),
+                Err(error) => {
+                    eprintln!("Error: Message was not sent: {}", error);
+                    None
+                }
+            }
+        },
+        "A" => {
+            // Answer a message of a group
+            let (group, leftover) = get_next_token(leftover)?;
+            let (parent, message) = get_next_token(leftover)?;
+            let parent = match parent.parse::<u64>() {
+                Ok(parent) => parent,
+                Err(_) => {
+                    eprintln!("Error: Incorrect answer command arguments. Should be 'A group_name seq message_text'.");
+                    return None;
+                }
+            };
+            let group = Arc::new(group.to_string());
+            if keys.is_encrypted(&group) {
+                eprintln!("Error: Replies are not supported in end-to-end encrypted groups.");
+                return None;
+            }
+            Some(ClientPacket::Send {
+                group,
+                message: Arc::new(message.trim_start().to_string()),
+                reply_to: Some(parent),
+            })
+        },
+        "F" => {
+            // Fetch thread
+            let (group, leftover) = get_next_token(leftover)?;
+            match leftover.trim().parse::<u64>() {
+                Ok(thread_root) => Some(ClientPacket::FetchThread { group: Arc::new(group.to_string()), thread_root }),
+                Err(_) => {
+                    eprintln!("Error: Incorrect fetch command arguments. Should be 'F group_name seq'.");
+                    None
+                }
+            }
+        },
+        "T" => {
+            // Typing in group
+            let (group, leftover) = get_next_token(leftover)?;
+            if !leftover.trim_start().is_empty() {
+                eprintln!("Error: Incorrect typing command arguments. Should be 'T group_name'.");
+                return None;
+            }
+            Some(ClientPacket::Typing {
                 group: Arc::new(group.to_string()),
+            })
+        },
+        "R" | "W" => {
+            // Read receipts
+            let (group, leftover) = get_next_token(leftover)?;
+            let seq = match leftover.trim().parse::<u64>() {
+                Ok(seq) => seq,
+                Err(_) => {
+                    eprintln!("Error: Incorrect read command arguments. Should be '{} group_name seq'.", token);
+                    return None;
+                }
+            };
+            let group = Arc::new(group.to_string());
+            match token {
+                "R" => Some(ClientPacket::Read { group, upto_seq: seq }),
+                _ => Some(ClientPacket::WhoRead { group, seq }),
+            }
+        },
+        "D" => {
+            // Direct message to a user
+            let (to, message) = get_next_token(leftover)?;
+            Some(ClientPacket::SendDirect {
+                to: Arc::new(to.to_string()),
                 message: Arc::new(message.trim_start().to_string()),
-            });
+            })
         },
         _ => {
             eprintln!("Error: Unrecognized command: {:?}", line);
@@ -110,33 +519,89 @@ fn command_to_packet(line: &str) -> Option<ClientPacket>
 #[test]
 fn test_command_to_packet()
 {
+    let mut keys = KeyRing::new();
+
     // Joins
-    let any_valid_join = command_to_packet("  J cats").unwrap();
+    let any_valid_join = command_to_packet("  J cats", &mut keys).unwrap();
     assert_eq!(ClientPacket::Join { group: Arc::new("cats".to_string()) }, any_valid_join);
 
-    let any_no_group_join = command_to_packet("J ");
+    let any_no_group_join = command_to_packet("J ", &mut keys);
     assert_eq!(None, any_no_group_join);
 
     // Sends
-    let any_valid_send = command_to_packet("S cats hello and myau!").unwrap();
+    let any_valid_send = command_to_packet("S cats hello and myau!", &mut keys).unwrap();
     let any_matching_send_packet = ClientPacket::Send {
         group: Arc::new("cats".to_string()),
         message: Arc::new("hello and myau!".to_string()),
+        reply_to: None,
     };
     assert_eq!(any_matching_send_packet, any_valid_send);
 
-    let any_no_message_send = command_to_packet("S cats ").unwrap();
+    // Replies and threads
+    let any_valid_answer = command_to_packet("A cats 3 me too", &mut keys).unwrap();
+    let any_matching_answer_packet = ClientPacket::Send {
+        group: Arc::new("cats".to_string()),
+        message: Arc::new("me too".to_string()),
+        reply_to: Some(3),
+    };
+    assert_eq!(any_matching_answer_packet, any_valid_answer);
+
+    let any_no_parent_answer = command_to_packet("A cats me too", &mut keys);
+    assert_eq!(None, any_no_parent_answer);
+
+    let any_valid_fetch = command_to_packet("F cats 3", &mut keys).unwrap();
+    assert_eq!(ClientPacket::FetchThread { group: Arc::new("cats".to_string()), thread_root: 3 }, any_valid_fetch);
+
+    let any_no_message_send = command_to_packet("S cats ", &mut keys).unwrap();
     let any_matching_send_packet = ClientPacket::Send {
         group: Arc::new("cats".to_string()),
         message: Arc::new("".to_string()),
+        reply_to: None,
     };
     assert_eq!(any_matching_send_packet, any_no_message_send);
 
-    let any_no_group_send = command_to_packet("S ");
+    let any_no_group_send = command_to_packet("S ", &mut keys);
     assert_eq!(None, any_no_group_send);
 
+    // Encrypted groups
+    let any_valid_encrypted_join = command_to_packet("E cats", &mut keys).unwrap();
+    let any_matching_encrypted_join = ClientPacket::JoinEncrypted {
+        group: Arc::new("cats".to_string()),
+        public_key: keys.public_key.clone(),
+    };
+    assert_eq!(any_matching_encrypted_join, any_valid_encrypted_join);
+
+    // Until the server confirms the join the group is plain, the server refuses plain text to encrypted groups
+    let any_send_before_join_confirmed = command_to_packet("S cats hello", &mut keys).unwrap();
+    assert!(matches!(any_send_before_join_confirmed, ClientPacket::Send { .. }));
+
+    let members = [Member { id: 1, public_key: keys.public_key.clone() }];
+    keys.rotate(&Arc::new("cats".to_string()), 1, &members);
+    let any_send_before_key_arrived = command_to_packet("S cats hello", &mut keys);
+    assert_eq!(None, any_send_before_key_arrived);
+
+    // Presence
+    let any_valid_typing = command_to_packet("T cats", &mut keys).unwrap();
+    assert_eq!(ClientPacket::Typing { group: Arc::new("cats".to_string()) }, any_valid_typing);
+
+    let any_valid_read = command_to_packet("R cats 42", &mut keys).unwrap();
+    assert_eq!(ClientPacket::Read { group: Arc::new("cats".to_string()), upto_seq: 42 }, any_valid_read);
+
+    let any_valid_who_read = command_to_packet("W cats 7", &mut keys).unwrap();
+    assert_eq!(ClientPacket::WhoRead { group: Arc::new("cats".to_string()), seq: 7 }, any_valid_who_read);
+
+    let any_no_seq_read = command_to_packet("R cats", &mut keys);
+    assert_eq!(None, any_no_seq_read);
+
+    // Direct messages
+    let any_valid_direct = command_to_packet("D tom hello tom", &mut keys).unwrap();
+    assert_eq!(ClientPacket::SendDirect { to: Arc::new("tom".to_string()), message: Arc::new("hello tom".to_string()) }, any_valid_direct);
+
+    let any_no_user_direct = command_to_packet("D ", &mut keys);
+    assert_eq!(None, any_no_user_direct);
+
     // Unknown commands
-    let any_unknown_command = command_to_packet("List database");
+    let any_unknown_command = command_to_packet("List database", &mut keys);
     assert_eq!(None, any_unknown_command);
 }
 
@@ -0,0 +1,332 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, str::FromStr};
+use web_chat::utils::AppResult;
+
+pub const USAGE: &str = "\
+Usage: server [<SERVER ADDRESS>:<PORT>] [options]
+  --config <file.toml>        read settings from a TOML file, flags override it
+  --bind <address:port>       address to listen on, can be repeated
+  --storage <file>            keep groups, memberships and history in that file across restarts
+  --queue-capacity <n>        messages buffered per group before slow clients lag
+  --history-size <n>          messages replayed to a client that joins a group
+  --offline-queue-limit <n>   messages per group delivered to a user that reconnects
+  --thread-history <n>        recent threads per group that can be fetched or replied to
+  --thread-replies <n>        recent replies kept per thread, older ones are forgotten
+  --max-message-length <n>    longest message accepted, in bytes
+  --max-groups <n>            how many groups can exist at the same time
+  --typing-interval-ms <n>    shortest time between relayed typing events of a member
+  --log-level <level>         error, warn, info or debug
+  --group <name>              group created at startup, can be repeated";
+
+/// Effective server settings.
+/// Defaults are overridden by the config file and then by the command line flags.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config
+{
+    pub bind: Vec<String>,
+    pub storage: Option<String>,
+    pub queue_capacity: usize,
+    pub history_size: usize,
+    pub offline_queue_limit: usize,
+    pub thread_history: usize,
+    pub thread_replies: usize,
+    pub log_level: LogLevel,
+    pub groups: Vec<String>,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits
+{
+    pub max_message_length: usize,
+    pub max_groups: usize,
+    pub typing_interval_ms: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, PartialOrd, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel
+{
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Default for Config
+{
+    fn default() -> Config
+    {
+        Config {
+            bind: Vec::new(),
+            storage: None,
+            queue_capacity: 1000, // was: MESSAGE_QUEUE_CAPACITY
+            history_size: 0,
+            offline_queue_limit: 100,
+            thread_history: 1000,
+            thread_replies: 100,
+            log_level: LogLevel::Info,
+            groups: Vec::new(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Default for Limits
+{
+    fn default() -> Limits
+    {
+        Limits {
+            max_message_length: 64 * 1024,
+            max_groups: 10_000,
+            typing_interval_ms: 1000,
+        }
+    }
+}
+
+impl FromStr for LogLevel
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogLevel, String>
+    {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("unknown log level '{}', expected error, warn, info or debug", s)),
+        }
+    }
+}
+
+impl fmt::Display for Config
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        // TOML is used so the printed config can be pasted back into a config file
+        let text = toml::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", text.trim_end())
+    }
+}
+
+impl Config
+{
+    /// Builds config from the command line arguments (without the program name)
+    pub fn from_args<I>(args: I) -> AppResult<Config>
+    where
+        I: IntoIterator<Item = String>
+    {
+        let args: Vec<String> = args.into_iter().collect();
+
+        // Config file is read first no matter where the flag is
+        // so that the rest of the flags could override its values
+        let mut config = match find_flag_value(&args, "--config")? {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+
+        // Bind addresses and groups given on the command line replace the file ones
+        let mut bind = Vec::new();
+        let mut groups = Vec::new();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                // was: the only accepted argument
+                bind.push(arg.clone());
+                continue;
+            }
+
+            let value = args.next().ok_or_else(|| format!("missing value for {}", arg))?;
+
+            match arg.as_str() {
+                "--config" => {}
+                "--bind" => bind.push(value.clone()),
+                "--storage" => config.storage = Some(value.clone()),
+                "--group" => groups.push(value.clone()),
+                "--queue-capacity" => config.queue_capacity = parse_flag(arg, value)?,
+                "--history-size" => config.history_size = parse_flag(arg, value)?,
+                "--offline-queue-limit" => config.offline_queue_limit = parse_flag(arg, value)?,
+                "--thread-history" => config.thread_history = parse_flag(arg, value)?,
+                "--thread-replies" => config.thread_replies = parse_flag(arg, value)?,
+                "--max-message-length" => config.limits.max_message_length = parse_flag(arg, value)?,
+                "--max-groups" => config.limits.max_groups = parse_flag(arg, value)?,
+                "--typing-interval-ms" => config.limits.typing_interval_ms = parse_flag(arg, value)?,
+                "--log-level" => config.log_level = parse_flag(arg, value)?,
+                _ => return Err(format!("unknown flag {}", arg).into()),
+            }
+        }
+
+        if !bind.is_empty() {
+            config.bind = bind;
+        }
+        if !groups.is_empty() {
+            config.groups = groups;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> AppResult<Config>
+    {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("can't read config file '{}': {}", path, e))?;
+        let config = toml::from_str(&text)
+            .map_err(|e| format!("invalid config file '{}': {}", path, e))?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> AppResult<()>
+    {
+        if self.bind.is_empty() {
+            return Err("no address to listen on, pass <SERVER ADDRESS>:<PORT> or --bind".into());
+        }
+
+        // Tokio broadcast channel panics on zero capacity
+        if self.queue_capacity == 0 {
+            return Err("queue_capacity must be greater than zero".into());
+        }
+
+        // Threads would be forgotten as soon as they start
+        if self.thread_history == 0 || self.thread_replies == 0 {
+            return Err("thread_history and thread_replies must be greater than zero".into());
+        }
+
+        if self.limits.max_message_length == 0 {
+            return Err("limits.max_message_length must be greater than zero".into());
+        }
+
+        if self.groups.len() > self.limits.max_groups {
+            return Err(format!(
+                "{} startup groups exceed limits.max_groups = {}",
+                self.groups.len(), self.limits.max_groups).into());
+        }
+
+        if let Some(group) = self.groups.iter().find(|group| group.trim().is_empty() || group.contains(char::is_whitespace)) {
+            return Err(format!("invalid group name '{}', it must be a single word", group).into());
+        }
+
+        // Names like --config would read as flags
+        if let Some(group) = self.groups.iter().find(|group| group.starts_with('-')) {
+            return Err(format!("invalid group name '{}', it must not start with '-'", group).into());
+        }
+
+        Ok(())
+    }
+
+    /// Should messages of that level be printed
+    pub fn logs(&self, level: LogLevel) -> bool
+    {
+        level <= self.log_level
+    }
+}
+
+fn find_flag_value<'a>(args: &'a [String], flag: &str) -> AppResult<Option<&'a str>>
+{
+    // Every flag takes a value, so the flag given as the value of another one is skipped with it
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            continue;
+        }
+        let value = args.next();
+        if arg == flag {
+            return match value {
+                Some(value) => Ok(Some(value)),
+                None => Err(format!("missing value for {}", flag).into()),
+            };
+        }
+    }
+    Ok(None)
+}
+
+fn parse_flag<T>(flag: &str, value: &str) -> AppResult<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display
+{
+    value
+        .parse::<T>()
+        .map_err(|e| format!("invalid value '{}' for {}: {}", value, flag, e).into())
+}
+
+#[cfg(test)]
+fn args(line: &str) -> Vec<String>
+{
+    line.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn test_config_from_args()
+{
+    // Old style positional address still works
+    let config = Config::from_args(args("127.0.0.1:8088")).unwrap();
+    assert_eq!(vec!["127.0.0.1:8088".to_string()], config.bind);
+    assert_eq!(1000, config.queue_capacity);
+
+    let config = Config::from_args(args(
+        "--bind 0.0.0.0:1 --bind [::]:1 --storage chat.jsonl --queue-capacity 10 --history-size 5 \
+        --offline-queue-limit 9 --thread-history 7 --thread-replies 3 --max-message-length 100 --max-groups 2 --typing-interval-ms 50 --log-level debug \
+        --group cats --group dogs")).unwrap();
+    assert_eq!(vec!["0.0.0.0:1".to_string(), "[::]:1".to_string()], config.bind);
+    assert_eq!(Some("chat.jsonl".to_string()), config.storage);
+    assert_eq!(10, config.queue_capacity);
+    assert_eq!(5, config.history_size);
+    assert_eq!(7, config.thread_history);
+    assert_eq!(3, config.thread_replies);
+    assert_eq!(9, config.offline_queue_limit);
+    assert_eq!(100, config.limits.max_message_length);
+    assert_eq!(2, config.limits.max_groups);
+    assert_eq!(50, config.limits.typing_interval_ms);
+    assert_eq!(LogLevel::Debug, config.log_level);
+    assert_eq!(vec!["cats".to_string(), "dogs".to_string()], config.groups);
+    assert!(config.logs(LogLevel::Info));
+
+    // Bad input
+    assert!(Config::from_args(args("")).is_err());
+    assert!(Config::from_args(args("a:1 --queue-capacity 0")).is_err());
+    assert!(Config::from_args(args("a:1 --queue-capacity lots")).is_err());
+    assert!(Config::from_args(args("a:1 --thread-history 0")).is_err());
+    assert!(Config::from_args(args("a:1 --thread-replies 0")).is_err());
+    assert!(Config::from_args(args("a:1 --log-level loud")).is_err());
+    assert!(Config::from_args(args("a:1 --max-groups 1 --group cats --group dogs")).is_err());
+    assert!(Config::from_args(args("a:1 --unknown 1")).is_err());
+    assert!(Config::from_args(args("a:1 --bind")).is_err());
+
+    // --config as the value of another flag is not the config flag, and not a group name either
+    assert!(Config::from_args(args("a:1 --group --config")).unwrap_err().to_string().contains("'--config'"));
+    assert!(Config::from_args(args("a:1 --group -cats")).is_err());
+    assert!(Config::from_args(args("a:1 --config")).is_err());
+}
+
+#[test]
+fn test_config_toml()
+{
+    let config: Config = toml::from_str(r#"
+        bind = ["127.0.0.1:8088"]
+        queue_capacity = 50
+        log_level = "warn"
+        groups = ["cats"]
+
+        [limits]
+        max_groups = 3
+    "#).unwrap();
+
+    assert_eq!(50, config.queue_capacity);
+    assert_eq!(LogLevel::Warn, config.log_level);
+    assert_eq!(3, config.limits.max_groups);
+    assert_eq!(Limits::default().max_message_length, config.limits.max_message_length);
+    assert!(!config.logs(LogLevel::Info));
+
+    // Printed config can be read back
+    let reparsed: Config = toml::from_str(&config.to_string()).unwrap();
+    assert_eq!(config, reparsed);
+
+    // Typos are not silently ignored
+    assert!(toml::from_str::<Config>("queue_capasity = 5").is_err());
+    assert!(toml::from_str::<Config>("queue_capacity = -5").is_err());
+}
@@ -1,54 +1,475 @@
 use async_std::task;
-use web_chat::ServerPacket;
-use std::{collections::HashMap, sync::{Arc, Mutex}};
+use web_chat::{Member, SealedKey, ServerPacket, ThreadMessage, utils::AppResult};
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, sync::{Arc, Mutex}, time::{Duration, Instant}};
 use tokio::sync::broadcast::{self, Sender, Receiver, error::RecvError};
 
-use crate::Outbound;
+use crate::{
+    config::Config,
+    storage::{Storage, StoredGroup, StoredMembership, StoredMessage},
+    users::User,
+    Outbound
+};
+
+/// Settings every group is created with
+#[derive(Clone, Copy)]
+pub struct GroupSettings
+{
+    pub queue_capacity: usize,
+    pub history_size: usize,
+    pub offline_queue_limit: usize,
+    pub thread_history: usize,
+    pub thread_replies: usize,
+    pub typing_interval: Duration,
+}
 
 pub struct Group
 {
     name: Arc<String>,
-    sender: Sender<Arc<String>>
+    settings: GroupSettings,
+    storage: Arc<dyn Storage>,
+    sender: Sender<ServerPacket>,
+    history: Mutex<History>,
+    presence: Mutex<Presence>,
+    membership: Option<Mutex<Membership>>,   // only end-to-end encrypted groups track members
+}
+
+struct History
+{
+    last_seq: u64,                          // seq of the last posted message, 0 if nothing was posted
+    packets: VecDeque<ServerPacket>,        // enough for both the join replay and the offline queue
+    threads: Threads,
 }
 
-const MESSAGE_QUEUE_CAPACITY: usize = 1000;
+/// Messages of the most recent threads, older threads can't be fetched or replied to.
+/// Long threads keep their root and the most recent replies.
+#[derive(Default)]
+struct Threads
+{
+    roots: HashMap<u64, u64>,               // message seq -> seq of its thread root
+    messages: BTreeMap<u64, Vec<ThreadMessage>>,  // thread root -> messages in seq order
+}
+
+/// Typing and read state of the members, it is not a part of the history
+#[derive(Default)]
+struct Presence
+{
+    members: HashSet<u64>,                  // connection ids that joined the group
+    typing: HashMap<u64, Instant>,          // connection id -> last relayed typing event
+    reads: HashMap<u64, u64>,               // connection id -> last read seq
+}
+
+/// Who holds the current key of an encrypted group.
+/// Every change of members starts a new epoch that needs a new key,
+/// so a member that left can't read anything posted after it left.
+struct Membership
+{
+    epoch: u64,
+    members: BTreeMap<u64, (Arc<String>, Arc<Outbound>)>,   // connection id -> public key, reply stream
+}
 
 impl Group
 {
-    pub fn new(name: Arc<String>) -> Group
+    pub fn new(name: Arc<String>, settings: GroupSettings, encrypted: bool, storage: Arc<dyn Storage>) -> Group
     {
-        let (sender, _) = broadcast::channel(MESSAGE_QUEUE_CAPACITY);
-        Group { name, sender }
+        let (sender, _) = broadcast::channel(settings.queue_capacity);
+        let history = Mutex::new(History {
+            last_seq: 0,
+            packets: VecDeque::with_capacity(settings.retained()),
+            threads: Threads::default(),
+        });
+        let presence = Mutex::new(Presence::default());
+        let membership = encrypted.then(|| Mutex::new(Membership { epoch: 0, members: BTreeMap::new() }));
+        Group { name, settings, storage, sender, history, presence, membership }
     }
 
-    pub fn join(&self, outbound: Arc<Outbound>)
+    /// Group that existed before the restart, with its recent history loaded back.
+    /// Threads are rebuilt from that history only, older replies can't be fetched anymore.
+    fn restore(stored: StoredGroup, settings: GroupSettings, storage: Arc<dyn Storage>) -> AppResult<Group>
     {
+        let messages = storage.recent_messages(&stored.name, settings.retained())?;
+        let group = Group::new(stored.name, settings, stored.encrypted, storage);
+
+        // Seq goes on from the last message, also when it is not kept or was encrypted
+        let mut history = group.history.lock().unwrap();
+        history.last_seq = stored.last_seq;
+        for stored in messages {
+            let message = ThreadMessage { seq: stored.seq, reply_to: stored.reply_to, message: stored.message };
+            let packet = ServerPacket::Message {
+                group: group.name.clone(),
+                seq: message.seq,
+                reply_to: message.reply_to,
+                thread_root: stored.thread_root,
+                message: message.message.clone(),
+            };
+
+            history.last_seq = message.seq.max(history.last_seq);
+            history.threads.add(message, stored.thread_root, &settings);
+            history.push(packet, settings.retained());
+        }
+        drop(history);
+
+        Ok(group)
+    }
+
+    pub fn is_encrypted(&self) -> bool
+    {
+        self.membership.is_some()
+    }
+
+    /// Known user that was in the group before gets what it has not read yet,
+    /// everybody else gets the recent history
+    pub fn join(&self, member_id: u64, outbound: Arc<Outbound>, user: Option<Arc<User>>) -> Result<(), String>
+    {
+        // Encrypted groups are joined with a public key only, so every member gets the group key
+        if self.is_encrypted() {
+            return Err(format!("Group '{}' is end-to-end encrypted, join it with a public key", self.name));
+        }
+
+        // History lock makes sure that a message posted right now
+        // ends up either in the replayed history or in the receiver, but not in both
+        let history = self.history.lock().unwrap();
+        if !self.presence.lock().unwrap().members.insert(member_id) {
+            return Err(format!("Already joined the group '{}'", self.name));
+        }
+        let receiver = self.sender.subscribe();
+
+        let last_seen = user.as_ref().and_then(|user| user.last_seen(&self.name));
+        let replay = match last_seen {
+            Some(last_seen) => history.missed_since(&self.name, last_seen, self.settings.offline_queue_limit),
+            None => history.recent(self.settings.history_size),
+        };
+
+        // Messages posted before the first join are not missed
+        if let (Some(user), None) = (&user, last_seen) {
+            user.saw(&self.name, history.last_seq);
+
+            let membership = StoredMembership { user: user.name().clone(), group: self.name.clone(), last_seen: history.last_seq };
+            if let Err(message) = self.storage.set_membership(membership) {
+                eprintln!("error: can't store that {} joined {}: {}", user.name(), self.name, message);
+            }
+        }
+        drop(history);
+
         // Who is monitoring the tasks that we create here?
         // Looks like the vars are moved here and when the tasks exists all the cleanup is done automatically
+        task::spawn(handle_subscriber(self.name.clone(), replay, receiver, outbound));
+        Ok(())
+    }
+
+    pub fn join_encrypted(&self, member_id: u64, public_key: Arc<String>, outbound: Arc<Outbound>) -> Result<(), String>
+    {
+        let mut membership = self.membership()?;
+        if membership.members.contains_key(&member_id) {
+            return Err(format!("Already joined the group '{}'", self.name));
+        }
+
+        // Subscribe first so the new member gets its own Members announcement
         let receiver = self.sender.subscribe();
-        task::spawn(handle_subscriber(self.name.clone(), receiver, outbound));
+        task::spawn(handle_subscriber(self.name.clone(), Vec::new(), receiver, outbound.clone()));
+
+        membership.members.insert(member_id, (public_key, outbound));
+        self.presence.lock().unwrap().members.insert(member_id);
+        self.announce_members(&mut membership);
+        Ok(())
+    }
+
+    pub fn leave(&self, member_id: u64)
+    {
+        let mut presence = self.presence.lock().unwrap();
+        presence.members.remove(&member_id);
+        presence.typing.remove(&member_id);
+        presence.reads.remove(&member_id);
+        drop(presence);
+
+        if let Ok(mut membership) = self.membership() {
+            if membership.members.remove(&member_id).is_some() && !membership.members.is_empty() {
+                self.announce_members(&mut membership);
+            }
+        }
+    }
+
+    /// Returns the sealed keys paired with the streams of the members they are for
+    pub fn share_key(&self, member_id: u64, epoch: u64, keys: Vec<SealedKey>) -> Result<Vec<(Arc<Outbound>, ServerPacket)>, String>
+    {
+        let membership = self.membership()?;
+        let from_public_key = match membership.members.get(&member_id) {
+            Some((public_key, _)) => public_key.clone(),
+            None => return Err(format!("Can't share a key of the group '{}' without joining it", self.name)),
+        };
+
+        if epoch != membership.epoch {
+            return Err(format!("Key for epoch {} of the group '{}' is outdated", epoch, self.name));
+        }
+
+        // One key per epoch, from the member with the smallest id as the clients expect
+        if membership.members.keys().next() != Some(&member_id) {
+            return Err(format!("Only the first member shares the key of the group '{}'", self.name));
+        }
+
+        // Keys for members that left already are silently dropped
+        let replies = keys
+            .into_iter()
+            .filter_map(|sealed| {
+                let (_, outbound) = membership.members.get(&sealed.member)?;
+                let packet = ServerPacket::GroupKey {
+                    group: self.name.clone(),
+                    epoch,
+                    from_public_key: from_public_key.clone(),
+                    key: sealed.key,
+                };
+                Some((outbound.clone(), packet))
+            })
+            .collect();
+
+        Ok(replies)
+    }
+
+    pub fn post(&self, message: Arc<String>, reply_to: Option<u64>) -> Result<(), String>
+    {
+        if self.is_encrypted() {
+            return Err(format!("Group '{}' is end-to-end encrypted, plain text messages are not relayed", self.name));
+        }
+
+        // Seq is assigned and broadcasted under the lock so members see messages in seq order
+        let mut history = self.history.lock().unwrap();
+        let seq = history.last_seq + 1;
+
+        let thread_root = match reply_to {
+            Some(parent) => match history.threads.roots.get(&parent) {
+                Some(root) => *root,
+                None => return Err(format!("Can't reply to message {} of the group '{}', it is unknown or too old", parent, self.name)),
+            },
+            None => seq,
+        };
+
+        // Message the storage did not take is not relayed either, otherwise it would be lost on restart
+        let stored = StoredMessage { seq, reply_to, thread_root, message: message.clone() };
+        self.storage
+            .add_message(&self.name, stored)
+            .map_err(|e| format!("Can't store message for the group '{}': {}", self.name, e))?;
+
+        history.last_seq = seq;
+        history.threads.add(ThreadMessage { seq, reply_to, message: message.clone() }, thread_root, &self.settings);
+        let packet = ServerPacket::Message { group: self.name.clone(), seq, reply_to, thread_root, message };
+        history.push(packet.clone(), self.settings.retained());
+
+        self.broadcast(packet);
+        Ok(())
+    }
+
+    pub fn post_encrypted(&self, member_id: u64, epoch: u64, ciphertext: Arc<String>) -> Result<(), String>
+    {
+        let membership = self.membership()?;
+        if !membership.members.contains_key(&member_id) {
+            return Err(format!("Can't send to the group '{}' without joining it", self.name));
+        }
+
+        // Old keys may be known to members that left already
+        if epoch != membership.epoch {
+            return Err(format!("Message for epoch {} of the group '{}' is outdated, current epoch is {}", epoch, self.name, membership.epoch));
+        }
+
+        // Opaque ciphertext is never stored in the history,
+        // new members would not have the key to read it anyway. Only its seq is.
+        let mut history = self.history.lock().unwrap();
+        let seq = history.last_seq + 1;
+        self.storage
+            .set_last_seq(&self.name, seq)
+            .map_err(|e| format!("Can't store message seq for the group '{}': {}", self.name, e))?;
+
+        history.last_seq = seq;
+        self.broadcast(ServerPacket::Encrypted { group: self.name.clone(), seq, epoch, ciphertext });
+        Ok(())
+    }
+
+    pub fn thread(&self, member_id: u64, thread_root: u64) -> Result<ServerPacket, String>
+    {
+        // Presence is released before the history, join takes them in the other order
+        drop(self.presence_of(member_id)?);
+        let history = self.history.lock().unwrap();
+        match history.threads.messages.get(&thread_root) {
+            Some(messages) => Ok(ServerPacket::Thread { group: self.name.clone(), thread_root, messages: messages.clone() }),
+            None => Err(format!("Message {} of the group '{}' does not start a known thread", thread_root, self.name)),
+        }
+    }
+
+    /// Relays typing event unless the member already typed recently
+    pub fn typing(&self, member_id: u64) -> Result<(), String>
+    {
+        let now = Instant::now();
+        let mut presence = self.presence_of(member_id)?;
+
+        if let Some(last) = presence.typing.get(&member_id) {
+            if now.duration_since(*last) < self.settings.typing_interval {
+                return Ok(());
+            }
+        }
+
+        presence.typing.insert(member_id, now);
+        self.broadcast(ServerPacket::Typing { group: self.name.clone(), member: member_id });
+        Ok(())
+    }
+
+    /// The user keeps the read position of plain groups only, what it has read there
+    /// is not delivered again on reconnect. Encrypted groups are not rejoined on reconnect.
+    pub fn mark_read(&self, member_id: u64, upto_seq: u64, user: Option<&User>) -> Result<(), String>
+    {
+        let last_seq = self.history.lock().unwrap().last_seq;
+        if upto_seq > last_seq {
+            return Err(format!("Can't mark message {} of the group '{}' as read, the last message is {}", upto_seq, self.name, last_seq));
+        }
+
+        // Read position only moves forward
+        let mut presence = self.presence_of(member_id)?;
+        let read = presence.reads.entry(member_id).or_insert(0);
+        *read = upto_seq.max(*read);
+        drop(presence);
+
+        if let (Some(user), false) = (user, self.is_encrypted()) {
+            user.saw(&self.name, upto_seq);
+        }
+        Ok(())
+    }
+
+    pub fn read_by(&self, member_id: u64, seq: u64) -> Result<ServerPacket, String>
+    {
+        let presence = self.presence_of(member_id)?;
+        let mut members: Vec<u64> = presence.reads
+            .iter()
+            .filter(|(_, read)| **read >= seq)
+            .map(|(member, _)| *member)
+            .collect();
+        members.sort();
+
+        Ok(ServerPacket::ReadBy { group: self.name.clone(), seq, members })
+    }
+
+    /// Presence is shared with the members only
+    fn presence_of(&self, member_id: u64) -> Result<std::sync::MutexGuard<'_, Presence>, String>
+    {
+        let presence = self.presence.lock().unwrap();
+        match presence.members.contains(&member_id) {
+            true => Ok(presence),
+            false => Err(format!("Not a member of the group '{}'", self.name)),
+        }
+    }
+
+    fn membership(&self) -> Result<std::sync::MutexGuard<'_, Membership>, String>
+    {
+        match &self.membership {
+            Some(membership) => Ok(membership.lock().unwrap()),
+            None => Err(format!("Group '{}' is not end-to-end encrypted", self.name)),
+        }
     }
 
-    pub fn post(&self, message: Arc<String>)
+    fn announce_members(&self, membership: &mut Membership)
+    {
+        membership.epoch += 1;
+        let members = membership.members
+            .iter()
+            .map(|(id, (public_key, _))| Member { id: *id, public_key: public_key.clone() })
+            .collect();
+
+        self.broadcast(ServerPacket::Members { group: self.name.clone(), epoch: membership.epoch, members });
+    }
+
+    fn broadcast(&self, packet: ServerPacket)
     {
         // Ignoring error here for unclear reasons.
         // If there are no subscribers (all tasks did exit) this call will return error.
         // But if there are no subscribers the counter on Outbound is zero and it is cleaned up automatically.
-        let _ = self.sender.send(message);
+        let _ = self.sender.send(packet);
+    }
+}
+
+impl GroupSettings
+{
+    fn retained(&self) -> usize
+    {
+        self.history_size.max(self.offline_queue_limit)
     }
 }
 
-async fn handle_subscriber(group: Arc<String>, mut receiver: Receiver<Arc<String>>, outbound: Arc<Outbound>)
+impl History
 {
+    fn push(&mut self, packet: ServerPacket, retained: usize)
+    {
+        if retained > 0 {
+            if self.packets.len() == retained {
+                self.packets.pop_front();
+            }
+            self.packets.push_back(packet);
+        }
+    }
+
+    fn recent(&self, count: usize) -> Vec<ServerPacket>
+    {
+        let skip = self.packets.len().saturating_sub(count);
+        self.packets.iter().skip(skip).cloned().collect()
+    }
+
+    /// Up to limit latest messages after last_seen,
+    /// preceded by a summary when some of the missed messages are not delivered
+    fn missed_since(&self, group: &Arc<String>, last_seen: u64, limit: usize) -> Vec<ServerPacket>
+    {
+        let missed = self.last_seq.saturating_sub(last_seen);
+        let available = self.packets
+            .iter()
+            .filter(|packet| matches!(packet, ServerPacket::Message { seq, .. } if *seq > last_seen))
+            .count();
+        let delivered = available.min(limit);
+
+        let mut replay = Vec::with_capacity(delivered + 1);
+        if missed > delivered as u64 {
+            replay.push(ServerPacket::Missed { group: group.clone(), skipped: missed - delivered as u64 });
+        }
+        replay.extend(self.recent(delivered));
+        replay
+    }
+}
+
+impl Threads
+{
+    fn add(&mut self, message: ThreadMessage, thread_root: u64, settings: &GroupSettings)
+    {
+        self.roots.insert(message.seq, thread_root);
+        let messages = self.messages.entry(thread_root).or_default();
+        messages.push(message);
+
+        // Root of a restored thread may be older than the restored history
+        let replies_start = usize::from(messages[0].seq == thread_root);
+        if messages.len() - replies_start > settings.thread_replies {
+            let forgotten = messages.remove(replies_start);
+            self.roots.remove(&forgotten.seq);
+        }
+
+        // Roots are seqs, so the first entry is the oldest thread
+        while self.messages.len() > settings.thread_history {
+            if let Some((_, forgotten)) = self.messages.pop_first() {
+                for message in forgotten {
+                    self.roots.remove(&message.seq);
+                }
+            }
+        }
+    }
+}
+
+async fn handle_subscriber(group: Arc<String>, replay: Vec<ServerPacket>, mut receiver: Receiver<ServerPacket>, outbound: Arc<Outbound>)
+{
+    for packet in replay {
+        if outbound.send(packet).await.is_err() {
+            return;
+        }
+    }
+
     loop {
         let packet = match receiver.recv().await {
-            Ok(message) => ServerPacket::Message { group: group.clone(), message: message.clone() },
+            Ok(packet) => packet,
             Err(RecvError::Lagged(n)) => ServerPacket::Error(format!("Dropped {} messages from {}", n, group)),
             Err(RecvError::Closed) => break,
         };
 
-        let reply_result = outbound.send(packet).await;
-        if reply_result.is_err() {
+        if outbound.send(packet).await.is_err() {
             break;
         }
     }
@@ -56,31 +477,357 @@ async fn handle_subscriber(group: Arc<String>, mut receiver: Receiver<Arc<String
 
 // Std mutex is used here. In case there is no need
 // to await anything it is faster compared to async Mutex
-pub struct Groups(Mutex<HashMap<Arc<String>, Arc<Group>>>);
+pub struct Groups
+{
+    groups: Mutex<HashMap<Arc<String>, Arc<Group>>>,
+    storage: Arc<dyn Storage>,
+    settings: GroupSettings,
+    max_groups: usize,
+}
 
 impl Groups
 {
-    pub fn new() -> Groups
+    /// Groups from the storage come first, then missing startup groups from the config are created
+    pub fn new(config: &Config, storage: Arc<dyn Storage>) -> AppResult<Groups>
     {
-        Groups(Mutex::new(HashMap::new()))
+        let groups = Groups {
+            groups: Mutex::new(HashMap::new()),
+            storage: storage.clone(),
+            settings: GroupSettings {
+                queue_capacity: config.queue_capacity,
+                history_size: config.history_size,
+                offline_queue_limit: config.offline_queue_limit,
+                thread_history: config.thread_history,
+                thread_replies: config.thread_replies,
+                typing_interval: Duration::from_millis(config.limits.typing_interval_ms),
+            },
+            max_groups: config.limits.max_groups,
+        };
+
+        for stored in storage.groups()? {
+            let name = stored.name.clone();
+            let group = Group::restore(stored, groups.settings, storage.clone())?;
+            groups.groups.lock().unwrap().insert(name, Arc::new(group));
+        }
+
+        // Groups the server starts with don't count against the limit,
+        // it only stops clients from creating more
+        for name in &config.groups {
+            groups.create(Arc::new(name.clone()), false, None, usize::MAX)?;
+        }
+
+        Ok(groups)
     }
 
     pub fn get(&self, name: &String) -> Option<Arc<Group>>
     {
-        self.0
+        self.groups
             .lock()
             .unwrap()
             .get(name)
             .cloned() // Cloned returns an option instead of just doing Clone
     }
 
-    pub fn get_or_create(&self, name: Arc<String>) -> Arc<Group>
+    /// Plain groups a returning user was in are joined again with what it has not read,
+    /// groups the connection joined before Hello are already there
+    pub fn rejoin(&self, member_id: u64, outbound: Arc<Outbound>, user: &Arc<User>)
     {
-        self.0
-            .lock()
-            .unwrap()
+        for (name, _) in user.groups() {
+            if let Some(group) = self.get(&name).filter(|group| !group.is_encrypted()) {
+                let _ = group.join(member_id, outbound.clone(), Some(user.clone()));
+            }
+        }
+    }
+
+    pub fn get_or_create(&self, name: Arc<String>, encrypted: bool, owner: Option<Arc<String>>) -> Result<Arc<Group>, String>
+    {
+        self.create(name, encrypted, owner, self.max_groups)
+    }
+
+    fn create(&self, name: Arc<String>, encrypted: bool, owner: Option<Arc<String>>, max_groups: usize) -> Result<Arc<Group>, String>
+    {
+        let mut groups = self.groups.lock().unwrap();
+
+        if let Some(group) = groups.get(&name) {
+            return match (group.is_encrypted(), encrypted) {
+                (true, false) => Err(format!("Group '{}' is end-to-end encrypted, join it with a public key", name)),
+                (false, true) => Err(format!("Group '{}' already exists and is not end-to-end encrypted", name)),
+                _ => Ok(group.clone()),
+            };
+        }
+
+        if groups.len() >= max_groups {
+            return Err(format!(
+                "Can't create group '{}' because the server already has {} groups",
+                name, max_groups));
+        }
+
+        let stored = StoredGroup { name: name.clone(), encrypted, owner, last_seq: 0 };
+        self.storage
+            .add_group(stored)
+            .map_err(|e| format!("Can't store the group '{}': {}", name, e))?;
+
+        let group = groups
             .entry(name.clone())
-            .or_insert_with(|| Arc::new(Group::new(name)))
-            .clone() // Clone just increments reference count
+            .or_insert_with(|| Arc::new(Group::new(name, self.settings, encrypted, self.storage.clone())))
+            .clone(); // Clone just increments reference count
+
+        Ok(group)
     }
+
+    /// Called when a connection closes so encrypted groups rotate their keys
+    /// and the read positions of the connection are forgotten
+    pub fn leave_all(&self, member_id: u64)
+    {
+        let groups: Vec<Arc<Group>> = self.groups.lock().unwrap().values().cloned().collect();
+        for group in groups {
+            group.leave(member_id);
+        }
+    }
+}
+
+#[cfg(test)]
+use crate::storage::MemoryStorage;
+
+#[cfg(test)]
+fn test_group(typing_interval: Duration) -> Group
+{
+    let settings = GroupSettings { queue_capacity: 10, history_size: 1, offline_queue_limit: 2, thread_history: 2, thread_replies: 3, typing_interval };
+    Group::new(Arc::new("cats".to_string()), settings, false, Arc::new(MemoryStorage::new(10)))
+}
+
+#[test]
+fn test_read_receipts()
+{
+    let group = test_group(Duration::ZERO);
+    let mut receiver = group.sender.subscribe();
+
+    group.post(Arc::new("one".to_string()), None).unwrap();
+    group.post(Arc::new("two".to_string()), None).unwrap();
+    assert!(matches!(receiver.try_recv(), Ok(ServerPacket::Message { seq: 1, .. })));
+    assert!(matches!(receiver.try_recv(), Ok(ServerPacket::Message { seq: 2, .. })));
+
+    // Members that did not join can neither mark nor query
+    group.presence.lock().unwrap().members.extend([1, 2, 3]);
+    assert!(group.mark_read(4, 1, None).is_err());
+    assert!(group.read_by(4, 1).is_err());
+
+    group.mark_read(1, 2, None).unwrap();
+    group.mark_read(2, 1, None).unwrap();
+    group.mark_read(1, 1, None).unwrap(); // does not move back
+    assert!(group.mark_read(3, 3, None).is_err());
+
+    assert_eq!(Ok(ServerPacket::ReadBy { group: group.name.clone(), seq: 1, members: vec![1, 2] }), group.read_by(3, 1));
+    assert_eq!(Ok(ServerPacket::ReadBy { group: group.name.clone(), seq: 2, members: vec![1] }), group.read_by(3, 2));
+
+    // Receipts are not messages
+    assert!(receiver.try_recv().is_err());
+
+    group.leave(1);
+    assert_eq!(Ok(ServerPacket::ReadBy { group: group.name.clone(), seq: 2, members: vec![] }), group.read_by(3, 2));
+    assert!(group.read_by(1, 2).is_err());
+}
+
+#[test]
+fn test_typing_rate_limit()
+{
+    let group = test_group(Duration::from_secs(60));
+    let mut receiver = group.sender.subscribe();
+
+    group.presence.lock().unwrap().members.extend([1, 2]);
+    group.typing(1).unwrap();
+    group.typing(1).unwrap();
+    group.typing(2).unwrap();
+    assert!(group.typing(3).is_err());
+
+    assert_eq!(ServerPacket::Typing { group: group.name.clone(), member: 1 }, receiver.try_recv().unwrap());
+    assert_eq!(ServerPacket::Typing { group: group.name.clone(), member: 2 }, receiver.try_recv().unwrap());
+    assert!(receiver.try_recv().is_err());
+    assert_eq!(0, group.history.lock().unwrap().last_seq);
+}
+
+#[test]
+fn test_threads()
+{
+    let group = test_group(Duration::ZERO);
+    let mut receiver = group.sender.subscribe();
+    let post = |text: &str, reply_to| group.post(Arc::new(text.to_string()), reply_to);
+    group.presence.lock().unwrap().members.insert(1);
+
+    post("root", None).unwrap();            // 1
+    post("reply", Some(1)).unwrap();        // 2
+    post("reply to reply", Some(2)).unwrap(); // 3
+    post("other root", None).unwrap();      // 4
+    assert!(post("reply to unknown", Some(42)).is_err());
+
+    let message = receiver.try_recv().unwrap();
+    assert!(matches!(message, ServerPacket::Message { seq: 1, reply_to: None, thread_root: 1, .. }));
+    let message = receiver.try_recv().unwrap();
+    assert!(matches!(message, ServerPacket::Message { seq: 2, reply_to: Some(1), thread_root: 1, .. }));
+    let message = receiver.try_recv().unwrap();
+    assert!(matches!(message, ServerPacket::Message { seq: 3, reply_to: Some(2), thread_root: 1, .. }));
+
+    match group.thread(1, 1).unwrap() {
+        ServerPacket::Thread { messages, .. } => {
+            assert_eq!(vec![1, 2, 3], messages.iter().map(|m| m.seq).collect::<Vec<_>>());
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(group.thread(1, 2).is_err()); // a reply is not a root
+    assert!(group.thread(2, 1).is_err()); // threads are for the members only
+
+    // Only two threads are kept, the oldest one is forgotten
+    post("third root", None).unwrap();      // 5
+    assert!(group.thread(1, 1).is_err());
+    assert!(post("late reply", Some(3)).is_err());
+    assert!(post("reply", Some(4)).is_ok());  // 6
+
+    // Only three replies are kept, the root stays
+    for reply_to in [4, 6, 7, 8] {
+        post("reply", Some(reply_to)).unwrap(); // 7, 8, 9, 10
+    }
+    match group.thread(1, 4).unwrap() {
+        ServerPacket::Thread { messages, .. } => {
+            assert_eq!(vec![4, 8, 9, 10], messages.iter().map(|m| m.seq).collect::<Vec<_>>());
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(post("reply to forgotten", Some(6)).is_err());
+    assert!(post("reply to root", Some(4)).is_ok());
+}
+
+#[test]
+fn test_missed_since()
+{
+    let group = test_group(Duration::ZERO);
+    let seqs = |packets: Vec<ServerPacket>| -> Vec<(u64, u64)> {
+        packets
+            .into_iter()
+            .map(|packet| match packet {
+                ServerPacket::Message { seq, .. } => (seq, 0),
+                ServerPacket::Missed { skipped, .. } => (0, skipped),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    };
+
+    for text in ["one", "two", "three", "four"] {
+        group.post(Arc::new(text.to_string()), None).unwrap();
+    }
+
+    let history = group.history.lock().unwrap();
+
+    // New members get the history, returning ones get up to the offline limit
+    assert_eq!(vec![(4, 0)], seqs(history.recent(group.settings.history_size)));
+    assert_eq!(Vec::<(u64, u64)>::new(), seqs(history.missed_since(&group.name, 4, 2)));
+    assert_eq!(vec![(4, 0)], seqs(history.missed_since(&group.name, 3, 2)));
+    assert_eq!(vec![(3, 0), (4, 0)], seqs(history.missed_since(&group.name, 2, 2)));
+
+    // Too much was missed, the rest is summarized
+    assert_eq!(vec![(0, 2), (3, 0), (4, 0)], seqs(history.missed_since(&group.name, 0, 2)));
+    assert_eq!(vec![(0, 3), (4, 0)], seqs(history.missed_since(&group.name, 0, 1)));
+}
+
+#[test]
+fn test_restore()
+{
+    let group = test_group(Duration::ZERO);
+    group.post(Arc::new("one".to_string()), None).unwrap();
+    group.post(Arc::new("two".to_string()), Some(1)).unwrap();
+    group.post(Arc::new("three".to_string()), None).unwrap();
+
+    let stored = StoredGroup { name: group.name.clone(), encrypted: false, owner: None, last_seq: 3 };
+    let restored = Group::restore(stored.clone(), group.settings, group.storage.clone()).unwrap();
+    restored.presence.lock().unwrap().members.insert(1);
+
+    // Seq continues where it stopped and recent threads can be replied to,
+    // "one" is older than the retained history so it is not in the thread anymore
+    restored.post(Arc::new("four".to_string()), Some(2)).unwrap();
+    assert_eq!(4, restored.history.lock().unwrap().last_seq);
+    assert!(matches!(restored.thread(1, 1), Ok(ServerPacket::Thread { messages, .. }) if messages.len() == 2));
+    assert!(matches!(restored.history.lock().unwrap().recent(1)[..], [ServerPacket::Message { seq: 4, thread_root: 1, .. }]));
+
+    // Without any retained history seq still goes on from the stored one
+    let settings = GroupSettings { history_size: 0, offline_queue_limit: 0, ..group.settings };
+    let restored = Group::restore(stored, settings, group.storage.clone()).unwrap();
+    assert_eq!(3, restored.history.lock().unwrap().last_seq);
+}
+
+#[test]
+fn test_restored_groups_over_limit()
+{
+    let storage = Arc::new(MemoryStorage::new(10));
+    for name in ["cats", "dogs"] {
+        storage.add_group(StoredGroup { name: Arc::new(name.to_string()), encrypted: false, owner: None, last_seq: 0 }).unwrap();
+    }
+
+    // Server starts with more groups than clients may create
+    let mut config = Config::default();
+    config.limits.max_groups = 1;
+    config.groups = vec!["birds".to_string()];
+    let groups = Groups::new(&config, storage).unwrap();
+
+    assert!(groups.get(&"cats".to_string()).is_some());
+    assert!(groups.get(&"birds".to_string()).is_some());
+    assert!(groups.get_or_create(Arc::new("dogs".to_string()), false, None).is_ok());
+    assert!(groups.get_or_create(Arc::new("fish".to_string()), false, None).is_err());
+}
+
+#[test]
+fn test_share_key_leader()
+{
+    let settings = GroupSettings { queue_capacity: 10, history_size: 1, offline_queue_limit: 2, thread_history: 2, thread_replies: 3, typing_interval: Duration::ZERO };
+    let group = Group::new(Arc::new("cats".to_string()), settings, true, Arc::new(MemoryStorage::new(10)));
+    let outbound = crate::users::test_outbound();
+    group.join_encrypted(1, Arc::new("leader".to_string()), outbound.clone()).unwrap();
+    group.join_encrypted(2, Arc::new("member".to_string()), outbound).unwrap();
+
+    let keys = vec![SealedKey { member: 1, key: "a".to_string() }, SealedKey { member: 2, key: "b".to_string() }];
+    assert!(group.share_key(2, 2, keys.clone()).is_err());
+    assert!(group.share_key(1, 1, keys.clone()).is_err());
+
+    let replies = group.share_key(1, 2, keys).unwrap();
+    assert_eq!(2, replies.len());
+    assert!(matches!(&replies[1].1, ServerPacket::GroupKey { epoch: 2, from_public_key, .. } if from_public_key.as_str() == "leader"));
+}
+
+#[test]
+fn test_encrypted_not_rejoined()
+{
+    let groups = Groups::new(&Config::default(), Arc::new(MemoryStorage::new(10))).unwrap();
+    let users = Arc::new(crate::users::Users::new(Arc::new(MemoryStorage::new(10)), 2).unwrap());
+    let (name, token) = (Arc::new("tom".to_string()), web_chat::e2e::generate_token());
+    let outbound = crate::users::test_outbound();
+
+    let (session, _) = users.login(name.clone(), &token, outbound.clone()).unwrap();
+    let group = groups.get_or_create(Arc::new("cats".to_string()), true, None).unwrap();
+    group.join_encrypted(1, Arc::new("key".to_string()), outbound.clone()).unwrap();
+    group.post_encrypted(1, 1, Arc::new("secret".to_string())).unwrap();
+    group.mark_read(1, 1, Some(session.user())).unwrap();
+    assert!(group.join(1, outbound.clone(), Some(session.user().clone())).is_err());
+    drop(session);
+    group.leave(1);
+
+    // Reconnected user is not subscribed to the encrypted group without a key
+    let (session, _) = users.login(name, &token, outbound.clone()).unwrap();
+    assert!(session.user().groups().is_empty());
+    let receivers = group.sender.receiver_count();
+    groups.rejoin(2, outbound, session.user());
+    assert!(group.typing(2).is_err());
+    assert_eq!(receivers, group.sender.receiver_count());
+}
+
+#[test]
+fn test_join_once()
+{
+    let group = test_group(Duration::ZERO);
+    let outbound = crate::users::test_outbound();
+
+    // Join after the Hello rejoin must not subscribe the connection again
+    group.join(1, outbound.clone(), None).unwrap();
+    assert!(group.join(1, outbound.clone(), None).is_err());
+    assert_eq!(1, group.sender.receiver_count());
+
+    group.join(2, outbound, None).unwrap();
+    assert_eq!(2, group.sender.receiver_count());
 }
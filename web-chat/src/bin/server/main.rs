@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::sync::{Arc, atomic::{AtomicU64, Ordering}};
 use async_std::{
     stream::StreamExt,
     sync::Mutex,
@@ -22,47 +22,128 @@ use web_chat::{
 };
 
 // this is not web_chat crate but rather bin/server crate inside web_chat
-use crate::groups::Groups;
+use crate::{
+    config::{Config, LogLevel},
+    groups::Groups,
+    storage::{FileStorage, MemoryStorage, Storage},
+    users::{Session, Users}
+};
 
+mod config;
 mod groups;
+mod storage;
+mod users;
+
+// Identifies a connection inside the groups it joined
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
 fn main() -> AppResult<()>
 {
+    // was: std::env::args().nth(1) with the server address
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => Arc::new(config),
+        Err(message) => {
+            eprintln!("error: {}", message);
+            eprintln!("{}", config::USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    println!("# Effective config\n{}\n", config);
+
+    // Without a storage file everything is forgotten on restart.
+    // Either way only the messages that can be replayed are kept, at least one for its seq.
+    let keep = config.history_size.max(config.offline_queue_limit).max(1);
+    let storage: Arc<dyn Storage> = match &config.storage {
+        Some(path) => Arc::new(FileStorage::open(path, keep)?),
+        None => Arc::new(MemoryStorage::new(keep)),
+    };
+
     // Shared across the server app
-    let groups = Arc::new(Groups::new());
+    let groups = Arc::new(Groups::new(&config, storage.clone())?);
+    let users = Arc::new(Users::new(storage, config.offline_queue_limit)?);
 
     async_std::task::block_on(async {
-        // was: places outside of async block
-        let server_address = std::env::args().nth(1).expect("Usage: server <SERVER ADDRESS>:<PORT>");
-
         // this is really a tcp socket server and original code calls it socket
-        let listner = TcpListener::bind(server_address).await?;
-
-        while let Some(tcp_stream_result) = listner.incoming().next().await {
-            let tcp_stream = tcp_stream_result?;
-            let groups_copy = groups.clone();
-
-            // async task that is spawn for each connection
-            // the tcp_streams would be shared via the groups that would remember
-            // what connection to use for replies
-            task::spawn(async {
-                let server_termination_reason = process_packets(tcp_stream, groups_copy).await;
-                if let Err(message) = server_termination_reason {
-                    eprintln!("error: {}", message);
-                }
-                else {
-                    println!("client connection was closed");
-                }
-            });
+        let mut listeners = Vec::new();
+        for address in &config.bind {
+            let listener = TcpListener::bind(address).await
+                .map_err(|e| format!("can't listen on {}: {}", address, e))?;
+            listeners.push(listener);
         }
 
-        Ok(())
+        // Each address gets its own accept loop, the server runs until all of them fail.
+        // A failing loop reports itself right away, the others keep accepting.
+        let accept_loops: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| {
+                let (groups, users, config) = (groups.clone(), users.clone(), config.clone());
+                task::spawn(async move {
+                    let address = listener.local_addr().map(|address| address.to_string()).unwrap_or_default();
+                    let result = accept_connections(listener, groups, users, config.clone()).await;
+                    if let Err(message) = &result {
+                        if config.logs(LogLevel::Error) {
+                            eprintln!("error: listener on {} stopped: {}", address, message);
+                        }
+                    }
+                    result
+                })
+            })
+            .collect();
+
+        let mut failed = 0;
+        for accept_loop in accept_loops {
+            if accept_loop.await.is_err() {
+                failed += 1;
+            }
+        }
+
+        match failed {
+            0 => Ok(()),
+            _ => Err(format!("{} of the listeners failed", failed).into()),
+        }
     })
 }
 
+async fn accept_connections(listener: TcpListener, groups: Arc<Groups>, users: Arc<Users>, config: Arc<Config>) -> AppResult<()>
+{
+    if config.logs(LogLevel::Info) {
+        println!("listening on {}", listener.local_addr()?);
+    }
+
+    while let Some(tcp_stream_result) = listener.incoming().next().await {
+        let tcp_stream = tcp_stream_result?;
+        let groups_copy = groups.clone();
+        let users_copy = users.clone();
+        let config_copy = config.clone();
+        let connection_id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+
+        // async task that is spawn for each connection
+        // the tcp_streams would be shared via the groups that would remember
+        // what connection to use for replies
+        task::spawn(async move {
+            let server_termination_reason = process_packets(tcp_stream, connection_id, groups_copy.clone(), users_copy, &config_copy).await;
+            groups_copy.leave_all(connection_id);
+            if let Err(message) = server_termination_reason {
+                if config_copy.logs(LogLevel::Error) {
+                    eprintln!("error: {}", message);
+                }
+            }
+            else if config_copy.logs(LogLevel::Info) {
+                println!("client connection was closed");
+            }
+        });
+    }
+
+    Ok(())
+}
+
 // was: serve
-async fn process_packets(stream: TcpStream, groups: Arc<Groups>) -> AppResult<()>
+async fn process_packets(stream: TcpStream, connection_id: u64, groups: Arc<Groups>, users: Arc<Users>, config: &Config) -> AppResult<()>
 {
+    // Anonymous until the client says Hello
+    let mut session: Option<Session> = None;
+
     // All replies to that connected to the servier client
     // go through that guarded reply stream
     let server_reply_stream = Arc::new(Outbound::new(stream.clone()));
@@ -73,17 +154,135 @@ async fn process_packets(stream: TcpStream, groups: Arc<Groups>) -> AppResult<()
 
     while let Some(client_read_packet_result) = client_read_packets_stream.next().await  {
         let client_packet_processing_result = match client_read_packet_result? {
+            ClientPacket::Hello { .. } if session.is_some() => {
+                Err("Hello can be sent only once per connection".to_string())
+            }
+            ClientPacket::Hello { user, token } => {
+                match users.login(user, &token, server_reply_stream.clone()) {
+                    Ok((new_session, unread)) => {
+                        let user = new_session.user().clone();
+                        if config.logs(LogLevel::Info) {
+                            println!("user {} connected", user.name());
+                        }
+
+                        groups.rejoin(connection_id, server_reply_stream.clone(), &user);
+
+                        session = Some(new_session);
+                        for packet in unread {
+                            server_reply_stream.send(packet).await?;
+                        }
+                        Ok(())
+                    }
+                    Err(message) => Err(message),
+                }
+            }
             ClientPacket::Join { group } => {
-                let used_group = groups.get_or_create(group);
-                used_group.join(server_reply_stream.clone());   // reply stream is needed in post
-                Ok(())
+                let user = session.as_ref().map(|session| session.user().clone());
+                let owner = user.as_ref().map(|user| user.name().clone());
+                groups
+                    .get_or_create(group, false, owner)
+                    .and_then(|used_group| used_group.join(connection_id, server_reply_stream.clone(), user))   // reply stream is needed in post
+            }
+            ClientPacket::JoinEncrypted { group, public_key } => {
+                let owner = session.as_ref().map(|session| session.user().name().clone());
+                groups
+                    .get_or_create(group, true, owner)
+                    .and_then(|used_group| used_group.join_encrypted(connection_id, public_key, server_reply_stream.clone()))
             }
-            ClientPacket::Send { group, message } => {
+            ClientPacket::SendDirect { message, .. } if message.len() > config.limits.max_message_length => {
+                Err(format!(
+                    "Message of {} bytes is longer than the {} bytes limit",
+                    message.len(), config.limits.max_message_length))
+            }
+            ClientPacket::SendDirect { to, message } => {
+                match &session {
+                    Some(session) => match users.send_direct(session.user().name(), &to, message) {
+                        Ok(Some((outbound, packet))) => {
+                            // Connection of the addressee may be gone already, the message stays unread then
+                            let _ = outbound.send(packet).await;
+                            Ok(())
+                        }
+                        Ok(None) => Ok(()),
+                        Err(message) => Err(message),
+                    },
+                    None => Err("Direct messages can be sent only after Hello".to_string()),
+                }
+            }
+            ClientPacket::ReadDirect { upto_seq } => {
+                match &session {
+                    Some(session) => session.user().read_direct(upto_seq),
+                    None => Err("Direct messages can be read only after Hello".to_string()),
+                }
+            }
+            ClientPacket::Send { message, .. } if message.len() > config.limits.max_message_length => {
+                Err(format!(
+                    "Message of {} bytes is longer than the {} bytes limit",
+                    message.len(), config.limits.max_message_length))
+            }
+            ClientPacket::SendEncrypted { ciphertext, .. } if ciphertext.len() > config.limits.max_message_length => {
+                Err(format!(
+                    "Encrypted message of {} bytes is longer than the {} bytes limit",
+                    ciphertext.len(), config.limits.max_message_length))
+            }
+            ClientPacket::SendEncrypted { group, epoch, ciphertext } => {
                 match groups.get(&group) {
-                    Some(used_group) => {
-                        used_group.post(message);               // would use preserved stream
+                    Some(used_group) => used_group.post_encrypted(connection_id, epoch, ciphertext),
+                    None => Err(format!("Can't send to the group '{}' because the group does not exist", group)),
+                }
+            }
+            ClientPacket::ShareKey { group, epoch, keys } => {
+                match groups.get(&group) {
+                    Some(used_group) => match used_group.share_key(connection_id, epoch, keys) {
+                        Ok(replies) => {
+                            // Each member gets only the key sealed for it
+                            for (outbound, packet) in replies {
+                                let _ = outbound.send(packet).await;
+                            }
+                            Ok(())
+                        }
+                        Err(message) => Err(message),
+                    },
+                    None => Err(format!("Can't share key of the group '{}' because the group does not exist", group)),
+                }
+            }
+            ClientPacket::Typing { group } => {
+                match groups.get(&group) {
+                    Some(used_group) => used_group.typing(connection_id),
+                    None => Err(format!("Can't type in the group '{}' because the group does not exist", group)),
+                }
+            }
+            ClientPacket::Read { group, upto_seq } => {
+                match groups.get(&group) {
+                    Some(used_group) => used_group.mark_read(connection_id, upto_seq, session.as_ref().map(|session| session.user().as_ref())),
+                    None => Err(format!("Can't read the group '{}' because the group does not exist", group)),
+                }
+            }
+            ClientPacket::WhoRead { group, seq } => {
+                match groups.get(&group).map(|used_group| used_group.read_by(connection_id, seq)) {
+                    Some(Ok(read_by)) => {
+                        // Direct reply, other members don't need it
+                        server_reply_stream.send(read_by).await?;
                         Ok(())
                     }
+                    Some(Err(message)) => Err(message),
+                    None => Err(format!("Can't query the group '{}' because the group does not exist", group)),
+                }
+            }
+            ClientPacket::FetchThread { group, thread_root } => {
+                match groups.get(&group).map(|used_group| used_group.thread(connection_id, thread_root)) {
+                    Some(Ok(thread)) => {
+                        server_reply_stream.send(thread).await?;
+                        Ok(())
+                    }
+                    Some(Err(message)) => Err(message),
+                    None => Err(format!("Can't fetch thread of the group '{}' because the group does not exist", group)),
+                }
+            }
+            ClientPacket::Send { group, message, reply_to } => {
+                match groups.get(&group) {
+                    Some(used_group) => {
+                        used_group.post(message, reply_to)      // would use preserved stream
+                    }
                     None => {
                         Err(format!(
                             "Can't send message '{}' to the group '{}' \
@@ -96,13 +295,16 @@ async fn process_packets(stream: TcpStream, groups: Arc<Groups>) -> AppResult<()
         };
 
         if let Err(message) = client_packet_processing_result {
+            if config.logs(LogLevel::Debug) {
+                eprintln!("debug: replying with error: {}", message);
+            }
             let error_reply = ServerPacket::Error(message);
             server_reply_stream.send(error_reply).await?;
         }
     }
 
-    // NOTE: server_reply_stream here is useless - the connection is closed
-    // so we actually need to remove this stream from all the groups that use it
+    // NOTE: server_reply_stream here is useless - the connection is closed,
+    // encrypted groups forget it in leave_all, plain groups drop it on the next failed send
     Ok(())
 }
 
@@ -0,0 +1,748 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+use web_chat::utils::AppResult;
+
+/// Where the server keeps the state that must survive a restart.
+/// Encrypted messages and the per connection state (typing, receipts, keys) are never stored.
+/// Only the latest messages of every group and user are kept, the server never reads the older ones.
+pub trait Storage: Send + Sync
+{
+    fn groups(&self) -> AppResult<Vec<StoredGroup>>;
+    fn memberships(&self) -> AppResult<Vec<StoredMembership>>;
+    fn users(&self) -> AppResult<Vec<StoredUser>>;
+
+    /// Up to count latest messages of the group in seq order
+    fn recent_messages(&self, group: &Arc<String>, count: usize) -> AppResult<Vec<StoredMessage>>;
+
+    /// Up to count latest direct messages to the user in seq order
+    fn recent_directs(&self, user: &Arc<String>, count: usize) -> AppResult<Vec<StoredDirect>>;
+
+    fn add_group(&self, group: StoredGroup) -> AppResult<()>;
+    fn add_message(&self, group: &Arc<String>, message: StoredMessage) -> AppResult<()>;
+    fn set_membership(&self, membership: StoredMembership) -> AppResult<()>;
+    fn set_user(&self, user: StoredUser) -> AppResult<()>;
+    fn add_direct(&self, user: &Arc<String>, message: StoredDirect) -> AppResult<()>;
+
+    /// Seq of a message that is not stored, encrypted messages take seqs too
+    fn set_last_seq(&self, group: &Arc<String>, seq: u64) -> AppResult<()>;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StoredGroup
+{
+    pub name: Arc<String>,
+    pub encrypted: bool,
+    pub owner: Option<Arc<String>>,     // user that created the group, None for anonymous and startup groups
+    #[serde(default)]
+    pub last_seq: u64,                  // seqs go on from it after a restart even when no message is kept
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StoredMessage
+{
+    pub seq: u64,
+    pub reply_to: Option<u64>,
+    pub thread_root: u64,
+    pub message: Arc<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StoredMembership
+{
+    pub user: Arc<String>,
+    pub group: Arc<String>,
+    pub last_seen: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StoredUser
+{
+    pub name: Arc<String>,
+    pub token_key: Arc<String>,         // e2e::token_key of the token, the token itself is never stored
+    pub direct_seen: u64,               // seq of the last direct message the user has read
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct StoredDirect
+{
+    pub seq: u64,
+    pub from: Arc<String>,
+    pub message: Arc<String>,
+}
+
+/// Keeps everything in memory, used in tests and when no storage file is configured
+pub struct MemoryStorage(Mutex<MemoryState>);
+
+struct MemoryState
+{
+    keep: usize,                                        // latest messages kept per group and per user
+    groups: HashMap<Arc<String>, StoredGroup>,
+    messages: HashMap<Arc<String>, VecDeque<StoredMessage>>,
+    memberships: HashMap<(Arc<String>, Arc<String>), u64>,
+    users: HashMap<Arc<String>, StoredUser>,
+    directs: HashMap<Arc<String>, VecDeque<StoredDirect>>,
+}
+
+impl MemoryStorage
+{
+    pub fn new(keep: usize) -> MemoryStorage
+    {
+        MemoryStorage(Mutex::new(MemoryState {
+            keep,
+            groups: HashMap::new(),
+            messages: HashMap::new(),
+            memberships: HashMap::new(),
+            users: HashMap::new(),
+            directs: HashMap::new(),
+        }))
+    }
+}
+
+impl Storage for MemoryStorage
+{
+    fn groups(&self) -> AppResult<Vec<StoredGroup>>
+    {
+        Ok(self.0.lock().unwrap().groups.values().cloned().collect())
+    }
+
+    fn memberships(&self) -> AppResult<Vec<StoredMembership>>
+    {
+        Ok(memberships(&self.0.lock().unwrap().memberships))
+    }
+
+    fn users(&self) -> AppResult<Vec<StoredUser>>
+    {
+        Ok(self.0.lock().unwrap().users.values().cloned().collect())
+    }
+
+    fn recent_messages(&self, group: &Arc<String>, count: usize) -> AppResult<Vec<StoredMessage>>
+    {
+        Ok(recent(self.0.lock().unwrap().messages.get(group), count))
+    }
+
+    fn recent_directs(&self, user: &Arc<String>, count: usize) -> AppResult<Vec<StoredDirect>>
+    {
+        Ok(recent(self.0.lock().unwrap().directs.get(user), count))
+    }
+
+    fn add_group(&self, group: StoredGroup) -> AppResult<()>
+    {
+        self.0.lock().unwrap().groups.insert(group.name.clone(), group);
+        Ok(())
+    }
+
+    fn add_message(&self, group: &Arc<String>, message: StoredMessage) -> AppResult<()>
+    {
+        let mut state = self.0.lock().unwrap();
+        if let Some(stored) = state.groups.get_mut(group) {
+            stored.last_seq = message.seq.max(stored.last_seq);
+        }
+
+        let keep = state.keep;
+        keep_latest(state.messages.entry(group.clone()).or_default(), message, keep);
+        Ok(())
+    }
+
+    fn set_membership(&self, membership: StoredMembership) -> AppResult<()>
+    {
+        self.0.lock().unwrap().memberships.insert((membership.user, membership.group), membership.last_seen);
+        Ok(())
+    }
+
+    fn set_user(&self, user: StoredUser) -> AppResult<()>
+    {
+        self.0.lock().unwrap().users.insert(user.name.clone(), user);
+        Ok(())
+    }
+
+    fn add_direct(&self, user: &Arc<String>, message: StoredDirect) -> AppResult<()>
+    {
+        let mut state = self.0.lock().unwrap();
+        let keep = state.keep;
+        keep_latest(state.directs.entry(user.clone()).or_default(), message, keep);
+        Ok(())
+    }
+
+    fn set_last_seq(&self, group: &Arc<String>, seq: u64) -> AppResult<()>
+    {
+        if let Some(stored) = self.0.lock().unwrap().groups.get_mut(group) {
+            stored.last_seq = seq.max(stored.last_seq);
+        }
+        Ok(())
+    }
+}
+
+fn keep_latest<T>(messages: &mut VecDeque<T>, message: T, keep: usize)
+{
+    messages.push_back(message);
+    if messages.len() > keep {
+        messages.pop_front();
+    }
+}
+
+fn recent<T: Clone>(messages: Option<&VecDeque<T>>, count: usize) -> Vec<T>
+{
+    match messages {
+        Some(messages) => messages.iter().skip(messages.len().saturating_sub(count)).cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Line of the storage file, the file is an append only log of these
+#[derive(Deserialize, Serialize)]
+enum Record
+{
+    Group(StoredGroup),
+    Message { group: Arc<String>, message: StoredMessage },
+    Membership(StoredMembership),
+    User(StoredUser),
+    Direct { user: Arc<String>, message: StoredDirect },
+    LastSeq { group: Arc<String>, seq: u64 },
+}
+
+/// Fewest records that are not needed anymore worth rewriting the file for,
+/// it is also how often the writer checks whether to compact
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+/// Append only JSON lines file.
+/// Groups, memberships and users are small and are kept in memory, messages stay in the file
+/// and only their offsets are indexed so history does not grow the server memory.
+///
+/// The file is owned by a writer thread, so the async tasks never wait for the disk:
+/// records are handed over through a channel and written in batches.
+/// When most of the file is outdated records and old messages the writer rewrites it
+/// with the latest state and only the messages that are kept.
+pub struct FileStorage
+{
+    commands: Option<mpsc::Sender<Command>>,
+    writer: Option<JoinHandle<()>>,
+    failure: Arc<Mutex<Option<String>>>,                // why the writer stopped
+}
+
+enum Command
+{
+    Append(Record),
+    Query(Box<dyn FnOnce(&mut FileState) + Send>),      // runs on the writer after everything before it is written
+}
+
+struct FileState
+{
+    path: String,
+    keep: usize,                                        // latest messages kept per group and per user on compaction
+    writer: BufWriter<File>,
+    reader: File,
+    end: u64,                                           // file length, offset of the next record
+    records: usize,                                     // lines in the file
+    next_check: usize,                                  // records count when to check whether to compact
+    groups: HashMap<Arc<String>, StoredGroup>,
+    memberships: HashMap<(Arc<String>, Arc<String>), u64>,
+    users: HashMap<Arc<String>, StoredUser>,
+    index: HashMap<Arc<String>, Vec<u64>>,              // group -> offsets of its messages in seq order
+    direct_index: HashMap<Arc<String>, Vec<u64>>,       // user -> offsets of its direct messages in seq order
+}
+
+impl FileStorage
+{
+    pub fn open(path: &str, keep: usize) -> AppResult<FileStorage>
+    {
+        let (writer, reader) = open_log(path)?;
+        let mut state = FileState {
+            path: path.to_string(),
+            keep,
+            writer,
+            reader,
+            end: 0,
+            records: 0,
+            next_check: 0,
+            groups: HashMap::new(),
+            memberships: HashMap::new(),
+            users: HashMap::new(),
+            index: HashMap::new(),
+            direct_index: HashMap::new(),
+        };
+
+        let mut reader = state.reader.try_clone()?;
+        let mut lines = BufReader::new(&mut reader);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = lines.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+
+            // A line cut short by a crash is the only thing that can be broken,
+            // it is cut off so that new records don't get glued to it
+            if !line.ends_with('\n') {
+                eprintln!("warning: storage file '{}' ends with an incomplete record, it is dropped", path);
+                state.writer.get_ref().set_len(state.end)?;
+                break;
+            }
+
+            let record = serde_json::from_str::<Record>(&line)
+                .map_err(|e| format!("storage file '{}' is corrupted at byte {}: {}", path, state.end, e))?;
+            state.apply(record, state.end);
+            state.end += read as u64;
+            state.records += 1;
+        }
+
+        // Whatever a previous run left over is compacted right away
+        state.compact_if_worth_it()?;
+
+        let failure = Arc::new(Mutex::new(None));
+        let (commands, received) = mpsc::channel();
+        let writer_failure = failure.clone();
+        let writer = thread::Builder::new()
+            .name("storage".to_string())
+            .spawn(move || state.run(received, writer_failure))?;
+
+        Ok(FileStorage { commands: Some(commands), writer: Some(writer), failure })
+    }
+
+    fn send(&self, command: Command) -> AppResult<()>
+    {
+        let sent = self.commands.as_ref().map(|commands| commands.send(command));
+        match sent {
+            Some(Ok(())) => Ok(()),
+            _ => {
+                let failure = self.failure.lock().unwrap().clone().unwrap_or_default();
+                Err(format!("storage writer stopped: {}", failure).into())
+            }
+        }
+    }
+
+    fn append(&self, record: Record) -> AppResult<()>
+    {
+        self.send(Command::Append(record))
+    }
+
+    /// Blocks until the writer answers, it is used only while the server starts
+    fn query<T, F>(&self, query: F) -> AppResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut FileState) -> T + Send + 'static
+    {
+        let (reply, answer) = mpsc::channel();
+        self.send(Command::Query(Box::new(move |state| {
+            let _ = reply.send(query(state));
+        })))?;
+        answer.recv().map_err(|_| "storage writer stopped while answering".into())
+    }
+}
+
+impl Drop for FileStorage
+{
+    fn drop(&mut self)
+    {
+        // Closed channel stops the writer once everything sent before is written
+        self.commands.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn open_log(path: &str) -> AppResult<(BufWriter<File>, File)>
+{
+    let file = OpenOptions::new().create(true).append(true).read(true).open(path)
+        .map_err(|e| format!("can't open storage file '{}': {}", path, e))?;
+    // Clones share the cursor, that is fine since appends ignore it and reads always seek first
+    let reader = file.try_clone()?;
+    Ok((BufWriter::new(file), reader))
+}
+
+impl FileState
+{
+    fn run(mut self, commands: mpsc::Receiver<Command>, failure: Arc<Mutex<Option<String>>>)
+    {
+        while let Ok(command) = commands.recv() {
+            // Whatever is already waiting goes into the same batch, the batch is flushed once
+            let mut next = Some(command);
+            let mut result = Ok(());
+            while let Some(command) = next {
+                result = self.execute(command);
+                if result.is_err() {
+                    break;
+                }
+                next = commands.try_recv().ok();
+            }
+
+            if let Err(e) = result.and_then(|_| Ok(self.writer.flush()?)) {
+                eprintln!("error: can't write storage file '{}': {}", self.path, e);
+                *failure.lock().unwrap() = Some(e.to_string());
+                return;
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> AppResult<()>
+    {
+        match command {
+            Command::Append(record) => {
+                self.write(record)?;
+                self.compact_if_worth_it()
+            }
+            Command::Query(query) => {
+                self.writer.flush()?;
+                query(self);
+                Ok(())
+            }
+        }
+    }
+
+    fn apply(&mut self, record: Record, offset: u64)
+    {
+        match record {
+            Record::Group(group) => {
+                // Group is written again on compaction, then with its last seq
+                let last_seq = self.groups.get(&group.name).map_or(0, |known| known.last_seq);
+                let group = StoredGroup { last_seq: group.last_seq.max(last_seq), ..group };
+                self.groups.insert(group.name.clone(), group);
+            }
+            Record::Message { group, message } => {
+                self.bump_last_seq(&group, message.seq);
+                self.index.entry(group).or_default().push(offset);
+            }
+            Record::Membership(membership) => {
+                self.memberships.insert((membership.user, membership.group), membership.last_seen);
+            }
+            Record::User(user) => {
+                self.users.insert(user.name.clone(), user);
+            }
+            Record::Direct { user, .. } => self.direct_index.entry(user).or_default().push(offset),
+            Record::LastSeq { group, seq } => self.bump_last_seq(&group, seq),
+        }
+    }
+
+    fn bump_last_seq(&mut self, group: &Arc<String>, seq: u64)
+    {
+        if let Some(stored) = self.groups.get_mut(group) {
+            stored.last_seq = seq.max(stored.last_seq);
+        }
+    }
+
+    fn write(&mut self, record: Record) -> AppResult<()>
+    {
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+
+        let offset = self.end;
+        self.end += line.len() as u64;
+        self.records += 1;
+        self.apply(record, offset);
+        Ok(())
+    }
+
+    /// Records at the offsets, in the same order
+    fn read(&mut self, offsets: &[u64]) -> AppResult<Vec<Record>>
+    {
+        let mut records = Vec::with_capacity(offsets.len());
+        let mut line = String::new();
+        for offset in offsets {
+            self.reader.seek(SeekFrom::Start(*offset))?;
+            line.clear();
+            BufReader::new(&mut self.reader).read_line(&mut line)?;
+            records.push(serde_json::from_str(&line)?);
+        }
+
+        Ok(records)
+    }
+
+    fn recent(&mut self, offsets: Option<&Vec<u64>>, count: usize) -> AppResult<Vec<Record>>
+    {
+        match offsets {
+            Some(offsets) => {
+                let offsets = offsets[offsets.len().saturating_sub(count)..].to_vec();
+                self.read(&offsets)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Records a compacted file would have
+    fn live_records(&self) -> usize
+    {
+        let kept = |index: &HashMap<Arc<String>, Vec<u64>>| -> usize {
+            index.values().map(|offsets| offsets.len().min(self.keep)).sum()
+        };
+        self.groups.len() + self.memberships.len() + self.users.len() + kept(&self.index) + kept(&self.direct_index)
+    }
+
+    fn compact_if_worth_it(&mut self) -> AppResult<()>
+    {
+        if self.records < self.next_check {
+            return Ok(());
+        }
+
+        let live = self.live_records();
+        if self.records - live >= COMPACT_MIN_RECORDS.max(live) {
+            self.compact()?;
+        }
+        self.next_check = self.records + COMPACT_MIN_RECORDS;
+        Ok(())
+    }
+
+    /// Rewrites the file with the latest state and the kept messages,
+    /// the new file replaces the old one only when it is completely written
+    fn compact(&mut self) -> AppResult<()>
+    {
+        self.writer.flush()?;
+
+        let mut records: Vec<Record> = self.groups.values().cloned().map(Record::Group).collect();
+        records.extend(self.users.values().cloned().map(Record::User));
+        records.extend(memberships(&self.memberships).into_iter().map(Record::Membership));
+
+        let groups: Vec<Arc<String>> = self.index.keys().cloned().collect();
+        for group in groups {
+            let index = self.index.get(&group).cloned();
+            records.extend(self.recent(index.as_ref(), self.keep)?);
+        }
+        let users: Vec<Arc<String>> = self.direct_index.keys().cloned().collect();
+        for user in users {
+            let index = self.direct_index.get(&user).cloned();
+            records.extend(self.recent(index.as_ref(), self.keep)?);
+        }
+
+        let compacted = format!("{}.compact", self.path);
+        let mut file = BufWriter::new(File::create(&compacted)?);
+        let mut line_lengths = Vec::with_capacity(records.len());
+        for record in &records {
+            let mut line = serde_json::to_string(record)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+            line_lengths.push(line.len() as u64);
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&compacted, &self.path)?;
+
+        (self.writer, self.reader) = open_log(&self.path)?;
+        self.end = 0;
+        self.records = 0;
+        self.index.clear();
+        self.direct_index.clear();
+        for (record, line_length) in records.into_iter().zip(line_lengths) {
+            self.apply(record, self.end);
+            self.end += line_length;
+            self.records += 1;
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage
+{
+    // A stopped writer is an error, not an empty storage, or names would be handed out again
+    fn groups(&self) -> AppResult<Vec<StoredGroup>>
+    {
+        self.query(|state| state.groups.values().cloned().collect())
+    }
+
+    fn memberships(&self) -> AppResult<Vec<StoredMembership>>
+    {
+        self.query(|state| memberships(&state.memberships))
+    }
+
+    fn users(&self) -> AppResult<Vec<StoredUser>>
+    {
+        self.query(|state| state.users.values().cloned().collect())
+    }
+
+    fn recent_messages(&self, group: &Arc<String>, count: usize) -> AppResult<Vec<StoredMessage>>
+    {
+        let group = group.clone();
+        let records = self.query(move |state| {
+            let index = state.index.get(&group).cloned();
+            state.recent(index.as_ref(), count)
+        })??;
+
+        let messages = records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Message { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect();
+
+        Ok(messages)
+    }
+
+    fn recent_directs(&self, user: &Arc<String>, count: usize) -> AppResult<Vec<StoredDirect>>
+    {
+        let user = user.clone();
+        let records = self.query(move |state| {
+            let index = state.direct_index.get(&user).cloned();
+            state.recent(index.as_ref(), count)
+        })??;
+
+        let directs = records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Direct { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect();
+
+        Ok(directs)
+    }
+
+    fn add_group(&self, group: StoredGroup) -> AppResult<()>
+    {
+        self.append(Record::Group(group))
+    }
+
+    fn add_message(&self, group: &Arc<String>, message: StoredMessage) -> AppResult<()>
+    {
+        self.append(Record::Message { group: group.clone(), message })
+    }
+
+    fn set_membership(&self, membership: StoredMembership) -> AppResult<()>
+    {
+        self.append(Record::Membership(membership))
+    }
+
+    fn set_user(&self, user: StoredUser) -> AppResult<()>
+    {
+        self.append(Record::User(user))
+    }
+
+    fn add_direct(&self, user: &Arc<String>, message: StoredDirect) -> AppResult<()>
+    {
+        self.append(Record::Direct { user: user.clone(), message })
+    }
+
+    fn set_last_seq(&self, group: &Arc<String>, seq: u64) -> AppResult<()>
+    {
+        self.append(Record::LastSeq { group: group.clone(), seq })
+    }
+}
+
+fn memberships(memberships: &HashMap<(Arc<String>, Arc<String>), u64>) -> Vec<StoredMembership>
+{
+    memberships
+        .iter()
+        .map(|((user, group), last_seen)| StoredMembership { user: user.clone(), group: group.clone(), last_seen: *last_seen })
+        .collect()
+}
+
+#[cfg(test)]
+fn check_storage(storage: &dyn Storage)
+{
+    let cats = Arc::new("cats".to_string());
+    let dogs = Arc::new("dogs".to_string());
+    let message = |seq, text: &str| StoredMessage { seq, reply_to: None, thread_root: seq, message: Arc::new(text.to_string()) };
+
+    storage.add_group(StoredGroup { name: cats.clone(), encrypted: false, owner: Some(Arc::new("tom".to_string())), last_seq: 0 }).unwrap();
+    storage.add_message(&cats, message(1, "one")).unwrap();
+    storage.add_message(&cats, message(2, "two")).unwrap();
+    storage.add_message(&cats, message(3, "three")).unwrap();
+    storage.set_membership(StoredMembership { user: Arc::new("tom".to_string()), group: cats.clone(), last_seen: 1 }).unwrap();
+    storage.set_membership(StoredMembership { user: Arc::new("tom".to_string()), group: cats.clone(), last_seen: 3 }).unwrap();
+
+    let tom = Arc::new("tom".to_string());
+    let direct = |seq, text: &str| StoredDirect { seq, from: Arc::new("jerry".to_string()), message: Arc::new(text.to_string()) };
+    storage.set_user(StoredUser { name: tom.clone(), token_key: Arc::new("key".to_string()), direct_seen: 0 }).unwrap();
+    storage.set_user(StoredUser { name: tom.clone(), token_key: Arc::new("key".to_string()), direct_seen: 1 }).unwrap();
+    storage.add_direct(&tom, direct(1, "hi")).unwrap();
+    storage.add_direct(&tom, direct(2, "there")).unwrap();
+
+    assert_eq!(1, storage.groups().unwrap().len());
+    assert_eq!(Some(Arc::new("tom".to_string())), storage.groups().unwrap()[0].owner);
+    assert_eq!(vec![message(2, "two"), message(3, "three")], storage.recent_messages(&cats, 2).unwrap());
+    assert!(storage.recent_messages(&dogs, 10).unwrap().is_empty());
+    assert_eq!(1, storage.memberships().unwrap().len());
+    assert_eq!(3, storage.memberships().unwrap()[0].last_seen);
+    assert_eq!(1, storage.users().unwrap().len());
+    assert_eq!(1, storage.users().unwrap()[0].direct_seen);
+    assert_eq!(vec![direct(2, "there")], storage.recent_directs(&tom, 1).unwrap());
+    assert!(storage.recent_directs(&cats, 10).unwrap().is_empty());
+
+    // Seq of the last message is known even when the message itself is not
+    assert_eq!(3, storage.groups().unwrap()[0].last_seq);
+    storage.set_last_seq(&cats, 5).unwrap();
+    assert_eq!(5, storage.groups().unwrap()[0].last_seq);
+}
+
+#[test]
+fn test_memory_storage()
+{
+    let storage = MemoryStorage::new(2);
+    check_storage(&storage);
+
+    // Only the kept messages take memory
+    let cats = Arc::new("cats".to_string());
+    assert_eq!(2, storage.recent_messages(&cats, 10).unwrap().len());
+    assert_eq!(2, storage.0.lock().unwrap().messages[&cats].len());
+}
+
+#[test]
+fn test_file_storage()
+{
+    let path = std::env::temp_dir().join(format!("web-chat-storage-{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+
+    check_storage(&FileStorage::open(path, 10).unwrap());
+
+    // Everything is there after a restart
+    let cats = Arc::new("cats".to_string());
+    let reopened = FileStorage::open(path, 10).unwrap();
+    assert_eq!(1, reopened.groups().unwrap().len());
+    assert_eq!(5, reopened.groups().unwrap()[0].last_seq);
+    assert_eq!(3, reopened.memberships().unwrap()[0].last_seen);
+    assert_eq!("three", reopened.recent_messages(&cats, 1).unwrap()[0].message.as_str());
+    assert_eq!(1, reopened.users().unwrap()[0].direct_seen);
+    assert_eq!(2, reopened.recent_directs(&Arc::new("tom".to_string()), 10).unwrap().len());
+    drop(reopened);
+
+    // Crash in the middle of a write loses only the last record
+    std::fs::OpenOptions::new().append(true).open(path).unwrap().write_all(b"{\"Group\":{\"na").unwrap();
+    let reopened = FileStorage::open(path, 10).unwrap();
+    assert_eq!(3, reopened.recent_messages(&cats, 10).unwrap().len());
+    reopened.add_message(&cats, StoredMessage { seq: 6, reply_to: None, thread_root: 6, message: Arc::new("six".to_string()) }).unwrap();
+    drop(reopened);
+    assert_eq!(4, FileStorage::open(path, 10).unwrap().recent_messages(&cats, 10).unwrap().len());
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_file_storage_compaction()
+{
+    let path = std::env::temp_dir().join(format!("web-chat-compaction-{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+    let lines = || std::fs::read_to_string(path).unwrap().lines().count();
+
+    let cats = Arc::new("cats".to_string());
+    let tom = Arc::new("tom".to_string());
+    let storage = FileStorage::open(path, 3).unwrap();
+    storage.add_group(StoredGroup { name: cats.clone(), encrypted: false, owner: None, last_seq: 0 }).unwrap();
+    for seq in 1..=COMPACT_MIN_RECORDS as u64 + 100 {
+        let message = StoredMessage { seq, reply_to: None, thread_root: seq, message: Arc::new(seq.to_string()) };
+        storage.add_message(&cats, message).unwrap();
+        storage.set_membership(StoredMembership { user: tom.clone(), group: cats.clone(), last_seen: seq }).unwrap();
+    }
+
+    // Compacted on the way, the kept messages and the ones after them are still there
+    let recent = storage.recent_messages(&cats, 200).unwrap();
+    assert_eq!((998..=1100).collect::<Vec<_>>(), recent.iter().map(|message| message.seq).collect::<Vec<_>>());
+    assert_eq!(1100, storage.memberships().unwrap()[0].last_seen);
+    drop(storage);
+    assert!(lines() < COMPACT_MIN_RECORDS);
+
+    // And after a restart
+    let storage = FileStorage::open(path, 3).unwrap();
+    assert_eq!(1100, storage.groups().unwrap()[0].last_seq);
+    assert_eq!(1100, storage.recent_messages(&cats, 1).unwrap()[0].seq);
+    drop(storage);
+
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,363 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+use web_chat::{e2e, utils::AppResult, ServerPacket};
+
+use crate::{
+    storage::{Storage, StoredDirect, StoredMembership, StoredUser},
+    Outbound
+};
+
+/// User that introduced itself with Hello.
+/// It is remembered after the connection closes so that a reconnect
+/// can rejoin its groups and get what was posted while it was away.
+pub struct User
+{
+    name: Arc<String>,
+    state: Mutex<UserState>,
+}
+
+struct UserState
+{
+    online: bool,
+    outbound: Option<Arc<Outbound>>,    // reply stream of the connection while online
+    token_key: Option<Arc<String>>,     // None until the first Hello of the user sets the token
+    seen: HashMap<Arc<String>, u64>,    // group -> seq of the last message the user has read
+    direct: Mailbox,
+}
+
+/// Direct messages to the user, the latest ones are kept for when it reconnects
+#[derive(Default)]
+struct Mailbox
+{
+    last_seq: u64,
+    seen: u64,
+    packets: VecDeque<ServerPacket>,
+}
+
+impl User
+{
+    fn new(name: Arc<String>) -> User
+    {
+        let state = UserState { online: false, outbound: None, token_key: None, seen: HashMap::new(), direct: Mailbox::default() };
+        User { name, state: Mutex::new(state) }
+    }
+
+    pub fn name(&self) -> &Arc<String>
+    {
+        &self.name
+    }
+
+    /// Groups the user joined with the last seq it has seen in each
+    pub fn groups(&self) -> Vec<(Arc<String>, u64)>
+    {
+        self.state
+            .lock()
+            .unwrap()
+            .seen
+            .iter()
+            .map(|(group, seq)| (group.clone(), *seq))
+            .collect()
+    }
+
+    pub fn last_seen(&self, group: &String) -> Option<u64>
+    {
+        self.state.lock().unwrap().seen.get(group).copied()
+    }
+
+    pub fn saw(&self, group: &Arc<String>, seq: u64)
+    {
+        let mut state = self.state.lock().unwrap();
+        let seen = state.seen.entry(group.clone()).or_insert(0);
+        *seen = seq.max(*seen);
+    }
+
+    pub fn read_direct(&self, upto_seq: u64) -> Result<(), String>
+    {
+        let mut state = self.state.lock().unwrap();
+        if upto_seq > state.direct.last_seq {
+            return Err(format!("Can't mark direct message {} as read, the last one is {}", upto_seq, state.direct.last_seq));
+        }
+
+        state.direct.seen = upto_seq.max(state.direct.seen);
+        Ok(())
+    }
+}
+
+impl Mailbox
+{
+    fn push(&mut self, packet: ServerPacket, limit: usize)
+    {
+        if limit > 0 {
+            if self.packets.len() == limit {
+                self.packets.pop_front();
+            }
+            self.packets.push_back(packet);
+        }
+    }
+
+    /// Unread messages, preceded by a summary when some of them are not kept anymore
+    fn unread(&self) -> Vec<ServerPacket>
+    {
+        let unread: Vec<ServerPacket> = self.packets
+            .iter()
+            .filter(|packet| matches!(packet, ServerPacket::Direct { seq, .. } if *seq > self.seen))
+            .cloned()
+            .collect();
+
+        let missed = self.last_seq - self.seen;
+        let mut replay = Vec::with_capacity(unread.len() + 1);
+        if missed > unread.len() as u64 {
+            replay.push(ServerPacket::MissedDirect { skipped: missed - unread.len() as u64 });
+        }
+        replay.extend(unread);
+        replay
+    }
+}
+
+// Std mutex for the same reason as in Groups, nothing is awaited under the lock
+pub struct Users
+{
+    users: Mutex<HashMap<Arc<String>, Arc<User>>>,
+    storage: Arc<dyn Storage>,
+    offline_queue_limit: usize,                 // direct messages kept per user
+}
+
+impl Users
+{
+    /// Users, the groups they joined and their direct messages are loaded back from the storage
+    pub fn new(storage: Arc<dyn Storage>, offline_queue_limit: usize) -> AppResult<Users>
+    {
+        let mut users: HashMap<Arc<String>, Arc<User>> = HashMap::new();
+        for membership in storage.memberships()? {
+            users
+                .entry(membership.user.clone())
+                .or_insert_with(|| Arc::new(User::new(membership.user.clone())))
+                .saw(&membership.group, membership.last_seen);
+        }
+
+        for stored in storage.users()? {
+            let user = users
+                .entry(stored.name.clone())
+                .or_insert_with(|| Arc::new(User::new(stored.name.clone())));
+            let mut state = user.state.lock().unwrap();
+            state.token_key = Some(stored.token_key);
+            state.direct.seen = stored.direct_seen;
+
+            // At least the last message is read so the seq goes on from it
+            for direct in storage.recent_directs(&stored.name, offline_queue_limit.max(1))? {
+                state.direct.last_seq = direct.seq;
+                let packet = ServerPacket::Direct { from: direct.from, seq: direct.seq, message: direct.message };
+                state.direct.push(packet, offline_queue_limit);
+            }
+        }
+
+        Ok(Users { users: Mutex::new(users), storage, offline_queue_limit })
+    }
+
+    /// User stays online while the returned session is alive.
+    /// The first login of a name sets its token, later ones must present the same token.
+    /// Direct messages the user has not read yet come along with the session.
+    pub fn login(self: &Arc<Self>, name: Arc<String>, token: &str, outbound: Arc<Outbound>) -> Result<(Session, Vec<ServerPacket>), String>
+    {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("Invalid user name '{}', it must be a single word", name));
+        }
+        let token_key = Arc::new(e2e::token_key(token).map_err(|e| format!("Invalid token: {}", e))?);
+
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .entry(name.clone())
+            .or_insert_with(|| Arc::new(User::new(name.clone())))
+            .clone();
+        drop(users);
+
+        let mut state = user.state.lock().unwrap();
+        match &state.token_key {
+            Some(known) if *known != token_key => return Err(format!("Wrong token for user '{}'", name)),
+            Some(_) => {}
+            None => {
+                let stored = StoredUser { name: name.clone(), token_key: token_key.clone(), direct_seen: state.direct.seen };
+                self.storage
+                    .set_user(stored)
+                    .map_err(|e| format!("Can't store the user '{}': {}", name, e))?;
+                state.token_key = Some(token_key);
+            }
+        }
+
+        if state.online {
+            return Err(format!("User '{}' is already connected", name));
+        }
+        state.online = true;
+        state.outbound = Some(outbound);
+        let unread = state.direct.unread();
+        drop(state);
+
+        Ok((Session { users: self.clone(), user }, unread))
+    }
+
+    /// Message is kept until the addressee reads it or it is pushed out by newer ones.
+    /// Returns the reply stream of the addressee with the packet when it is online.
+    pub fn send_direct(&self, from: &Arc<String>, to: &Arc<String>, message: Arc<String>) -> Result<Option<(Arc<Outbound>, ServerPacket)>, String>
+    {
+        let user = match self.users.lock().unwrap().get(to) {
+            Some(user) => user.clone(),
+            None => return Err(format!("Can't send to the user '{}' because the user is unknown", to)),
+        };
+
+        // Seq is assigned and stored under the lock so the user gets messages in seq order
+        let mut state = user.state.lock().unwrap();
+        let seq = state.direct.last_seq + 1;
+        let stored = StoredDirect { seq, from: from.clone(), message: message.clone() };
+        self.storage
+            .add_direct(to, stored)
+            .map_err(|e| format!("Can't store message for the user '{}': {}", to, e))?;
+
+        state.direct.last_seq = seq;
+        let packet = ServerPacket::Direct { from: from.clone(), seq, message };
+        state.direct.push(packet.clone(), self.offline_queue_limit);
+
+        Ok(state.outbound.clone().map(|outbound| (outbound, packet)))
+    }
+
+    /// Read positions are stored only here and on the first join,
+    /// storing them on every read would be too slow
+    fn logout(&self, user: &User)
+    {
+        let mut state = user.state.lock().unwrap();
+        state.online = false;
+        state.outbound = None;
+        let stored = state.token_key.clone().map(|token_key| StoredUser { name: user.name.clone(), token_key, direct_seen: state.direct.seen });
+        drop(state);
+
+        if let Some(stored) = stored {
+            if let Err(message) = self.storage.set_user(stored) {
+                eprintln!("error: can't store last read direct message of {}: {}", user.name, message);
+            }
+        }
+
+        for (group, last_seen) in user.groups() {
+            let membership = StoredMembership { user: user.name.clone(), group, last_seen };
+            if let Err(message) = self.storage.set_membership(membership) {
+                eprintln!("error: can't store last seen message of {}: {}", user.name, message);
+            }
+        }
+    }
+}
+
+/// Logs the user out when the connection is done with it, no matter how the connection ended
+pub struct Session
+{
+    users: Arc<Users>,
+    user: Arc<User>,
+}
+
+impl Session
+{
+    pub fn user(&self) -> &Arc<User>
+    {
+        &self.user
+    }
+}
+
+impl Drop for Session
+{
+    fn drop(&mut self)
+    {
+        self.users.logout(&self.user);
+    }
+}
+
+#[cfg(test)]
+use crate::storage::MemoryStorage;
+
+#[cfg(test)]
+pub fn test_outbound() -> Arc<Outbound>
+{
+    async_std::task::block_on(async {
+        let listener = async_std::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = async_std::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        Arc::new(Outbound::new(stream))
+    })
+}
+
+#[test]
+fn test_login()
+{
+    let storage = Arc::new(MemoryStorage::new(2));
+    let users = Arc::new(Users::new(storage.clone(), 2).unwrap());
+    let cats = Arc::new("cats".to_string());
+    let (tom, token) = (Arc::new("tom".to_string()), e2e::generate_token());
+    let login = |users: &Arc<Users>, token: &str| users.login(tom.clone(), token, test_outbound()).map(|(session, _)| session);
+
+    let session = login(&users, &token).unwrap();
+    assert!(login(&users, &token).is_err());
+    assert!(users.login(Arc::new("tom and jerry".to_string()), &token, test_outbound()).is_err());
+
+    session.user().saw(&cats, 5);
+    session.user().saw(&cats, 3);
+    drop(session);
+
+    // Only the token of the first login works
+    assert!(login(&users, &e2e::generate_token()).is_err());
+    assert!(login(&users, "not a token").is_err());
+
+    // Same identity keeps its groups between connections
+    let session = login(&users, &token).unwrap();
+    assert_eq!(Some(5), session.user().last_seen(&cats));
+    assert_eq!(vec![(cats.clone(), 5)], session.user().groups());
+
+    // And after a restart
+    drop(session);
+    let users = Arc::new(Users::new(storage, 2).unwrap());
+    assert!(login(&users, &e2e::generate_token()).is_err());
+    let session = login(&users, &token).unwrap();
+    assert_eq!(Some(5), session.user().last_seen(&cats));
+}
+
+#[test]
+fn test_direct_messages()
+{
+    let storage = Arc::new(MemoryStorage::new(2));
+    let users = Arc::new(Users::new(storage.clone(), 2).unwrap());
+    let (tom, jerry, token) = (Arc::new("tom".to_string()), Arc::new("jerry".to_string()), e2e::generate_token());
+    let send = |users: &Users, text: &str| users.send_direct(&jerry, &tom, Arc::new(text.to_string()));
+    let seqs = |packets: Vec<ServerPacket>| -> Vec<(u64, u64)> {
+        packets
+            .into_iter()
+            .map(|packet| match packet {
+                ServerPacket::Direct { seq, .. } => (seq, 0),
+                ServerPacket::MissedDirect { skipped } => (0, skipped),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect()
+    };
+
+    // Unknown users can't get messages
+    assert!(send(&users, "hi").is_err());
+
+    let (session, unread) = users.login(tom.clone(), &token, test_outbound()).unwrap();
+    assert!(unread.is_empty());
+
+    // Online user gets the message right away
+    assert!(matches!(send(&users, "one"), Ok(Some((_, ServerPacket::Direct { seq: 1, .. })))));
+    drop(session);
+
+    // Offline user gets up to the offline limit and a summary of the rest when it is back
+    assert!(matches!(send(&users, "two"), Ok(None)));
+    send(&users, "three").unwrap();
+    let (session, unread) = users.login(tom.clone(), &token, test_outbound()).unwrap();
+    assert_eq!(vec![(0, 1), (2, 0), (3, 0)], seqs(unread));
+
+    // Only read messages are not delivered again, also after a restart
+    assert!(session.user().read_direct(4).is_err());
+    session.user().read_direct(2).unwrap();
+    drop(session);
+    let users = Arc::new(Users::new(storage, 2).unwrap());
+    let (session, unread) = users.login(tom.clone(), &token, test_outbound()).unwrap();
+    assert_eq!(vec![(3, 0)], seqs(unread));
+    drop(session);
+
+    // Seq goes on where it stopped
+    assert!(matches!(send(&users, "four"), Ok(None)));
+    let (_session, unread) = users.login(tom.clone(), &token, test_outbound()).unwrap();
+    assert_eq!(vec![(3, 0), (4, 0)], seqs(unread));
+}
@@ -0,0 +1,177 @@
+// End-to-end encryption helpers used by the client.
+// The server never calls them, it only relays the base64 strings they produce.
+// The only exception is token_key, the server keeps that instead of the user tokens.
+//
+// Each client has an X25519 key pair. The group key is a random XChaCha20Poly1305 key
+// that one member generates and hands out to every member in a crypto box
+// authenticated by its own secret key. Messages are encrypted with the group key.
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use crypto_box::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    PublicKey,
+    SalsaBox,
+    SecretKey,
+};
+
+use crate::utils::AppResult;
+
+pub type GroupKey = chacha20poly1305::Key;
+pub type MemberSecret = SecretKey;
+
+pub fn generate_member_secret() -> SecretKey
+{
+    SecretKey::generate(&mut OsRng)
+}
+
+pub fn public_key_string(secret: &SecretKey) -> String
+{
+    BASE64.encode(secret.public_key().as_bytes())
+}
+
+pub fn generate_group_key() -> GroupKey
+{
+    XChaCha20Poly1305::generate_key(&mut OsRng)
+}
+
+/// Encrypts group key so that only the 'to' member can read it
+/// and it can tell the key came from the owner of 'from'
+pub fn seal_group_key(key: &GroupKey, from: &SecretKey, to: &str) -> AppResult<String>
+{
+    let salsa_box = SalsaBox::new(&decode_public_key(to)?, from);
+    let nonce = SalsaBox::generate_nonce(&mut OsRng);
+    let sealed = salsa_box
+        .encrypt(&nonce, key.as_slice())
+        .map_err(|_| "can't seal group key")?;
+
+    Ok(join_nonce(&nonce, &sealed))
+}
+
+pub fn open_group_key(sealed: &str, from: &str, to: &SecretKey) -> AppResult<GroupKey>
+{
+    let salsa_box = SalsaBox::new(&decode_public_key(from)?, to);
+    let bytes = decode_encrypted(sealed, 24)?;
+    let (nonce, sealed) = bytes.split_at(24);
+    let key = salsa_box
+        .decrypt(nonce.into(), sealed)
+        .map_err(|_| "group key was not sealed for us or was tampered with")?;
+
+    if key.len() != 32 {
+        return Err("group key has wrong length".into());
+    }
+
+    Ok(*GroupKey::from_slice(&key))
+}
+
+pub fn encrypt(key: &GroupKey, message: &str) -> AppResult<String>
+{
+    let cipher = XChaCha20Poly1305::new(key);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, message.as_bytes())
+        .map_err(|_| "can't encrypt message")?;
+
+    Ok(join_nonce(&nonce, &ciphertext))
+}
+
+pub fn decrypt(key: &GroupKey, ciphertext: &str) -> AppResult<String>
+{
+    let cipher = XChaCha20Poly1305::new(key);
+    let bytes = decode_encrypted(ciphertext, 24)?;
+    let (nonce, ciphertext) = bytes.split_at(24);
+    let message = cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "message was encrypted with another key or was tampered with")?;
+
+    Ok(String::from_utf8(message)?)
+}
+
+/// Random secret a user proves its identity with, base64 like the keys
+pub fn generate_token() -> String
+{
+    BASE64.encode(SecretKey::generate(&mut OsRng).to_bytes())
+}
+
+/// The token is an X25519 secret key and this is its public key,
+/// so whoever reads the stored value still can't log in with it
+pub fn token_key(token: &str) -> AppResult<String>
+{
+    let bytes: [u8; 32] = BASE64
+        .decode(token)
+        .map_err(|_| "token is not base64")?
+        .try_into()
+        .map_err(|_| "token has wrong length")?;
+
+    Ok(public_key_string(&SecretKey::from(bytes)))
+}
+
+fn decode_public_key(text: &str) -> AppResult<PublicKey>
+{
+    let bytes: [u8; 32] = BASE64
+        .decode(text)?
+        .try_into()
+        .map_err(|_| format!("public key '{}' has wrong length", text))?;
+
+    Ok(PublicKey::from(bytes))
+}
+
+/// Random nonce is sent in front of the encrypted bytes
+fn join_nonce(nonce: &[u8], encrypted: &[u8]) -> String
+{
+    let mut bytes = nonce.to_vec();
+    bytes.extend_from_slice(encrypted);
+    BASE64.encode(bytes)
+}
+
+fn decode_encrypted(text: &str, nonce_len: usize) -> AppResult<Vec<u8>>
+{
+    let bytes = BASE64.decode(text)?;
+    if bytes.len() < nonce_len {
+        return Err("encrypted data is too short".into());
+    }
+
+    Ok(bytes)
+}
+
+#[test]
+fn test_group_key_exchange()
+{
+    let leader = generate_member_secret();
+    let member = generate_member_secret();
+    let stranger = generate_member_secret();
+
+    let key = generate_group_key();
+    let sealed = seal_group_key(&key, &leader, &public_key_string(&member)).unwrap();
+
+    let opened = open_group_key(&sealed, &public_key_string(&leader), &member).unwrap();
+    assert_eq!(key, opened);
+
+    // Only the addressee can open it and only if it came from the expected sender
+    assert!(open_group_key(&sealed, &public_key_string(&leader), &stranger).is_err());
+    assert!(open_group_key(&sealed, &public_key_string(&stranger), &member).is_err());
+}
+
+#[test]
+fn test_encrypt_decrypt()
+{
+    let key = generate_group_key();
+    let ciphertext = encrypt(&key, "Hello cats!").unwrap();
+
+    assert!(!ciphertext.contains("cats"));
+    assert_eq!("Hello cats!", decrypt(&key, &ciphertext).unwrap());
+    assert!(decrypt(&generate_group_key(), &ciphertext).is_err());
+    assert!(decrypt(&key, "c2hvcnQ=").is_err());
+}
+
+#[test]
+fn test_token_key()
+{
+    let token = generate_token();
+    let key = token_key(&token).unwrap();
+
+    assert_eq!(key, token_key(&token).unwrap());
+    assert_ne!(key, token_key(&generate_token()).unwrap());
+    assert_ne!(token, key);
+    assert!(token_key("c2hvcnQ=").is_err());
+    assert!(token_key("not base64!").is_err());
+}
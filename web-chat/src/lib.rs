@@ -1,27 +1,136 @@
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+pub mod e2e;
 pub mod utils;
 
 // p569
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub enum ClientPacket {             // was:FromClient
+    Hello {                         // identifies the user, a reconnect gets what was missed
+        user: Arc<String>,
+        token: Arc<String>,         // e2e::generate_token, the first Hello of a user sets it
+    },
     Join {
         group: Arc<String>,         // was:group
     },
     Send {                          // was:Post
         group: Arc<String>,
         message: Arc<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<u64>,      // seq of the parent message when this is a reply
+    },
+    JoinEncrypted {                 // end-to-end encrypted group, server sees only ciphertext
+        group: Arc<String>,
+        public_key: Arc<String>,    // base64 X25519 key of the joining client
+    },
+    ShareKey {                      // new group key sealed for every member of an epoch
+        group: Arc<String>,
+        epoch: u64,
+        keys: Vec<SealedKey>,
+    },
+    SendEncrypted {
+        group: Arc<String>,
+        epoch: u64,                 // group key generation the message is encrypted with
+        ciphertext: Arc<String>,
+    },
+    Typing {                        // rate limited by the server, never stored
+        group: Arc<String>,
+    },
+    Read {                          // everything up to and including that message was read
+        group: Arc<String>,
+        upto_seq: u64,
+    },
+    WhoRead {                       // asks which members have read up to that message
+        group: Arc<String>,
+        seq: u64,
+    },
+    FetchThread {                   // asks for the root message and all the replies under it
+        group: Arc<String>,
+        thread_root: u64,
+    },
+    SendDirect {                    // to a single user, kept for it while it is offline
+        to: Arc<String>,
+        message: Arc<String>,
+    },
+    ReadDirect {                    // direct messages up to and including that one were read
+        upto_seq: u64,
     },
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub enum ServerPacket {             // was:FromServer, enum
     Message {                       // struct variant
         group: Arc<String>,         // Arc allows server to reuse strings for messages and group names
+        seq: u64,                   // position of the message in the group, starts with 1
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reply_to: Option<u64>,
+        thread_root: u64,           // seq of the message that started the thread, own seq for a root
         message: Arc<String>,       // These strings are not reused for serialization/deserialization
     },
     Error(String),                  // tuple variant
+    Members {                       // membership of an encrypted group changed, key must rotate
+        group: Arc<String>,
+        epoch: u64,
+        members: Vec<Member>,       // sorted by id, the first one generates the group key
+    },
+    GroupKey {
+        group: Arc<String>,
+        epoch: u64,
+        from_public_key: Arc<String>,
+        key: String,                // sealed for the receiving member only
+    },
+    Encrypted {
+        group: Arc<String>,
+        seq: u64,
+        epoch: u64,
+        ciphertext: Arc<String>,
+    },
+    Typing {
+        group: Arc<String>,
+        member: u64,                // connection id of the typing member
+    },
+    ReadBy {                        // reply to WhoRead
+        group: Arc<String>,
+        seq: u64,
+        members: Vec<u64>,
+    },
+    Missed {                        // that many messages were posted while the user was away and are not delivered
+        group: Arc<String>,
+        skipped: u64,
+    },
+    Thread {                        // reply to FetchThread, messages are in seq order
+        group: Arc<String>,
+        thread_root: u64,
+        messages: Vec<ThreadMessage>,
+    },
+    Direct {
+        from: Arc<String>,
+        seq: u64,                   // position among the direct messages of the receiving user
+        message: Arc<String>,
+    },
+    MissedDirect {                  // like Missed, for the direct messages
+        skipped: u64,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct Member {
+    pub id: u64,
+    pub public_key: Arc<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ThreadMessage {
+    pub seq: u64,
+    pub reply_to: Option<u64>,
+    pub message: Arc<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct SealedKey {
+    pub member: u64,
+    pub key: String,
 }
 
 #[test]
@@ -30,6 +139,7 @@ fn test_client_packet_json()
     let target = ClientPacket::Send {
         group: Arc::new("Cats".to_string()),
         message: Arc::new("Hello cats!".to_string()),
+        reply_to: None,
     };
 
     let serialized = serde_json::to_string(&target).unwrap();
@@ -38,4 +148,18 @@ fn test_client_packet_json()
     assert_eq!(serialized, "{\"Send\":{\"group\":\"Cats\",\"message\":\"Hello cats!\"}}");
     assert_eq!(serialized, r#"{"Send":{"group":"Cats","message":"Hello cats!"}}"#); // raw string p74
     assert_eq!(deserialized, target);
+}
+
+#[test]
+fn test_reply_packet_json()
+{
+    let target = ClientPacket::Send {
+        group: Arc::new("Cats".to_string()),
+        message: Arc::new("Me too".to_string()),
+        reply_to: Some(3),
+    };
+
+    let serialized = serde_json::to_string(&target).unwrap();
+    assert_eq!(serialized, r#"{"Send":{"group":"Cats","message":"Me too","reply_to":3}}"#);
+    assert_eq!(serde_json::from_str::<ClientPacket>(&serialized).unwrap(), target);
 }
\ No newline at end of file